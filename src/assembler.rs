@@ -0,0 +1,752 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use num_bigint::BigUint;
+use num_traits::One;
+
+use crate::computer::CPU;
+use crate::object::SectionKind;
+use crate::writers::ProgramWriter;
+use crate::InstructionSet;
+
+// Text assembler.
+//
+// Turns assembly source into a ProgramWriter for the CPU it was made for, which is then built into a
+// flat program or an object file like any other. One statement per line, ';' starts a comment:
+//
+//     .equ COUNT, 3
+//     .define counter reg_2
+//     .macro countdown reg
+//     loop\@: SubImmediate \reg, 1
+//             BranchNotEqual loop\@, \reg, 0
+//     .endm
+//     start:  LoadImmediate counter, COUNT
+//             countdown counter
+//             Halt
+//
+// Instructions are named after InstructionSet, in any case, and take their operands comma separated:
+// register names, constant expressions, or one label for an address or a PC relative offset. Directives:
+//
+// .equ NAME, expr            numeric constant
+// .define NAME text          replaces the word NAME by text, e.g. a register alias
+// .macro name [param, ...]   until .endm. \param is an argument, \@ a number unique to each expansion
+// .include "file"            a source added with Assembler::file, or a path relative to the includer
+// .if expr, .ifdef NAME, .ifndef NAME, .else, .endif
+// .code, .data, .bss         section of the labels and data that follow, code to begin with
+// .global name
+// .byte expr, ...            one byte each
+// .word expr, ...            data_size bytes each, in the CPU's byte order
+// .string "text"             text followed by a zero byte
+// .fill count [, value]      count bytes of value, zero by default
+// .align n                   zeros up to the next multiple of n
+//
+// Expressions are numbers (decimal, 0x hex, 0b binary or 'c') and constants joined by + and -.
+// Negative values are stored as two's complement.
+
+// Macro expansions and includes nested deeper than this are assumed to be recursive
+const MAX_DEPTH: usize = 64;
+
+pub struct Assembler<'a> {
+    cpu: &'a CPU,
+    // Sources for .include by name, looked up before the file system
+    sources: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+struct SourceLine {
+    file: String,
+    line: usize,
+    text: String,
+}
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+struct Condition {
+    // Whether the .if itself is assembled
+    enclosing: bool,
+    // Whether a branch has been taken already
+    taken: bool,
+    active: bool,
+    seen_else: bool,
+}
+
+// State of one assemble call
+struct Assembly<'a> {
+    assembler: &'a Assembler<'a>,
+    writer: ProgramWriter,
+    section: SectionKind,
+    constants: HashMap<String, i64>,
+    defines: HashMap<String, String>,
+    macros: HashMap<String, Macro>,
+    conditions: Vec<Condition>,
+    // The macro being defined, with the line of its .macro
+    definition: Option<(String, Macro, SourceLine)>,
+    expansions: usize,
+    // Files being assembled, innermost last
+    includes: Vec<String>,
+}
+
+impl<'a> Assembler<'a> {
+    pub fn new(cpu: &'a CPU) -> Self {
+        Assembler { cpu, sources: HashMap::new() }
+    }
+
+    // Makes text available to .include "name"
+    pub fn file(&mut self, name: &str, text: &str) -> &mut Self {
+        self.sources.insert(name.to_string(), text.to_string());
+        self
+    }
+
+    pub fn assemble_file(&self, path: &str) -> Result<ProgramWriter, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+        self.assemble(path, &text)
+    }
+
+    // Errors start with file:line, followed by the lines of any macro expansions or includes involved
+    pub fn assemble(&self, name: &str, text: &str) -> Result<ProgramWriter, String> {
+        let mut writer = ProgramWriter::new(self.cpu.instruction_set.clone(), &self.cpu.registers);
+        writer.set_endianness(self.cpu.endianness).set_memory_size(self.cpu.memory.size());
+        let mut assembly = Assembly {
+            assembler: self,
+            writer,
+            section: SectionKind::Code,
+            constants: HashMap::new(),
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            conditions: Vec::new(),
+            definition: None,
+            expansions: 0,
+            includes: vec![name.to_string()],
+        };
+        assembly.lines(&source_lines(name, text), 0)?;
+        if let Some((macro_name, _, start)) = &assembly.definition {
+            return Err(format!("{}:{}: .macro {} has no .endm", start.file, start.line, macro_name));
+        }
+        if !assembly.conditions.is_empty() {
+            return Err(format!("{}: .if without .endif", name));
+        }
+        Ok(assembly.writer)
+    }
+}
+
+impl Assembly<'_> {
+    fn lines(&mut self, lines: &[SourceLine], depth: usize) -> Result<(), String> {
+        for line in lines {
+            self.statement(line, depth).map_err(|error| format!("{}:{}: {}", line.file, line.line, error))?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, line: &SourceLine, depth: usize) -> Result<(), String> {
+        let text = strip_comment(&line.text).trim();
+        let (word, rest) = split_word(text);
+        if let Some((name, definition, _)) = &mut self.definition {
+            match word {
+                ".endm" => {
+                    let name = name.clone();
+                    let definition = definition.clone();
+                    self.macros.insert(name, definition);
+                    self.definition = None;
+                }
+                ".macro" => return Err("macros cannot be defined inside a macro".to_string()),
+                _ => definition.body.push(line.clone()),
+            }
+            return Ok(());
+        }
+        let active = self.active();
+        match word {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let taken = active
+                    && match word {
+                        ".if" => self.expression(&self.substitute(rest))? != 0,
+                        ".ifdef" => self.is_defined(identifier(rest)?),
+                        _ => !self.is_defined(identifier(rest)?),
+                    };
+                self.conditions.push(Condition { enclosing: active, taken, active: taken, seen_else: false });
+                return Ok(());
+            }
+            ".else" => {
+                let condition = self.conditions.last_mut().ok_or(".else without .if")?;
+                if condition.seen_else {
+                    return Err("second .else for the same .if".to_string());
+                }
+                condition.active = condition.enclosing && !condition.taken;
+                condition.seen_else = true;
+                return Ok(());
+            }
+            ".endif" => {
+                self.conditions.pop().ok_or(".endif without .if")?;
+                return Ok(());
+            }
+            _ if !active => return Ok(()),
+            ".define" => {
+                let (name, value) = split_word(rest);
+                let name = identifier(name)?;
+                self.check_undefined(name)?;
+                self.defines.insert(name.to_string(), value.to_string());
+                return Ok(());
+            }
+            ".macro" => {
+                let (name, params) = split_word(rest);
+                let name = identifier(name)?;
+                self.check_undefined(name)?;
+                let params = split_args(params)?.into_iter().map(|param| identifier(param).map(str::to_string)).collect::<Result<_, _>>()?;
+                self.definition = Some((name.to_string(), Macro { params, body: Vec::new() }, line.clone()));
+                return Ok(());
+            }
+            ".endm" => return Err(".endm without .macro".to_string()),
+            _ => {}
+        }
+
+        let text = self.substitute(text);
+        let mut text = text.as_str();
+        let label_length = identifier_length(text);
+        if label_length > 0 && text[label_length..].starts_with(':') {
+            self.writer.try_label_in(self.section, &text[..label_length])?;
+            text = text[label_length + 1..].trim_start();
+        }
+        let (word, rest) = split_word(text);
+        match word {
+            "" => {}
+            ".equ" => {
+                let [name, value] = split_args(rest)?[..] else {
+                    return Err(".equ takes a name and a value".to_string());
+                };
+                let name = identifier(name)?;
+                self.check_undefined(name)?;
+                let value = self.expression(value)?;
+                self.constants.insert(name.to_string(), value);
+            }
+            ".include" => {
+                if depth >= MAX_DEPTH {
+                    return Err(format!("includes nested more than {} deep", MAX_DEPTH));
+                }
+                let (name, text) = self.source(&string_literal(rest)?, &line.file)?;
+                if self.includes.contains(&name) {
+                    return Err(format!("{} includes itself", name));
+                }
+                self.includes.push(name.clone());
+                self.lines(&source_lines(&name, &text), depth + 1)?;
+                self.includes.pop();
+            }
+            ".code" | ".data" | ".bss" => {
+                if !rest.is_empty() {
+                    return Err(format!("{} takes no operands", word));
+                }
+                self.section = SectionKind::ALL.into_iter().find(|section| section.name() == &word[1..]).expect("Every section has a directive");
+            }
+            ".global" => {
+                self.writer.global(identifier(rest)?);
+            }
+            ".byte" | ".word" => {
+                let width = if word == ".byte" { 1 } else { self.assembler.cpu.cpu_data_size as usize };
+                let mut bytes = Vec::new();
+                for arg in split_args(rest)? {
+                    let value = fit(self.expression(arg)?, width)?;
+                    bytes.extend(self.assembler.cpu.endianness.encode(&value, width));
+                }
+                self.writer.try_emit(self.section, &bytes)?;
+            }
+            ".string" => {
+                let mut bytes = string_literal(rest)?.into_bytes();
+                bytes.push(0);
+                self.writer.try_emit(self.section, &bytes)?;
+            }
+            ".fill" => {
+                let (count, value) = match split_args(rest)?[..] {
+                    [count] => (self.expression(count)?, 0),
+                    [count, value] => (self.expression(count)?, self.expression(value)?),
+                    _ => return Err(".fill takes a count and an optional value".to_string()),
+                };
+                let count = usize::try_from(count).map_err(|_| format!("cannot fill {} bytes", count))?;
+                let value = u8::try_from(fit(value, 1)?).expect("Fits in one byte");
+                self.writer.try_emit(self.section, &vec![value; count])?;
+            }
+            ".align" => {
+                let alignment = self.expression(rest)?;
+                let alignment = usize::try_from(alignment).ok().filter(|alignment| *alignment > 0).ok_or_else(|| format!("cannot align to {}", alignment))?;
+                let padding = (alignment - self.writer.section_size(self.section) % alignment) % alignment;
+                self.writer.try_emit(self.section, &vec![0; padding])?;
+            }
+            _ if word.starts_with('.') => return Err(format!("unknown directive {}", word)),
+            _ if self.macros.contains_key(word) => self.expand(word, rest, depth)?,
+            _ => self.instruction(word, rest)?,
+        }
+        Ok(())
+    }
+
+    fn active(&self) -> bool {
+        self.conditions.last().is_none_or(|condition| condition.active)
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.constants.contains_key(name) || self.defines.contains_key(name) || self.macros.contains_key(name)
+    }
+
+    fn check_undefined(&self, name: &str) -> Result<(), String> {
+        match self.is_defined(name) {
+            true => Err(format!("'{}' is already defined", name)),
+            false => Ok(()),
+        }
+    }
+
+    fn expand(&mut self, name: &str, args: &str, depth: usize) -> Result<(), String> {
+        if depth >= MAX_DEPTH {
+            return Err(format!("macros nested more than {} deep, is {} recursive?", MAX_DEPTH, name));
+        }
+        let definition = self.macros[name].clone();
+        let args = split_args(args)?;
+        if args.len() != definition.params.len() {
+            return Err(format!("macro {} takes {} argument(s), got {}", name, definition.params.len(), args.len()));
+        }
+        self.expansions += 1;
+        let body: Vec<SourceLine> = definition
+            .body
+            .iter()
+            .map(|line| SourceLine { text: expand_parameters(&line.text, &definition.params, &args, self.expansions), ..line.clone() })
+            .collect();
+        self.lines(&body, depth + 1)
+    }
+
+    fn instruction(&mut self, name: &str, operands: &str) -> Result<(), String> {
+        let opcode = InstructionSet::ALL
+            .into_iter()
+            .find(|opcode| format!("{:?}", opcode).eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown instruction or macro '{}'", name))?;
+        let instruction = self.assembler.cpu.instruction_set.get(&(opcode as u8))
+            .ok_or_else(|| format!("{:?} is not in this CPU's instruction set", opcode))?;
+        if self.section != SectionKind::Code {
+            return Err(format!("{:?} in the {} section, instructions belong in code", opcode, self.section.name()));
+        }
+        let operands = split_args(operands)?;
+        if operands.len() != instruction.operands.len() {
+            return Err(format!("{:?} takes {} operand(s), got {}", opcode, instruction.operands.len(), operands.len()));
+        }
+        let mut values = Vec::new();
+        let mut label = None;
+        for (index, (operand, kind)) in operands.into_iter().zip(&instruction.operands).enumerate() {
+            if kind.is_register() {
+                values.push(BigUint::from(self.assembler.cpu.registers.id_of(operand)?));
+            } else if identifier_length(operand) == operand.len() && !operand.as_bytes()[0].is_ascii_digit() && !self.constants.contains_key(operand) {
+                if label.is_some() {
+                    return Err(format!("{:?} can only take one label", opcode));
+                }
+                label = Some((index, operand));
+            } else {
+                values.push(fit(self.expression(operand)?, instruction.operand_width(*kind) as usize)?);
+            }
+        }
+        match label {
+            None => self.writer.try_add_instruction_big(opcode, &values)?,
+            Some((index, label)) => self.writer.add_with_label(opcode as u8, index, label, &values, instruction.pc_relative_operand() == Some(index))?,
+        };
+        Ok(())
+    }
+
+    // Replaces .define names outside of numbers, strings and characters
+    fn substitute(&self, text: &str) -> String {
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(first) = rest.chars().next() {
+            let length = match first {
+                '"' | '\'' => quoted_length(rest),
+                _ if first.is_ascii_alphanumeric() || first == '_' => identifier_length(rest),
+                _ => first.len_utf8(),
+            };
+            let (token, remainder) = rest.split_at(length);
+            match self.defines.get(token) {
+                Some(value) if !first.is_ascii_digit() => out.push_str(value),
+                _ => out.push_str(token),
+            }
+            rest = remainder;
+        }
+        out
+    }
+
+    // Terms joined by + and -, each term a number, a character or a constant with an optional minus
+    fn expression(&self, text: &str) -> Result<i64, String> {
+        let mut total: i64 = 0;
+        let mut rest = text.trim();
+        let mut sign = 1;
+        loop {
+            let (negative, term) = match rest.strip_prefix('-') {
+                Some(term) => (true, term.trim_start()),
+                None => (false, rest),
+            };
+            let length = match term.chars().next() {
+                Some('\'') => quoted_length(term),
+                _ => identifier_length(term),
+            };
+            if length == 0 {
+                return Err(format!("expected a value, got '{}'", term));
+            }
+            let value = self.value(&term[..length])?;
+            let value = if negative != (sign < 0) { value.checked_neg() } else { Some(value) };
+            total = value.and_then(|value| total.checked_add(value)).ok_or_else(|| format!("'{}' overflows", text.trim()))?;
+            rest = term[length..].trim_start();
+            sign = match rest.chars().next() {
+                None => return Ok(total),
+                Some('+') => 1,
+                Some('-') => -1,
+                Some(_) => return Err(format!("unexpected '{}'", rest)),
+            };
+            rest = rest[1..].trim_start();
+        }
+    }
+
+    fn value(&self, term: &str) -> Result<i64, String> {
+        if term.starts_with('\'') {
+            if term.len() < 2 || !term.ends_with('\'') {
+                return Err(format!("unterminated character literal {}", term));
+            }
+            return match unescape(&term[1..term.len() - 1])?.as_bytes() {
+                [byte] => Ok(*byte as i64),
+                _ => Err(format!("{} is not a single character", term)),
+            };
+        }
+        let parsed = if let Some(hex) = term.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16)
+        } else if let Some(binary) = term.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2)
+        } else if term.as_bytes()[0].is_ascii_digit() {
+            term.parse()
+        } else {
+            return self.constants.get(term).copied().ok_or_else(|| format!("'{}' is not a constant", term));
+        };
+        parsed.map_err(|_| format!("'{}' is not a number", term))
+    }
+
+    // Text of an included source and the name it is known by
+    fn source(&self, name: &str, from: &str) -> Result<(String, String), String> {
+        if let Some(text) = self.assembler.sources.get(name) {
+            return Ok((name.to_string(), text.clone()));
+        }
+        let path = Path::new(from).parent().map_or_else(|| Path::new(name).to_path_buf(), |directory| directory.join(name));
+        let text = std::fs::read_to_string(&path).map_err(|error| format!("cannot include {}: {}", path.display(), error))?;
+        Ok((path.to_string_lossy().into_owned(), text))
+    }
+}
+
+fn source_lines(file: &str, text: &str) -> Vec<SourceLine> {
+    text.lines()
+        .enumerate()
+        .map(|(index, text)| SourceLine { file: file.to_string(), line: index + 1, text: text.to_string() })
+        .collect()
+}
+
+// Two's complement in width bytes, failing if the value needs more. Any i64 fits in 8 bytes or more,
+// negative values are sign extended to the full width
+fn fit(value: i64, width: usize) -> Result<BigUint, String> {
+    let bits = 8 * width;
+    if bits < 64 && (value < -(1 << (bits - 1)) || value >= 1 << bits) {
+        return Err(format!("{} does not fit in {} byte(s)", value, width));
+    }
+    if value < 0 {
+        Ok((BigUint::one() << bits) - value.unsigned_abs())
+    } else {
+        Ok(BigUint::from(value as u64))
+    }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+// Comma separated operands, commas in strings and characters do not count
+fn split_args(text: &str) -> Result<Vec<&str>, String> {
+    if text.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut args = Vec::new();
+    let mut start = 0;
+    let mut index = 0;
+    while index < text.len() {
+        match text.as_bytes()[index] {
+            b'"' | b'\'' => index += quoted_length(&text[index..]),
+            b',' => {
+                args.push(text[start..index].trim());
+                index += 1;
+                start = index;
+            }
+            _ => index += 1,
+        }
+    }
+    args.push(text[start..].trim());
+    match args.iter().any(|arg| arg.is_empty()) {
+        true => Err(format!("empty operand in '{}'", text)),
+        false => Ok(args),
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut index = 0;
+    while index < text.len() {
+        match text.as_bytes()[index] {
+            b'"' | b'\'' => index += quoted_length(&text[index..]),
+            b';' => return &text[..index],
+            _ => index += 1,
+        }
+    }
+    text
+}
+
+// Bytes up to and including the closing quote, or the rest of the text if there is none
+fn quoted_length(text: &str) -> usize {
+    let quote = text.as_bytes()[0];
+    let mut index = 1;
+    while index < text.len() {
+        match text.as_bytes()[index] {
+            b'\\' => index += 2,
+            byte if byte == quote => return index + 1,
+            _ => index += 1,
+        }
+    }
+    text.len()
+}
+
+fn identifier_length(text: &str) -> usize {
+    text.find(|character: char| !(character.is_ascii_alphanumeric() || character == '_')).unwrap_or(text.len())
+}
+
+fn identifier(text: &str) -> Result<&str, String> {
+    match !text.is_empty() && identifier_length(text) == text.len() && !text.as_bytes()[0].is_ascii_digit() {
+        true => Ok(text),
+        false => Err(format!("'{}' is not a name", text)),
+    }
+}
+
+fn string_literal(text: &str) -> Result<String, String> {
+    if text.len() < 2 || !text.starts_with('"') || quoted_length(text) != text.len() || !text.ends_with('"') {
+        return Err(format!("expected a quoted string, got '{}'", text));
+    }
+    unescape(&text[1..text.len() - 1])
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            out.push(character);
+            continue;
+        }
+        out.push(match characters.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(other @ ('\\' | '"' | '\'')) => other,
+            other => return Err(format!("unknown escape \\{}", other.map(String::from).unwrap_or_default())),
+        });
+    }
+    Ok(out)
+}
+
+// \param becomes its argument and \@ the expansion number, anything else is kept
+fn expand_parameters(text: &str, params: &[String], args: &[&str], expansion: usize) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(index) = rest.find('\\') {
+        out.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        let length = identifier_length(after);
+        if let Some(position) = params.iter().position(|param| *param == after[..length]) {
+            out.push_str(args[position]);
+            rest = &after[length..];
+        } else if let Some(after) = after.strip_prefix('@') {
+            out.push_str(&expansion.to_string());
+            rest = after;
+        } else {
+            out.push('\\');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+
+    fn assemble(machine: &TestMachine, text: &str) -> Result<Vec<u8>, String> {
+        Assembler::new(&machine.cpu).assemble("main.s", text)?.try_build()
+    }
+
+    #[test]
+    fn macros_constants_and_conditions() {
+        // Fibonacci with the loop body as a macro, expanded twice with its own labels each time
+        let source = "
+            .equ STEPS, 5
+            .define a reg_0
+            .define b reg_1
+            .macro fibonacci count, scratch
+                    LoadImmediate \\scratch, \\count
+            loop\\@: MoveRegister a, reg_2          ; reg_2 = a + b, a = b, b = reg_2
+                    AddReg reg_2, b
+                    MoveRegister b, a
+                    MoveRegister reg_2, b
+                    SubImmediate \\scratch, 1
+                    BranchNotEqual loop\\@, \\scratch, 0
+            .endm
+                    LoadImmediate a, 0
+                    LoadImmediate b, 1
+                    fibonacci STEPS - 2, reg_3
+            .ifdef FAST
+                    Halt
+            .else
+                    fibonacci 2, reg_4
+            .endif
+            .if STEPS - 5
+                    LoadImmediate reg_5, 1
+            .endif
+                    Halt
+        ";
+        let mut machine = TestMachine::default();
+        let program = assemble(&machine, source).unwrap();
+        machine.cpu.load_program(0, &program);
+        machine.run(2000);
+        machine.expect().halted(true).register("reg_1", 8).register("reg_0", 5).register("reg_5", 0).check();
+    }
+
+    #[test]
+    fn data_directives_fill_their_sections() {
+        let source = "
+                    LoadFromMemory table, reg_0
+                    Halt
+                    .byte 1, -1, 'A'
+                    .align 4
+            .data
+            table:  .word 0x1234, -2
+                    .string \"a;b\\n\"
+                    .fill 2, 7
+            .bss
+            buffer: .fill 3
+        ";
+        let machine = TestMachine::with_data_size(64, 2);
+        let program = assemble(&machine, source).unwrap();
+        let load = InstructionSet::LoadFromMemory as u8;
+        let reg_0 = machine.cpu.registers.id_of("reg_0").unwrap();
+        assert_eq!(program, vec![
+            load, 0, 8, reg_0, InstructionSet::Halt as u8, 1, 0xff, b'A',
+            0x12, 0x34, 0xff, 0xfe, b'a', b';', b'b', b'\n', 0, 7, 7,
+            0, 0, 0
+        ]);
+    }
+
+    #[test]
+    fn includes_share_constants_and_macros() {
+        let mut machine = TestMachine::default();
+        let mut assembler = Assembler::new(&machine.cpu);
+        assembler
+            .file("consts.s", ".equ ANSWER, 42\n.macro answer reg\nLoadImmediate \\reg, ANSWER\n.endm")
+            .file("loop.s", ".include \"loop.s\"");
+        let program = assembler.assemble("main.s", ".include \"consts.s\"\nanswer reg_0\nHalt").unwrap().build();
+        assert_eq!(assembler.assemble("main.s", ".include \"loop.s\"").err().as_deref(), Some("main.s:1: loop.s:1: loop.s includes itself"));
+        machine.cpu.load_program(0, &program);
+        machine.run(200);
+        machine.expect().halted(true).register("reg_0", 42).check();
+    }
+
+    #[test]
+    fn negative_values_fill_operands_wider_than_8_bytes() {
+        let mut machine = TestMachine::with_data_size(128, 9);
+        let program = assemble(&machine, "LoadImmediate reg_0, -2\nHalt\n.data\n.word -1, 2").unwrap();
+        let data = &program[program.len() - 18..];
+        assert_eq!(data[..9], [0xff; 9]);
+        assert_eq!(data[9..], machine.cpu.endianness.encode(&BigUint::from(2u8), 9)[..]);
+        machine.cpu.load_program(0, &program);
+        machine.run(200);
+        machine.expect().halted(true).check();
+        assert_eq!(machine.register("reg_0"), (BigUint::one() << 72u32) - 2u8);
+    }
+
+    #[test]
+    fn includes_are_read_relative_to_the_includer() {
+        let directory = std::env::temp_dir().join(format!("assembler_includes_{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        std::fs::write(directory.join("main.s"), ".include \"lib/consts.s\"\nLoadImmediate reg_0, ANSWER\nHalt").unwrap();
+        std::fs::write(directory.join("lib/consts.s"), ".include \"more.s\"\n.equ ANSWER, BASE + 2").unwrap();
+        std::fs::write(directory.join("lib/more.s"), ".ifndef BASE\n.equ BASE, 40\n.endif").unwrap();
+        let mut machine = TestMachine::default();
+        let assembler = Assembler::new(&machine.cpu);
+        let main = directory.join("main.s");
+        let program = assembler.assemble_file(main.to_str().unwrap()).map(|writer| writer.build());
+        let missing = assembler.assemble("main.s", ".include \"missing.s\"").err().unwrap_or_default();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(missing.starts_with("main.s:1: cannot include missing.s"), "{}", missing);
+        machine.cpu.load_program(0, &program.unwrap());
+        machine.run(200);
+        machine.expect().halted(true).register("reg_0", 42).check();
+    }
+
+    #[test]
+    fn ifndef_assembles_when_the_name_is_undefined() {
+        let source = "
+            .equ ONE, 1
+            .ifndef ONE
+                    LoadImmediate reg_0, 1
+            .else
+                    LoadImmediate reg_0, 2
+            .endif
+            .ifndef TWO
+                    LoadImmediate reg_1, 3
+            .endif
+                    Halt
+        ";
+        let mut machine = TestMachine::default();
+        let program = assemble(&machine, source).unwrap();
+        machine.cpu.load_program(0, &program);
+        machine.run(200);
+        machine.expect().halted(true).register("reg_0", 2).register("reg_1", 3).check();
+    }
+
+    #[test]
+    fn globals_are_exported_from_objects() {
+        let machine = TestMachine::default();
+        let source = ".global start\n.global table\nstart: Halt\nlocal: Halt\n.data\ntable: .byte 1";
+        let object = Assembler::new(&machine.cpu).assemble("main.s", source).unwrap().try_build_object("main").unwrap();
+        let mut globals: Vec<_> = object.symbols.iter().filter(|symbol| symbol.global).map(|symbol| symbol.name.as_str()).collect();
+        globals.sort();
+        assert_eq!(globals, ["start", "table"]);
+        assert!(object.symbols.iter().any(|symbol| symbol.name == "local" && !symbol.global));
+        let error = Assembler::new(&machine.cpu).assemble("main.s", ".global nowhere\nHalt").unwrap().try_build_object("main").unwrap_err();
+        assert_eq!(error, "Global 'nowhere' is not defined");
+    }
+
+    #[test]
+    fn align_pads_the_current_section() {
+        let machine = TestMachine::default();
+        let source = "Halt\n.data\n.byte 1\n.align 4\nafter: .byte 2\n.bss\n.fill 3\n.align 2\nspace: .fill 1";
+        let object = Assembler::new(&machine.cpu).assemble("main.s", source).unwrap().try_build_object("main").unwrap();
+        assert_eq!(object.data, [1, 0, 0, 0, 2]);
+        assert_eq!(object.bss_size, 5);
+        let offset = |name: &str| object.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| (symbol.section, symbol.offset));
+        assert_eq!(offset("after"), Some((SectionKind::Data, 4)));
+        assert_eq!(offset("space"), Some((SectionKind::Bss, 4)));
+        assert_eq!(object.code, [InstructionSet::Halt as u8]);
+    }
+
+    #[test]
+    fn errors_point_at_the_line() {
+        let machine = TestMachine::default();
+        let error = |text: &str| assemble(&machine, text).unwrap_err();
+        assert_eq!(error("Halt\nJump"), "main.s:2: Jump takes 1 operand(s), got 0");
+        assert_eq!(error("LoadImmediate reg_0, 256"), "main.s:1: 256 does not fit in 1 byte(s)");
+        assert_eq!(error("Frobnicate"), "main.s:1: unknown instruction or macro 'Frobnicate'");
+        assert_eq!(error(".macro twice\nFrobnicate\n.endm\ntwice"), "main.s:4: main.s:2: unknown instruction or macro 'Frobnicate'");
+        assert_eq!(error(".macro again\nagain\n.endm\nagain").matches("recursive").count(), 1);
+        assert_eq!(error(".if 1\nHalt"), "main.s: .if without .endif");
+        assert_eq!(error(".equ X, 1\n.equ X, 2"), "main.s:2: 'X' is already defined");
+        assert_eq!(error(".byte '"), "main.s:1: unterminated character literal '");
+        assert_eq!(error("LoadImmediate reg_0, 'a"), "main.s:1: unterminated character literal 'a");
+        assert_eq!(error(".data\nHalt"), "main.s:2: Halt in the data section, instructions belong in code");
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::computer::{AccessKind, Endianness, Fault, Instruction, Memory, SubInstructions, CPU};
+
+// Basic block translation.
+//
+// With a BlockCache the CPU decodes straight line code once, from the program counter up to and
+// including the first instruction that can change control flow. Operand bytes are read at decode time
+// so the register ids and immediates are baked into the micro-ops, and each clock then runs a whole
+// instruction. Architectural state, cycle counts and cache statistics match the micro-step engine:
+// the opcode fetch and each operand read are still charged to the cache and the memory latency, in
+// the order the micro-step engine makes them. Code behind a device or outside the memory map is left
+// to the micro-step engine.
+//
+// Blocks are keyed by their start address and dropped when write_memory touches their bytes. Writes
+// made straight to cpu.memory, or by another core, only show up in the memory generation, and the
+// whole cache is dropped on the next lookup after one. Translation is skipped while an MMU is fitted
+// since the same address may then map to different code.
+//
+// cpu.block_cache = Some(BlockCache::default());
+
+// Longest block decoded in one go, loops of straight line code longer than this just chain blocks
+const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+// Granularity of the index from written addresses to the blocks covering them
+const PAGE_BITS: u32 = 6;
+
+// Operand micro-ops keep the address their operand was read from, so the read can still be charged
+pub enum DecodedOp {
+    // Micro-ops that do not read the instruction stream run unchanged
+    Micro(SubInstructions),
+    // Byte immediate already read from the program
+    Immediate(u8, usize),
+    // Wide immediate already read from the program, in the operand's byte order
+    WideImmediate(Vec<u8>, Endianness, usize),
+    // Register operands already read from the program, the privilege check still runs each time
+    LoadFromRegister(u8, usize),
+    StoreToRegister(u8, usize),
+}
+
+impl DecodedOp {
+    pub fn execute(&self, cpu: &mut CPU) -> Result<(), Fault> {
+        match self {
+            DecodedOp::Micro(sub_instruction) => sub_instruction.execute(cpu),
+            DecodedOp::Immediate(value, operand) => {
+                cpu.charge_fetch(*operand, 1);
+                SubInstructions::LoadImmediateInternal(*value).execute(cpu)
+            }
+            DecodedOp::WideImmediate(bytes, endianness, operand) => {
+                cpu.charge_fetch(*operand, bytes.len());
+                cpu.set_decoded(cpu.special.accumulator, bytes, *endianness);
+                Ok(())
+            }
+            DecodedOp::LoadFromRegister(register, operand) => {
+                cpu.charge_fetch(*operand, 1);
+                cpu.check_register_access(*register, AccessKind::Read)?;
+                SubInstructions::LoadFromRegisterInternal(*register).execute(cpu)
+            }
+            DecodedOp::StoreToRegister(register, operand) => {
+                cpu.charge_fetch(*operand, 1);
+                cpu.check_register_access(*register, AccessKind::Write)?;
+                SubInstructions::StoreToRegisterInternal(*register).execute(cpu)
+            }
+        }
+    }
+
+    // The micro-op this stands for, the timing model charges by variant
+    pub fn timed_as(&self) -> &SubInstructions {
+        match self {
+            DecodedOp::Micro(sub_instruction) => sub_instruction,
+            DecodedOp::Immediate(..) => &SubInstructions::LoadImmediate(0),
+            DecodedOp::WideImmediate(..) => &SubInstructions::LoadImmediateWide(0, 0, Endianness::Big),
+            DecodedOp::LoadFromRegister(..) => &SubInstructions::LoadFromRegister(0),
+            DecodedOp::StoreToRegister(..) => &SubInstructions::StoreToRegister(0),
+        }
+    }
+}
+
+pub struct DecodedInstruction {
+    pub address: usize,
+    pub opcode: u8,
+    pub privileged: bool,
+    pub ops: Vec<DecodedOp>,
+}
+
+pub struct Block {
+    pub start: usize,
+    // One past the last byte of the last instruction
+    pub end: usize,
+    pub instructions: Vec<DecodedInstruction>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    pub translated: u64,
+    pub invalidated: u64,
+    pub instructions: u64,
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: HashMap<usize, Rc<Block>>,
+    // Start addresses of the blocks overlapping each page
+    pages: HashMap<usize, Vec<usize>>,
+    // Where execution continues when the last instruction ran on into the next one of its block
+    next: Option<(Rc<Block>, usize)>,
+    // Memory generation the blocks were last checked against
+    generation: u64,
+    // Blocks dropped so far, and how many had been when the last lookup returned, so advance can tell
+    // the block it ran is still cached without looking it up again
+    removals: u64,
+    removals_at_lookup: u64,
+    pub stats: BlockStats,
+}
+
+impl BlockCache {
+    // The decoded instruction at address with its block, translating a new block if needed.
+    // None when not even one instruction can be decoded there, the micro-step engine then runs it
+    pub fn lookup(&mut self, memory: &Memory, instruction_set: &HashMap<u8, Instruction>, address: usize) -> Option<(Rc<Block>, usize)> {
+        if memory.generation() != self.generation {
+            self.clear();
+            self.generation = memory.generation();
+        }
+        self.removals_at_lookup = self.removals;
+        if let Some((block, index)) = &self.next {
+            if block.instructions[*index].address == address {
+                return Some((block.clone(), *index));
+            }
+        }
+        if let Some(block) = self.blocks.get(&address) {
+            return Some((block.clone(), 0));
+        }
+        let block = Rc::new(decode_block(memory, instruction_set, address)?);
+        for page in (block.start >> PAGE_BITS)..=((block.end - 1) >> PAGE_BITS) {
+            self.pages.entry(page).or_default().push(block.start);
+        }
+        self.blocks.insert(address, block.clone());
+        self.stats.translated += 1;
+        Some((block, 0))
+    }
+
+    // Called after running instruction index of block, with the program counter it left behind
+    pub fn advance(&mut self, block: Rc<Block>, index: usize, address: usize) {
+        self.stats.instructions += 1;
+        let still_cached = self.removals == self.removals_at_lookup
+            || self.blocks.get(&block.start).is_some_and(|cached| Rc::ptr_eq(cached, &block));
+        let falls_through = block.instructions.get(index + 1).is_some_and(|next| next.address == address);
+        self.next = (still_cached && falls_through).then_some((block, index + 1));
+    }
+
+    // Drops every block holding a byte in start..end
+    pub fn invalidate(&mut self, start: usize, end: usize) {
+        if self.blocks.is_empty() || start >= end {
+            return;
+        }
+        for page in (start >> PAGE_BITS)..=((end - 1) >> PAGE_BITS) {
+            let Some(starts) = self.pages.get(&page) else {
+                continue;
+            };
+            let stale: Vec<usize> = starts
+                .iter()
+                .copied()
+                .filter(|block_start| self.blocks.get(block_start).is_some_and(|block| block.start < end && start < block.end))
+                .collect();
+            for block_start in stale {
+                self.remove(block_start);
+            }
+        }
+    }
+
+    // Marks the writes that took memory from generation before to after as handled, once their bytes
+    // have been invalidated. Writes the cache had not caught up with still clear it on the next lookup
+    pub fn seen(&mut self, before: u64, after: u64) {
+        if self.generation == before {
+            self.generation = after;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.stats.invalidated += self.blocks.len() as u64;
+        self.removals += self.blocks.len() as u64;
+        self.blocks.clear();
+        self.pages.clear();
+        self.next = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn remove(&mut self, block_start: usize) {
+        let Some(block) = self.blocks.remove(&block_start) else {
+            return;
+        };
+        for page in (block.start >> PAGE_BITS)..=((block.end - 1) >> PAGE_BITS) {
+            if let Some(starts) = self.pages.get_mut(&page) {
+                starts.retain(|start| *start != block_start);
+            }
+        }
+        if self.next.as_ref().is_some_and(|(next, _)| Rc::ptr_eq(next, &block)) {
+            self.next = None;
+        }
+        self.stats.invalidated += 1;
+        self.removals += 1;
+    }
+}
+
+fn ends_block(sub_instruction: &SubInstructions) -> bool {
+    matches!(
+        sub_instruction,
+        SubInstructions::Halt
+            | SubInstructions::Jump
+            | SubInstructions::JumpIfFlag(..)
+            | SubInstructions::JumpIfNotFlag(..)
+            | SubInstructions::SystemCall
+            | SubInstructions::ReturnFromTrap
+    )
+}
+
+// Stops before an unknown opcode or an instruction running past the end of memory
+fn decode_block(memory: &Memory, instruction_set: &HashMap<u8, Instruction>, start: usize) -> Option<Block> {
+    let mut instructions = Vec::new();
+    let mut address = start;
+    while address < memory.size() && instructions.len() < MAX_BLOCK_INSTRUCTIONS {
+        let opcode = memory.read(address);
+        let Some(instruction) = instruction_set.get(&opcode) else {
+            break;
+        };
+        let end = address + 1 + instruction.args as usize;
+        if end > memory.size() {
+            break;
+        }
+        let ops = instruction
+            .sub_instructions
+            .iter()
+            .map(|sub_instruction| match sub_instruction {
+                SubInstructions::LoadImmediate(offset) => {
+                    let operand = address + *offset as usize;
+                    DecodedOp::Immediate(memory.read(operand), operand)
+                }
+                SubInstructions::LoadImmediateWide(offset, width, endianness) => {
+                    let operand = address + *offset as usize;
+                    DecodedOp::WideImmediate(memory.read_chunk(operand, operand + *width as usize).to_vec(), *endianness, operand)
+                }
+                SubInstructions::LoadFromRegister(offset) => {
+                    let operand = address + *offset as usize;
+                    DecodedOp::LoadFromRegister(memory.read(operand), operand)
+                }
+                SubInstructions::StoreToRegister(offset) => {
+                    let operand = address + *offset as usize;
+                    DecodedOp::StoreToRegister(memory.read(operand), operand)
+                }
+                other => DecodedOp::Micro(other.clone()),
+            })
+            .collect();
+        instructions.push(DecodedInstruction { address, opcode, privileged: instruction.privileged, ops });
+        address = end;
+        if instruction.sub_instructions.iter().any(ends_block) {
+            break;
+        }
+    }
+    if instructions.is_empty() {
+        return None;
+    }
+    Some(Block { start, end: address, instructions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{Cache, CacheConfig};
+    use crate::harness::TestMachine;
+    use crate::InstructionSet;
+
+    // Counts reg_0 down from 5, the loop body is one block and the Halt another
+    fn countdown(translated: bool) -> TestMachine {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        let reg_2 = machine.reg("reg_2");
+        machine.set_register("reg_0", 5);
+        if translated {
+            machine.cpu.block_cache = Some(BlockCache::default());
+        }
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::SubImmediate, &[reg_0, 1])
+                .add_instruction(InstructionSet::PushReg, &[reg_0])
+                .add_instruction(InstructionSet::PopReg, &[reg_1])
+                .add_instruction(InstructionSet::JumpNotEqual, &[reg_2, reg_1, 0])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine
+    }
+
+    #[test]
+    fn translated_run_matches_micro_steps() {
+        let mut stepped = countdown(false);
+        let mut translated = countdown(true);
+        stepped.run(10_000);
+        let clocks = translated.run(10_000);
+        translated.expect().halted(true).register("reg_0", 0).register("reg_1", 0).check();
+        assert_eq!(translated.cpu.register_data, stepped.cpu.register_data);
+        assert_eq!(translated.cpu.cycles, stepped.cpu.cycles);
+        assert_eq!(translated.cpu.timing_report.total(), stepped.cpu.timing_report.total());
+        // One clock per instruction
+        assert_eq!(clocks as u64, translated.cpu.timing_report.total().retired);
+        let stats = translated.cpu.block_cache.as_ref().unwrap().stats;
+        assert_eq!(stats.translated, 2);
+        assert_eq!(stats.instructions, 5 * 4 + 1);
+    }
+
+    #[test]
+    fn translated_fetches_reach_the_cache_like_micro_steps() {
+        let run = |translated: bool| {
+            let mut machine = countdown(translated);
+            machine.cpu.cache = Some(Cache::new(CacheConfig { size: 16, line_size: 4, miss_penalty: 3, ..CacheConfig::default() }));
+            machine.cpu.timing.memory_latency = 2;
+            machine.cpu.timing.set_latency(&SubInstructions::LoadImmediate(0), 4);
+            machine.run(10_000);
+            machine
+        };
+        let (stepped, translated) = (run(false), run(true));
+        translated.expect().halted(true).register("reg_0", 0).check();
+        let stats = |machine: &TestMachine| machine.cpu.cache.as_ref().unwrap().stats;
+        assert_eq!(stats(&translated), stats(&stepped));
+        assert!(stats(&translated).misses > 0);
+        assert_eq!(translated.cpu.cycles, stepped.cpu.cycles);
+        assert_eq!(translated.cpu.timing_report.rows(), stepped.cpu.timing_report.rows());
+    }
+
+    #[test]
+    fn writes_to_code_drop_its_blocks() {
+        let mut machine = TestMachine::default();
+        machine.cpu.block_cache = Some(BlockCache::default());
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        let reg_2 = machine.reg("reg_2");
+        let reg_c = machine.reg("reg_c");
+        // The first pass patches the immediate of the first instruction, the second pass must see it
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg_1, 1])   // 0, immediate at 2
+                .add_instruction(InstructionSet::AddImmediate, &[reg_2, 1])    // 3
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_0, 7])   // 6
+                .add_instruction(InstructionSet::StoreToMemory, &[reg_0, 2])   // 9
+                .add_instruction(InstructionSet::JumpNotEqual, &[reg_c, reg_2, 2]) // 12
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true).register("reg_1", 7).register("reg_2", 2).check();
+        // Both passes patch the block they run in
+        assert_eq!(machine.cpu.block_cache.as_ref().unwrap().stats.invalidated, 2);
+    }
+
+    #[test]
+    fn direct_memory_writes_drop_every_block() {
+        let mut machine = TestMachine::default();
+        machine.cpu.block_cache = Some(BlockCache::default());
+        let reg_1 = machine.reg("reg_1");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg_1, 1])   // 0, immediate at 2
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true).register("reg_1", 1).check();
+        // Patched behind the CPU's back, as a debugger or another core would
+        machine.load(2, &[5]);
+        machine.cpu.unhalt();
+        machine.cpu.set_program_counter(0u32.into());
+        machine.run(100);
+        machine.expect().halted(true).register("reg_1", 5).check();
+        assert_eq!(machine.cpu.block_cache.as_ref().unwrap().stats.invalidated, 1);
+    }
+
+    #[test]
+    fn undecodable_code_falls_back_to_micro_steps() {
+        // A fault on the fetch is raised by the micro-step engine as before
+        let mut machine = TestMachine::new(4);
+        machine.cpu.block_cache = Some(BlockCache::default());
+        machine.load(0, &[InstructionSet::Jump as u8, 9]);
+        machine.run(100);
+        machine.expect().fault(Some(Fault::BusError { address: 9 })).check();
+    }
+
+    // cargo test --release translated_cycles_per_second -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn translated_cycles_per_second() {
+        let mut machine = TestMachine::with_data_size(64, 4);
+        machine.cpu.block_cache = Some(BlockCache::default());
+        let reg_0 = machine.reg("reg_0") as u64;
+        let reg_2 = machine.reg("reg_2") as u64;
+        machine.program(|p| {
+            p.add_instruction_wide(InstructionSet::AddImmediate, &[reg_0, 1])
+                .add_instruction_wide(InstructionSet::JumpNotEqual, &[reg_2, reg_0, 0])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let start = std::time::Instant::now();
+        machine.run(1_000_000);
+        let elapsed = start.elapsed();
+        let cycles = machine.cpu.cycles;
+        println!("{} cycles in {:?}, {:.0} cycles per second", cycles, elapsed, cycles as f64 / elapsed.as_secs_f64());
+    }
+}
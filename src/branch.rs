@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+// Branch prediction for the conditional jumps, which all resolve through JumpIfFlag or JumpIfNotFlag.
+//
+// The CPU asks its predictor before each conditional jump resolves, then tells it the outcome. A wrong
+// guess stalls the CPU for misprediction_penalty cycles, and the pipeline mode only flushes on a wrong
+// guess instead of on every taken jump.
+//
+// cpu.branch_unit = Some(BranchUnit::new(Box::new(TwoBit::new(64)), 3));
+
+pub trait BranchPredictor {
+    fn predict(&self, address: usize) -> bool;
+    fn update(&mut self, address: usize, taken: bool, target: usize);
+}
+
+pub struct StaticNotTaken;
+
+impl BranchPredictor for StaticNotTaken {
+    fn predict(&self, _address: usize) -> bool {
+        false
+    }
+
+    fn update(&mut self, _address: usize, _taken: bool, _target: usize) {}
+}
+
+// Remembers the last outcome of each branch, table entries are shared by addresses that alias
+pub struct OneBit {
+    table: Vec<bool>,
+}
+
+impl OneBit {
+    pub fn new(entries: usize) -> Self {
+        assert!(entries > 0, "Predictor table needs at least one entry");
+        OneBit { table: vec![false; entries] }
+    }
+}
+
+impl BranchPredictor for OneBit {
+    fn predict(&self, address: usize) -> bool {
+        self.table[address % self.table.len()]
+    }
+
+    fn update(&mut self, address: usize, taken: bool, _target: usize) {
+        let index = address % self.table.len();
+        self.table[index] = taken;
+    }
+}
+
+// Saturating counters 0..=3, 2 and 3 predict taken, they start weakly not taken
+fn counter_update(counter: &mut u8, taken: bool) {
+    *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+}
+
+pub struct TwoBit {
+    counters: Vec<u8>,
+}
+
+impl TwoBit {
+    pub fn new(entries: usize) -> Self {
+        assert!(entries > 0, "Predictor table needs at least one entry");
+        TwoBit { counters: vec![1; entries] }
+    }
+}
+
+impl BranchPredictor for TwoBit {
+    fn predict(&self, address: usize) -> bool {
+        self.counters[address % self.counters.len()] >= 2
+    }
+
+    fn update(&mut self, address: usize, taken: bool, _target: usize) {
+        let index = address % self.counters.len();
+        counter_update(&mut self.counters[index], taken);
+    }
+}
+
+// Two bit counters indexed by the branch address xor the last history_bits global outcomes
+pub struct Gshare {
+    history_bits: u32,
+    history: usize,
+    counters: Vec<u8>,
+}
+
+impl Gshare {
+    pub fn new(history_bits: u32) -> Self {
+        assert!((1..usize::BITS).contains(&history_bits), "History bits must be between 1 and {}", usize::BITS - 1);
+        Gshare { history_bits, history: 0, counters: vec![1; 1 << history_bits] }
+    }
+
+    fn index(&self, address: usize) -> usize {
+        (address ^ self.history) & ((1 << self.history_bits) - 1)
+    }
+}
+
+impl BranchPredictor for Gshare {
+    fn predict(&self, address: usize) -> bool {
+        self.counters[self.index(address)] >= 2
+    }
+
+    fn update(&mut self, address: usize, taken: bool, _target: usize) {
+        let index = self.index(address);
+        counter_update(&mut self.counters[index], taken);
+        self.history = ((self.history << 1) | taken as usize) & ((1 << self.history_bits) - 1);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BranchStats {
+    pub executed: u64,
+    pub taken: u64,
+    pub correct: u64,
+    pub last_target: usize,
+}
+
+impl BranchStats {
+    pub fn accuracy(&self) -> f64 {
+        if self.executed == 0 {
+            0.0
+        } else {
+            self.correct as f64 / self.executed as f64
+        }
+    }
+}
+
+pub struct BranchUnit {
+    pub predictor: Box<dyn BranchPredictor>,
+    // Stall cycles charged for each wrong guess
+    pub misprediction_penalty: u32,
+    // Keyed by the address of the conditional jump's opcode
+    pub stats: HashMap<usize, BranchStats>,
+    // Whether the most recent conditional jump was predicted correctly, taken by the pipeline mode
+    pub last_correct: Option<bool>,
+}
+
+impl BranchUnit {
+    pub fn new(predictor: Box<dyn BranchPredictor>, misprediction_penalty: u32) -> Self {
+        BranchUnit {
+            predictor,
+            misprediction_penalty,
+            stats: HashMap::new(),
+            last_correct: None,
+        }
+    }
+
+    // Predicts, then trains the predictor on the outcome, returns whether the prediction was correct
+    pub fn resolve(&mut self, address: usize, taken: bool, target: usize) -> bool {
+        let correct = self.predictor.predict(address) == taken;
+        self.predictor.update(address, taken, target);
+        let stats = self.stats.entry(address).or_default();
+        stats.executed += 1;
+        stats.taken += taken as u64;
+        stats.correct += correct as u64;
+        stats.last_target = target;
+        self.last_correct = Some(correct);
+        correct
+    }
+
+    pub fn total(&self) -> BranchStats {
+        self.stats.values().fold(BranchStats::default(), |total, stats| BranchStats {
+            executed: total.executed + stats.executed,
+            taken: total.taken + stats.taken,
+            correct: total.correct + stats.correct,
+            last_target: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::pipeline::{Pipeline, PipelineConfig};
+    use crate::InstructionSet;
+
+    fn accuracy_on(predictor: &mut dyn BranchPredictor, outcomes: &[bool]) -> usize {
+        outcomes.iter().filter(|taken| {
+            let correct = predictor.predict(4) == **taken;
+            predictor.update(4, **taken, 0);
+            correct
+        }).count()
+    }
+
+    #[test]
+    fn predictors_learn_loop_branches() {
+        // A loop branch taken 4 times then falling through, run twice
+        let outcomes = [true, true, true, true, false, true, true, true, true, false];
+        assert_eq!(accuracy_on(&mut StaticNotTaken, &outcomes), 2);
+        assert_eq!(accuracy_on(&mut OneBit::new(16), &outcomes), 6);
+        assert_eq!(accuracy_on(&mut TwoBit::new(16), &outcomes), 7);
+    }
+
+    #[test]
+    fn gshare_learns_alternating_pattern() {
+        let outcomes: Vec<bool> = (0..40).map(|i| i % 2 == 0).collect();
+        let mut gshare = Gshare::new(4);
+        accuracy_on(&mut gshare, &outcomes);
+        assert_eq!(accuracy_on(&mut gshare, &outcomes), 40);
+        // Without history the counter flips between its two middle states and is always wrong
+        assert_eq!(accuracy_on(&mut TwoBit::new(16), &outcomes), 0);
+    }
+
+    // Counts reg_0 down from 5, the JumpNotEqual at address 3 is taken 4 times
+    fn countdown(predictor: Box<dyn BranchPredictor>, penalty: u32) -> TestMachine {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_2 = machine.reg("reg_2");
+        machine.set_register("reg_0", 5);
+        machine.cpu.branch_unit = Some(BranchUnit::new(predictor, penalty));
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::SubImmediate, &[reg_0, 1])      // 0
+                .add_instruction(InstructionSet::JumpNotEqual, &[reg_2, reg_0, 0]) // 3
+                .add_instruction(InstructionSet::Halt, &[]);                   // 7
+        });
+        machine
+    }
+
+    #[test]
+    fn reports_accuracy_per_branch_address() {
+        let mut machine = countdown(Box::new(TwoBit::new(16)), 0);
+        machine.run(1000);
+        machine.expect().halted(true).register("reg_0", 0).check();
+        let stats = machine.cpu.branch_unit.as_ref().unwrap().stats[&3];
+        assert_eq!(stats, BranchStats { executed: 5, taken: 4, correct: 3, last_target: 0 });
+    }
+
+    #[test]
+    fn mispredictions_cost_cycles() {
+        let mut free = countdown(Box::new(StaticNotTaken), 0);
+        let mut charged = countdown(Box::new(StaticNotTaken), 10);
+        // 4 of the 5 outcomes are mispredicted
+        assert_eq!(charged.run(1000), free.run(1000) + 4 * 10);
+    }
+
+    #[test]
+    fn pipeline_flushes_only_on_misprediction() {
+        let mut machine = countdown(Box::new(TwoBit::new(16)), 0);
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        pipeline.run(&mut machine.cpu, 100);
+        machine.expect().halted(true).check();
+        assert_eq!(pipeline.stats.flushes, 2 * 2);
+    }
+}
@@ -0,0 +1,240 @@
+// Set associative cache model.
+//
+// The cache only tracks tags, data always lives in Memory, so guest visible behaviour is unchanged and
+// the cache just counts what a real one would have done. An address splits into
+//
+// | tag ... | set index | line offset |
+//
+// Write back caches allocate on a write miss and write a line to memory when a dirty line is evicted.
+// Write through caches write every store to memory and do not allocate on a write miss.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    pub size: usize,        // Total bytes
+    pub line_size: usize,   // Bytes per line
+    pub associativity: usize,
+    pub write_policy: WritePolicy,
+    pub replacement: Replacement,
+    // Extra clock cycles the CPU stalls for on each miss, 0 to only collect statistics
+    pub miss_penalty: u32,
+    pub seed: u64,          // For Random replacement
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            size: 64,
+            line_size: 8,
+            associativity: 2,
+            write_policy: WritePolicy::WriteBack,
+            replacement: Replacement::Lru,
+            miss_penalty: 0,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // Writes that reached memory, every store when writing through, dirty evictions when writing back
+    pub memory_writes: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let accesses = self.hits + self.misses;
+        if accesses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / accesses as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: usize,
+    last_used: u64,
+    filled: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cache {
+    pub config: CacheConfig,
+    pub stats: CacheStats,
+    sets: Vec<Vec<Line>>,
+    tick: u64,
+    random_state: u64,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        let set_bytes = config.line_size * config.associativity;
+        assert!(set_bytes > 0 && config.size.is_multiple_of(set_bytes), "Cache size {} is not a multiple of line size * associativity {}", config.size, set_bytes);
+        let set_count = config.size / set_bytes;
+        Cache {
+            config,
+            stats: CacheStats::default(),
+            sets: vec![vec![Line::default(); config.associativity]; set_count],
+            tick: 0,
+            // xorshift gets stuck on 0
+            random_state: config.seed.max(1),
+        }
+    }
+
+    pub fn line_of(&self, address: usize) -> usize {
+        address / self.config.line_size
+    }
+
+    // Records one access to the line holding a physical address, returns whether it hit
+    pub fn access(&mut self, address: usize, write: bool) -> bool {
+        let line_address = self.line_of(address);
+        let set_index = line_address % self.sets.len();
+        let tag = line_address / self.sets.len();
+        self.tick += 1;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+        if write && !write_back {
+            self.stats.memory_writes += 1;
+        }
+
+        if let Some(line) = self.sets[set_index].iter_mut().find(|line| line.valid && line.tag == tag) {
+            self.stats.hits += 1;
+            line.last_used = self.tick;
+            line.dirty |= write && write_back;
+            return true;
+        }
+
+        self.stats.misses += 1;
+        if write && !write_back {
+            return false;
+        }
+        let victim = self.choose_victim(set_index);
+        let line = &mut self.sets[set_index][victim];
+        if line.valid {
+            self.stats.evictions += 1;
+            if line.dirty {
+                self.stats.memory_writes += 1;
+            }
+        }
+        *line = Line { valid: true, dirty: write, tag, last_used: self.tick, filled: self.tick };
+        false
+    }
+
+    fn choose_victim(&mut self, set_index: usize) -> usize {
+        let set = &self.sets[set_index];
+        if let Some(empty) = set.iter().position(|line| !line.valid) {
+            return empty;
+        }
+        let oldest_by = |key: fn(&Line) -> u64| {
+            set.iter().enumerate().min_by_key(|(_, line)| key(line)).map(|(way, _)| way).unwrap()
+        };
+        match self.config.replacement {
+            Replacement::Lru => oldest_by(|line| line.last_used),
+            Replacement::Fifo => oldest_by(|line| line.filled),
+            Replacement::Random => {
+                self.random_state ^= self.random_state << 13;
+                self.random_state ^= self.random_state >> 7;
+                self.random_state ^= self.random_state << 17;
+                (self.random_state % self.config.associativity as u64) as usize
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::InstructionSet;
+
+    // 4 sets of 2 ways with 4 byte lines, addresses 32 bytes apart share a set
+    fn small_cache(write_policy: WritePolicy, replacement: Replacement) -> Cache {
+        Cache::new(CacheConfig { size: 32, line_size: 4, associativity: 2, write_policy, replacement, ..CacheConfig::default() })
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut cache = small_cache(WritePolicy::WriteBack, Replacement::Lru);
+        assert!(!cache.access(0, false));
+        assert!(!cache.access(32, false));
+        assert!(cache.access(1, false));
+        // Set 0 is full, 32 was used longest ago
+        assert!(!cache.access(64, false));
+        assert!(cache.access(0, false));
+        assert!(!cache.access(32, false));
+        assert_eq!(cache.stats, CacheStats { hits: 2, misses: 4, evictions: 2, memory_writes: 0 });
+    }
+
+    #[test]
+    fn fifo_evicts_first_filled() {
+        let mut cache = small_cache(WritePolicy::WriteBack, Replacement::Fifo);
+        cache.access(0, false);
+        cache.access(32, false);
+        cache.access(0, false);
+        cache.access(64, false);
+        assert!(!cache.access(0, false));
+        assert!(cache.access(64, false));
+    }
+
+    #[test]
+    fn write_back_writes_dirty_lines_on_eviction() {
+        let mut cache = small_cache(WritePolicy::WriteBack, Replacement::Lru);
+        cache.access(0, true);
+        cache.access(0, true);
+        cache.access(32, false);
+        assert_eq!(cache.stats.memory_writes, 0);
+        cache.access(64, false);
+        assert_eq!(cache.stats.memory_writes, 1);
+    }
+
+    #[test]
+    fn write_through_does_not_allocate() {
+        let mut cache = small_cache(WritePolicy::WriteThrough, Replacement::Lru);
+        assert!(!cache.access(0, true));
+        assert!(!cache.access(0, false));
+        assert!(cache.access(0, true));
+        assert_eq!(cache.stats, CacheStats { hits: 1, misses: 2, evictions: 0, memory_writes: 2 });
+    }
+
+    #[test]
+    fn program_accesses_go_through_cache() {
+        let mut machine = TestMachine::default();
+        machine.cpu.cache = Some(Cache::new(CacheConfig { miss_penalty: 10, ..CacheConfig::default() }));
+        let reg_0 = machine.reg("reg_0");
+        machine.load(0x30, &[7]);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadFromMemory, &[0x30, reg_0])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let cycles = machine.run(200);
+        machine.expect().halted(true).register("reg_0", 7).check();
+        let stats = machine.cpu.cache.as_ref().unwrap().stats;
+        // Code fits in line 0, the load misses on line 6
+        assert_eq!((stats.hits, stats.misses), (3, 2));
+        let mut uncached = TestMachine::default();
+        uncached.load(0x30, &[7]);
+        uncached.program(|p| {
+            p.add_instruction(InstructionSet::LoadFromMemory, &[0x30, reg_0])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        assert_eq!(cycles, uncached.run(200) + 2 * 10);
+    }
+}
@@ -3,24 +3,35 @@ use num_bigint::BigUint;
 use num_traits::One; // Ensure the trait is in scope for BigUint::one()
 use num_traits::ToPrimitive;
 use num_traits::Zero;
-const USIZE_SIZE: usize = mem::size_of::<usize>();
 pub struct Memory {
     data: Vec<u8>,
+    // Bumped by every write so translated blocks can tell memory changed behind their back
+    generation: u64,
 }
 
 impl Memory {
     pub fn new(size: usize) -> Self {
         Memory {
             data: vec![0; size],
+            generation: 0,
         }
     }
 
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn read(&self, address: usize) -> u8 {
         self.data[address]
     }
 
     pub fn write(&mut self, address: usize, value: u8) {
         self.data[address] = value;
+        self.generation += 1;
     }
 
     pub fn read_chunk(&self, start: usize, end: usize) -> &[u8] {
@@ -29,10 +40,12 @@ impl Memory {
 
     pub fn write_chunk(&mut self, start: usize, data: &[u8]) {
         self.data[start..start + data.len()].copy_from_slice(data);
+        self.generation += 1;
     }
 
     pub fn clear_chunk(&mut self, start: usize, end: usize) {
         self.data[start..end].fill(0);
+        self.generation += 1;
     }
 }
 
@@ -47,6 +60,10 @@ impl Storage {
         }
     }
 
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn read(&self, address: usize) -> u8 {
         self.data[address]
     }
@@ -64,11 +81,65 @@ impl Storage {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+impl Endianness {
+    pub fn decode(&self, bytes: &[u8]) -> BigUint {
+        match self {
+            Endianness::Big => BigUint::from_bytes_be(bytes),
+            Endianness::Little => BigUint::from_bytes_le(bytes),
+        }
+    }
+
+    // Fast path decode for at most 8 bytes
+    pub fn decode_u64(&self, bytes: &[u8]) -> u64 {
+        let fold = |value: u64, byte: &u8| (value << 8) | *byte as u64;
+        match self {
+            Endianness::Big => bytes.iter().fold(0, fold),
+            Endianness::Little => bytes.iter().rev().fold(0, fold),
+        }
+    }
+
+    // Fast path encode, fills all of out, zero extending or dropping the bytes above out.len()
+    pub fn encode_u64_into(&self, value: u64, out: &mut [u8]) {
+        let size = out.len();
+        if size <= 8 {
+            match self {
+                Endianness::Big => out.copy_from_slice(&value.to_be_bytes()[8 - size..]),
+                Endianness::Little => out.copy_from_slice(&value.to_le_bytes()[..size]),
+            }
+            return;
+        }
+        for (index, byte) in out.iter_mut().enumerate() {
+            let significance = match self {
+                Endianness::Big => size - 1 - index,
+                Endianness::Little => index,
+            };
+            *byte = if significance < 8 { (value >> (8 * significance)) as u8 } else { 0 };
+        }
+    }
+
+    // Encodes value in exactly size bytes, dropping any bytes above size
+    pub fn encode(&self, value: &BigUint, size: usize) -> Vec<u8> {
+        let mut bytes = value.to_bytes_le();
+        bytes.resize(size, 0);
+        if *self == Endianness::Big {
+            bytes.reverse();
+        }
+        bytes
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum SubInstructions {
     NoOperation,
     Halt,
     LoadImmediate(u8),
+    LoadImmediateWide(u8, u8, Endianness), // Offset, width in bytes, byte order
     LoadImmediateInternal(u8),
     LoadFromMemory,
     LoadFromRegister(u8),
@@ -86,162 +157,504 @@ pub enum SubInstructions {
     Compare,
     JumpIfFlag(BigUint, BigUint),
     JumpIfNotFlag(BigUint, BigUint),
+    SystemCall,
+    ReturnFromTrap,
+    // Atomic read-modify-write at memory_address, done in a single clock so no other core can interleave
+    CompareAndSwap, // If memory equals reg_a store reg_b and set the zero flag, the old value goes to the accumulator
+    FetchAdd,       // Add reg_b to memory, the old value goes to the accumulator
+    Fence,
+    // Effective addresses, computed into the accumulator for SetMemoryAddress without touching the flags.
+    // Displacements are two's complement and the sum wraps at the accumulator's width
+    AddressOffset(u8, u8, u8, Endianness), // Base register offset, displacement offset, width, byte order
+    AddressIndexed(u8, u8, u8),            // Base register offset, index register offset, Imm8 scale offset
+    AddressPcRelative(u8, u8, Endianness), // Displacement offset, width, byte order, from the opcode's address
 }
 
 // Implement execution logic for SubInstructions
 impl SubInstructions {
-    pub fn execute(&self, cpu: &mut CPU) {
+    pub fn execute(&self, cpu: &mut CPU) -> Result<(), Fault> {
         match self {
             SubInstructions::NoOperation => {}
             SubInstructions::Halt => {
                 cpu.halted = true;
             }
             SubInstructions::LoadImmediate(data_offset) => {
-                let value = cpu.read_program_memory_offset(*data_offset);
-                cpu.set_accumulator(BigUint::from(value));
+                let value = cpu.read_program_memory_offset(*data_offset)?;
+                cpu.set_word(cpu.special.accumulator, value as u64);
+            }
+            SubInstructions::LoadImmediateWide(data_offset, width, endianness) => {
+                let bytes = cpu.read_program_memory_operand(*data_offset, *width)?;
+                cpu.set_decoded(cpu.special.accumulator, &bytes, *endianness);
             }
-            SubInstructions::LoadImmediateInternal(value) => {;
-                cpu.set_accumulator(BigUint::from(*value));
+            SubInstructions::LoadImmediateInternal(value) => {
+                cpu.set_word(cpu.special.accumulator, *value as u64);
             }
             SubInstructions::LoadFromMemory => {
-                // TODO: handle longer numbers
-                let address = cpu.read_register_string("memory_address").clone().unwrap()[0] as usize;
-                let value = cpu.memory.read_chunk(address, address + cpu.cpu_data_size as usize);
-                cpu.set_accumulator(BigUint::from_bytes_be(value));
+                let address = cpu.get_memory_address()?;
+                let value = cpu.read_memory(address, cpu.cpu_data_size as usize, AccessKind::Read)?;
+                cpu.set_decoded(cpu.special.accumulator, &value, cpu.endianness);
             }
             SubInstructions::LoadFromRegister(data_offset) => {
-                let register = cpu.read_program_memory_offset(*data_offset);
-                let bytes = cpu.read_register(register).unwrap_or(&[0]);
-                cpu.set_accumulator(BigUint::from_bytes_be(bytes));
+                let register = cpu.read_program_memory_offset(*data_offset)?;
+                cpu.check_register_access(register, AccessKind::Read)?;
+                cpu.load_register_to_accumulator(register);
             }
             SubInstructions::LoadFromRegisterInternal(register) => {
-                let bytes= cpu.read_register(*register).unwrap_or(&[0]).to_vec();
-                cpu.set_accumulator(BigUint::from_bytes_be(&bytes));
+                cpu.load_register_to_accumulator(*register);
             }
             SubInstructions::SetMemoryAddress => {
-               let value = cpu.get_accumulator_bytes().to_vec();
-               let _ = cpu.write_register_string("memory_address", value.as_slice());
+                let memory_address = cpu.special.memory_address.expect("Memory address register not found.");
+                cpu.copy_register_bytes(cpu.special.accumulator, memory_address);
             }
             SubInstructions::StoreToMemory => {
-                // TODO: handle longer numbers
-                let address = cpu.read_register_string("memory_address").clone().unwrap()[0] as usize;
+                let address = cpu.get_memory_address()?;
                 let value = cpu.get_accumulator_bytes().to_vec();
-                cpu.memory.write_chunk(address, value.as_slice());
+                cpu.write_memory(address, value.as_slice())?;
             }
             SubInstructions::StoreToRegister(data_offset) => {
-                let register = cpu.read_program_memory_offset(*data_offset);
-                let value = cpu.get_accumulator_bytes().to_vec();
-                let _ = cpu.write_register(register, value.as_slice());
+                let register = cpu.read_program_memory_offset(*data_offset)?;
+                cpu.check_register_access(register, AccessKind::Write)?;
+                if let Some(target) = cpu.registers.look_up_u8(register).copied() {
+                    cpu.copy_register_bytes(cpu.special.accumulator, target);
+                }
             }
             SubInstructions::StoreToRegisterInternal(register) => {
-                let value: Vec<u8> = cpu.get_accumulator_bytes().to_vec();
-                let _ = cpu.write_register(*register, value.as_slice());
+                if let Some(target) = cpu.registers.look_up_u8(*register).copied() {
+                    cpu.copy_register_bytes(cpu.special.accumulator, target);
+                }
             }
             SubInstructions::StepProgramMemory(steps) => {
                 cpu.step_size(*steps);
             }
             SubInstructions::Add => {
-                let a_value = BigUint::from_bytes_be(cpu.read_register_string("reg_a").unwrap());
-                let b_value = BigUint::from_bytes_be(cpu.read_register_string("reg_b").unwrap());
-                let sum_value = a_value + b_value;
-                cpu.set_accumulator(sum_value);
+                let (a, b) = cpu.alu_operands();
+                let accumulator = cpu.special.accumulator;
+                match (cpu.word(a), cpu.word(b)) {
+                    (Some(a_value), Some(b_value)) if accumulator.size <= 8 => cpu.set_word(accumulator, a_value.wrapping_add(b_value)),
+                    _ => cpu.set_value(accumulator, &(cpu.value(a) + cpu.value(b))),
+                }
             }
             SubInstructions::Sub => {
-                let a_value = BigUint::from_bytes_be(cpu.read_register_string("reg_a").unwrap());
-                let b_value = BigUint::from_bytes_be(cpu.read_register_string("reg_b").unwrap());
-                let sub_value = a_value - b_value;
-                cpu.set_accumulator(sub_value);
+                let (a, b) = cpu.alu_operands();
+                let accumulator = cpu.special.accumulator;
+                // Wraps at the accumulator's width like Add
+                match (cpu.word(a), cpu.word(b)) {
+                    (Some(a_value), Some(b_value)) if accumulator.size <= 8 => cpu.set_word(accumulator, a_value.wrapping_sub(b_value)),
+                    _ => {
+                        let modulus = BigUint::one() << (8 * accumulator.size);
+                        cpu.set_value(accumulator, &(cpu.value(a) + &modulus - cpu.value(b) % &modulus));
+                    }
+                }
             }
             SubInstructions::PushToStack => {
-                let stack_pointer = BigUint::from_bytes_be(cpu.read_register_string("stack_pointer").unwrap());
+                let stack_pointer = cpu.special.stack_pointer.expect("Stack pointer register not found.");
                 let accumulator_bytes = cpu.get_accumulator_bytes().to_vec();
-                let destination = (stack_pointer - cpu.cpu_data_size).to_usize().expect("Stack pointer outside usize range");
-                cpu.memory.write_chunk(destination, accumulator_bytes.as_slice());
-                let cpu_adjusted = &destination.to_be_bytes()[USIZE_SIZE - cpu.cpu_data_size as usize..];
-                let _ = cpu.write_register_string("stack_pointer", cpu_adjusted);
+                // The stack pointer wraps, so a full size stack starts at 0 in a register too small to hold the memory size
+                let destination = match cpu.word(stack_pointer) {
+                    Some(top) => {
+                        let mask = if stack_pointer.size >= 8 { u64::MAX } else { (1 << (8 * stack_pointer.size)) - 1 };
+                        (top.wrapping_sub(cpu.cpu_data_size as u64) & mask) as usize
+                    }
+                    None => {
+                        let modulus = BigUint::one() << (8 * stack_pointer.size);
+                        ((cpu.value(stack_pointer) + &modulus - cpu.cpu_data_size) % modulus).to_usize().ok_or(Fault::BusError { address: usize::MAX })?
+                    }
+                };
+                cpu.write_memory(destination, accumulator_bytes.as_slice())?;
+                cpu.set_word(stack_pointer, destination as u64);
             }
             SubInstructions::PopFromStack => {
-                let stack_pointer = BigUint::from_bytes_be(cpu.read_register_string("stack_pointer").unwrap());
-                let address = stack_pointer.to_usize().expect("Stack pointer outside usize range");
-                let data = cpu.memory.read_chunk(address, address + cpu.cpu_data_size as usize);
-                cpu.set_accumulator(BigUint::from_bytes_be(data));
-                cpu.memory.write_chunk(address, vec![0; cpu.cpu_data_size as usize].as_slice());
-                let cpu_adjusted = &address.to_be_bytes()[USIZE_SIZE - cpu.cpu_data_size as usize..];
-                let _ = cpu.write_register_string("stack_pointer", cpu_adjusted);
+                let stack_pointer = cpu.special.stack_pointer.expect("Stack pointer register not found.");
+                let address = cpu.address_in(stack_pointer)?;
+                let data = cpu.read_memory(address, cpu.cpu_data_size as usize, AccessKind::Read)?;
+                cpu.set_decoded(cpu.special.accumulator, &data, cpu.endianness);
+                cpu.write_memory(address, vec![0; cpu.cpu_data_size as usize].as_slice())?;
+                let top = address + cpu.cpu_data_size as usize;
+                cpu.set_word(stack_pointer, top as u64);
             }
             SubInstructions::Jump => {
-                cpu.set_program_counter(cpu.get_accumulator());
+                cpu.copy_register_value(cpu.special.accumulator, cpu.special.program_counter);
                 cpu.current_opcode = None;
             }
             SubInstructions::Compare => {
                 // Compare reg_a to reg_b
-                // TODO: handle longer numbers
-                let a = cpu.read_register_string("reg_a").unwrap()[0];
-                let b = cpu.read_register_string("reg_b").unwrap()[0];
-                let mut flags = cpu.get_flags();
-                if a == b {
-                    flags |= ZERO_FLAG.clone();
-                    flags &= flag_invert(&GREATER_FLAG );
-                } else if a > b {
-                    flags |= GREATER_FLAG.clone();
-                    flags &= flag_invert(&ZERO_FLAG);
-                } else {
-                    flags &= flag_invert(&(ZERO_FLAG.clone() | GREATER_FLAG.clone()));
-                }
-                cpu.set_flags(flags);
+                let (a, b) = cpu.alu_operands();
+                let ordering = match (cpu.word(a), cpu.word(b)) {
+                    (Some(a_value), Some(b_value)) => a_value.cmp(&b_value),
+                    _ => cpu.value(a).cmp(&cpu.value(b)),
+                };
+                cpu.set_compare_flags(ordering);
             }
             SubInstructions::JumpIfFlag(true_mask, false_mask) => {
                 // Jump if all bits in true_mask are set and all bits in false_mask are clear
-                let flags = cpu.get_flags();
-                let true_condition = (flags.clone() & true_mask) == *true_mask;
-                let false_condition = (flags & false_mask) == BigUint::zero();
-                if true_condition && false_condition {
-                    cpu.set_program_counter(cpu.get_accumulator());
+                let taken = cpu.flags_match(true_mask, false_mask);
+                cpu.resolve_branch(taken)?;
+                if taken {
+                    cpu.copy_register_value(cpu.special.accumulator, cpu.special.program_counter);
                     cpu.current_opcode = None;
                 }
             }
 
             SubInstructions::JumpIfNotFlag(true_mask, false_mask) => {
                 // Jump if NOT (all bits in true_mask are set and all bits in false_mask are clear)
-                let flags = cpu.get_flags();
-                let true_condition = (flags.clone() & true_mask) == *true_mask;
-                let false_condition = (flags & false_mask) == BigUint::zero();
-                if !(true_condition && false_condition) {
-                    cpu.set_program_counter(cpu.get_accumulator());
+                let taken = !cpu.flags_match(true_mask, false_mask);
+                cpu.resolve_branch(taken)?;
+                if taken {
+                    cpu.copy_register_value(cpu.special.accumulator, cpu.special.program_counter);
                     cpu.current_opcode = None;
                 }
             }
+            SubInstructions::SystemCall | SubInstructions::ReturnFromTrap if !cpu.can_trap() => {
+                // Only reachable through an unchecked instruction set
+                return Err(Fault::InvalidOpcode { address: cpu.program_counter_usize()? });
+            }
+            SubInstructions::SystemCall => {
+                // There is nowhere to keep a second return address, so the supervisor cannot trap into itself
+                if cpu.privilege == Privilege::Supervisor {
+                    return Err(Fault::PrivilegeViolation { address: cpu.program_counter_usize()? });
+                }
+                let return_address = cpu.get_program_counter() + BigUint::one();
+                cpu.trap(TrapCause::SystemCall, return_address);
+            }
+            SubInstructions::ReturnFromTrap => {
+                cpu.return_from_trap();
+            }
+            SubInstructions::CompareAndSwap => {
+                let address = cpu.get_memory_address()?;
+                let size = cpu.cpu_data_size as usize;
+                let data = cpu.read_memory(address, size, AccessKind::Read)?;
+                let old = cpu.endianness.decode(&data);
+                let (a, b) = cpu.alu_operands();
+                let mut flags = cpu.get_flags();
+                if old == cpu.value(a) {
+                    let new = cpu.value(b);
+                    cpu.write_memory(address, &cpu.endianness.encode(&new, size))?;
+                    flags |= ZERO_FLAG.clone();
+                } else {
+                    flags &= flag_invert(&ZERO_FLAG);
+                }
+                cpu.set_flags(flags);
+                cpu.set_accumulator(old);
+            }
+            SubInstructions::FetchAdd => {
+                let address = cpu.get_memory_address()?;
+                let size = cpu.cpu_data_size as usize;
+                let data = cpu.read_memory(address, size, AccessKind::Read)?;
+                let old = cpu.endianness.decode(&data);
+                let (_, b) = cpu.alu_operands();
+                let sum = &old + cpu.value(b);
+                cpu.write_memory(address, &cpu.endianness.encode(&sum, size))?;
+                cpu.set_accumulator(old);
+            }
+            SubInstructions::Fence => {
+                // Every access completes before the next micro-op and cores share one memory, so the
+                // machine is sequentially consistent and there is nothing to drain
+            }
+            SubInstructions::AddressOffset(base_offset, displacement_offset, width, endianness) => {
+                let base = cpu.operand_register_value(*base_offset)?;
+                let bytes = cpu.read_program_memory_operand(*displacement_offset, *width)?;
+                let address = base + cpu.sign_extend(&bytes, *endianness);
+                cpu.set_value(cpu.special.accumulator, &address);
+            }
+            SubInstructions::AddressIndexed(base_offset, index_offset, scale_offset) => {
+                let base = cpu.operand_register_value(*base_offset)?;
+                let index = cpu.operand_register_value(*index_offset)?;
+                let scale = cpu.read_program_memory_offset(*scale_offset)?;
+                cpu.set_value(cpu.special.accumulator, &(base + index * scale));
+            }
+            SubInstructions::AddressPcRelative(displacement_offset, width, endianness) => {
+                let bytes = cpu.read_program_memory_operand(*displacement_offset, *width)?;
+                let address = cpu.get_program_counter() + cpu.sign_extend(&bytes, *endianness);
+                cpu.set_value(cpu.special.accumulator, &address);
+            }
         }
+        Ok(())
     }
 }
 
+// Why one of the CPU's run methods returned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Halted,
+    Fault(Fault),
+    // The program counter reached this breakpoint, the instruction there has not been fetched
+    Breakpoint(usize),
+    CycleLimit,
+    // The run_until condition held
+    Condition,
+    // step_instruction completed its instruction
+    Stepped,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+// Raised by an access that cannot complete, the CPU traps or stops with it in `fault`
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    // Virtual address with no present page table entry, or one without the needed permission
+    PageFault { address: usize, access: AccessKind },
+    // Physical address outside of Memory
+    BusError { address: usize },
+    // A supervisor only instruction or register used in user mode, address is the instruction's
+    PrivilegeViolation { address: usize },
+    // The fetched opcode is not in the instruction set, address is the opcode's
+    InvalidOpcode { address: usize },
+}
+
+impl Fault {
+    pub fn address(&self) -> usize {
+        match self {
+            Fault::PageFault { address, .. }
+            | Fault::BusError { address }
+            | Fault::PrivilegeViolation { address }
+            | Fault::InvalidOpcode { address } => *address,
+        }
+    }
+
+    pub fn cause(&self) -> TrapCause {
+        match self {
+            Fault::PageFault { .. } => TrapCause::PageFault,
+            Fault::BusError { .. } => TrapCause::BusError,
+            Fault::PrivilegeViolation { .. } => TrapCause::PrivilegeViolation,
+            Fault::InvalidOpcode { .. } => TrapCause::InvalidOpcode,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User,
+    Supervisor,
+}
+
+// Written to trap_cause when the CPU enters the trap vector
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapCause {
+    SystemCall = 1,
+    PageFault,
+    BusError,
+    PrivilegeViolation,
+    InvalidOpcode,
+}
+
 #[derive(Clone)]
 pub struct Instruction {
     pub sub_instructions: Vec<SubInstructions>,
+    pub operands: Vec<Operand>,
+    pub data_size: u8,
     pub args: u8,
+    // Only runs in supervisor mode, user mode raises a privilege violation when it is fetched
+    pub privileged: bool,
 }
 
-#[derive(Clone)]
+impl Instruction {
+    pub fn operand_width(&self, operand: Operand) -> u8 {
+        operand.width(self.data_size)
+    }
+
+    // The operand each argument byte belongs to, in program order
+    pub fn operand_layout(&self) -> Vec<Operand> {
+        self.operands
+            .iter()
+            .flat_map(|operand| std::iter::repeat_n(*operand, self.operand_width(*operand) as usize))
+            .collect()
+    }
+
+    // Byte offset of each operand from the opcode
+    pub fn operand_offsets(&self) -> Vec<u8> {
+        let mut offset = 1;
+        self.operands
+            .iter()
+            .map(|operand| {
+                let start = offset;
+                offset += self.operand_width(*operand);
+                start
+            })
+            .collect()
+    }
+
+    // The operand the microcode reads as an AddressPcRelative displacement, if any
+    pub fn pc_relative_operand(&self) -> Option<usize> {
+        let offsets = self.operand_offsets();
+        self.sub_instructions.iter().find_map(|sub_instruction| match sub_instruction {
+            SubInstructions::AddressPcRelative(offset, _, _) => offsets.iter().position(|start| start == offset),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Reg,        // Register id
+    Imm8,       // 8 bit immediate
+    Imm16,      // 16 bit immediate
+    Imm,        // Immediate of cpu_data_size bytes
+    Addr,       // Memory address of cpu_data_size bytes
+    RegOrAddr,  // Register id holding a memory address
+}
+
+impl Operand {
+    pub fn width(&self, data_size: u8) -> u8 {
+        match self {
+            Operand::Reg | Operand::Imm8 | Operand::RegOrAddr => 1,
+            Operand::Imm16 => 2,
+            Operand::Imm | Operand::Addr => data_size,
+        }
+    }
+
+    pub fn is_register(&self) -> bool {
+        matches!(self, Operand::Reg | Operand::RegOrAddr)
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct Register {
     pub size: usize,
     pub location: usize,
+    // Lowest privilege that may name the register as an instruction operand, microcode is not limited
+    pub privilege: Privilege,
+    // Lowest privilege that may name it as an operand that is written, never below privilege
+    pub write_privilege: Privilege,
+    // Register this is a view into, its bytes are the parent's so writes to either show in both
+    pub parent: Option<u8>,
+    // Set when the view is not whole bytes, location and size then cover the bytes holding it
+    pub field: Option<BitField>,
+}
+
+// Bits shift..shift + width of the value held in a register's bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitField {
+    pub shift: u32,
+    pub width: u32,
+}
+
+impl BitField {
+    fn mask(&self) -> u64 {
+        if self.width >= 64 { u64::MAX } else { (1 << self.width) - 1 }
+    }
+
+    fn mask_big(&self) -> BigUint {
+        (BigUint::one() << self.width) - BigUint::one()
+    }
+}
+
+// What the engine and the default microcode use a register for, independent of its name
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegisterRole {
+    ProgramCounter,
+    Accumulator,
+    Flags,
+    StackPointer,
+    BasePointer,
+    MemoryAddress,
+    // ALU and compare inputs, reg_a and reg_b on the default CPU
+    OperandA,
+    OperandB,
+    // Microcode temporary, reg_c on the default CPU
+    Scratch,
+    PageTableBase,
+    FaultAddress,
+    SupervisorStackPointer,
+    TrapVector,
+    TrapReturn,
+    TrapCause,
+    CoreId,
+}
+
+impl RegisterRole {
+    pub const ALL: [RegisterRole; 16] = [
+        RegisterRole::ProgramCounter,
+        RegisterRole::Accumulator,
+        RegisterRole::Flags,
+        RegisterRole::StackPointer,
+        RegisterRole::BasePointer,
+        RegisterRole::MemoryAddress,
+        RegisterRole::OperandA,
+        RegisterRole::OperandB,
+        RegisterRole::Scratch,
+        RegisterRole::PageTableBase,
+        RegisterRole::FaultAddress,
+        RegisterRole::SupervisorStackPointer,
+        RegisterRole::TrapVector,
+        RegisterRole::TrapReturn,
+        RegisterRole::TrapCause,
+        RegisterRole::CoreId,
+    ];
+
+    // Entering and leaving a trap needs all of these, the validator rejects SystemCall and ReturnFromTrap
+    // without them and faults only trap when they are present
+    pub const TRAP: [RegisterRole; 3] = [RegisterRole::TrapVector, RegisterRole::TrapReturn, RegisterRole::SupervisorStackPointer];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegisterRole::ProgramCounter => "program_counter",
+            RegisterRole::Accumulator => "accumulator",
+            RegisterRole::Flags => "flags",
+            RegisterRole::StackPointer => "stack_pointer",
+            RegisterRole::BasePointer => "base_pointer",
+            RegisterRole::MemoryAddress => "memory_address",
+            RegisterRole::OperandA => "operand_a",
+            RegisterRole::OperandB => "operand_b",
+            RegisterRole::Scratch => "scratch",
+            RegisterRole::PageTableBase => "page_table_base",
+            RegisterRole::FaultAddress => "fault_address",
+            RegisterRole::SupervisorStackPointer => "supervisor_stack_pointer",
+            RegisterRole::TrapVector => "trap_vector",
+            RegisterRole::TrapReturn => "trap_return",
+            RegisterRole::TrapCause => "trap_cause",
+            RegisterRole::CoreId => "core_id",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RegisterRole> {
+        RegisterRole::ALL.iter().copied().find(|role| role.name() == name)
+    }
 }
 
 pub struct Registers {
     pub total_length: usize,
     pub string_reference: HashMap<String, u8>,
     pub registers: Vec<Register>,
+    // Register id holding each role, indexed by RegisterRole
+    roles: [Option<u8>; RegisterRole::ALL.len()],
+    // Byte order of the register file, which decides where views find their bytes. The CPU takes its
+    // own when built, so views added before that must agree with it
+    endianness: Endianness,
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::new()
+    }
 }
 
 impl Registers {
     pub fn new() -> Self {
+        Registers::with_endianness(MachineConfig::default().endianness)
+    }
+
+    pub fn with_endianness(endianness: Endianness) -> Self {
         Registers {
             total_length: 0,
             string_reference: HashMap::new(),
             registers: Vec::new(),
+            roles: [None; RegisterRole::ALL.len()],
+            endianness,
         }
     }
 
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
     pub fn look_up_string(&self, name: &str) -> Option<&Register> {
         let id = self.string_reference.get(name);
         if let Some(id) = id {
@@ -259,15 +672,110 @@ impl Registers {
         *self.string_reference.get(name).expect("Register Not Found")
     }
 
+    pub fn id_of(&self, name: &str) -> Result<u8, String> {
+        self.string_reference.get(name).copied().ok_or_else(|| format!("Register '{}' not found", name))
+    }
+
     pub fn add_register(&mut self, name: String, size: usize, location: usize) {
         self.total_length = self.total_length.max(location + size);
         self.string_reference.insert(name, self.registers.len() as u8);
-        self.registers.push(Register { size, location });
+        self.registers.push(Register { size, location, privilege: Privilege::User, write_privilege: Privilege::User, parent: None, field: None });
+    }
+
+    // A view of bits low..low + width of parent's value, bit 0 being the least significant. Whole byte
+    // views such as the halves of a register are plain registers over some of the parent's bytes, others
+    // are masked on every access. The view starts with its parent's privileges
+    pub fn add_sub_register(&mut self, name: String, parent: &str, low: usize, width: usize) -> Result<(), String> {
+        let parent_id = self.id_of(parent)?;
+        let parent_register = self.registers[parent_id as usize];
+        if parent_register.field.is_some() {
+            return Err(format!("Register '{}' is itself a bit field, views must be of whole registers", parent));
+        }
+        if width == 0 || low + width > parent_register.size * 8 {
+            return Err(format!("Bits {}..{} are outside the {} bit register '{}'", low, low + width, parent_register.size * 8, parent));
+        }
+        if self.string_reference.contains_key(&name) {
+            return Err(format!("Register '{}' already exists", name));
+        }
+        // Bytes counted from the least significant one
+        let first_byte = low / 8;
+        let last_byte = (low + width - 1) / 8;
+        let size = last_byte - first_byte + 1;
+        let location = match self.endianness {
+            Endianness::Little => parent_register.location + first_byte,
+            Endianness::Big => parent_register.location + parent_register.size - 1 - last_byte,
+        };
+        let shift = (low - first_byte * 8) as u32;
+        let field = (shift != 0 || width != size * 8).then_some(BitField { shift, width: width as u32 });
+        self.string_reference.insert(name, self.registers.len() as u8);
+        self.registers.push(Register {
+            size,
+            location,
+            privilege: parent_register.privilege,
+            write_privilege: parent_register.write_privilege,
+            parent: Some(parent_id),
+            field,
+        });
+        Ok(())
+    }
+
+    pub fn name_of(&self, id: u8) -> Option<&str> {
+        self.string_reference.iter().find(|(_, other)| **other == id).map(|(name, _)| name.as_str())
+    }
+
+    // Ids of the views into a register, in the order they were added
+    pub fn children(&self, id: u8) -> Vec<u8> {
+        (0..self.registers.len()).filter(|child| self.registers[*child].parent == Some(id)).map(|child| child as u8).collect()
+    }
+
+    // Sets the privilege needed to read and to write the register
+    pub fn set_privilege(&mut self, name: &str, privilege: Privilege) -> Result<(), String> {
+        let id = self.id_of(name)?;
+        self.registers[id as usize].privilege = privilege;
+        self.registers[id as usize].write_privilege = privilege;
+        Ok(())
+    }
+
+    // Raises only the privilege needed to write the register, reading keeps its privilege
+    pub fn set_write_privilege(&mut self, name: &str, privilege: Privilege) -> Result<(), String> {
+        let id = self.id_of(name)?;
+        let register = &mut self.registers[id as usize];
+        register.write_privilege = privilege.max(register.privilege);
+        Ok(())
+    }
+
+    // A role belongs to one register at a time, giving it to another register moves it
+    pub fn set_role(&mut self, name: &str, role: RegisterRole) -> Result<(), String> {
+        let id = self.id_of(name)?;
+        self.roles[role as usize] = Some(id);
+        Ok(())
+    }
+
+    pub fn role(&self, role: RegisterRole) -> Option<u8> {
+        self.roles[role as usize]
+    }
+
+    pub fn look_up_role(&self, role: RegisterRole) -> Option<&Register> {
+        self.role(role).and_then(|id| self.look_up_u8(id))
+    }
+
+    pub fn role_of(&self, id: u8) -> Option<RegisterRole> {
+        RegisterRole::ALL.iter().copied().find(|role| self.role(*role) == Some(id))
     }
 }
 
-use std::mem;
-use std::{collections::HashMap, hash::Hash, i32, ops::Sub, vec};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use crate::blocks::BlockCache;
+use crate::devices::{Device, MappedDevice};
+use crate::branch::BranchUnit;
+use crate::cache::Cache;
+use crate::image::{Executable, IsaId};
+use crate::mmu::Mmu;
+use crate::timing::{TimingModel, TimingReport};
 
 // Truth Table
 // 
@@ -288,33 +796,152 @@ pub fn flag_invert(mask: &BigUint) -> BigUint {
     &*FLAG_ALL - mask
 }
 
+// Machine wide settings that the CPU and its default instruction set follow
+#[derive(Clone, Copy, Debug)]
+pub struct MachineConfig {
+    pub data_size: u8,
+    pub endianness: Endianness,
+    // reg_0 upwards in the default register file, machine descriptions list their own registers
+    pub general_registers: u8,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            data_size: 1,
+            endianness: Endianness::Big,
+            general_registers: 8,
+        }
+    }
+}
+
+// Registers the engine itself uses, looked up by role once when the CPU is created
+#[derive(Clone, Copy)]
+pub struct SpecialRegisters {
+    pub program_counter: Register,
+    pub accumulator: Register,
+    pub flags: Option<Register>,
+    pub memory_address: Option<Register>,
+    pub stack_pointer: Option<Register>,
+    pub reg_a: Option<Register>,
+    pub reg_b: Option<Register>,
+    // Every role, indexed by RegisterRole
+    pub roles: [Option<Register>; RegisterRole::ALL.len()],
+}
+
+impl SpecialRegisters {
+    // The program counter, accumulator and stack pointer are required, microcode using a missing optional role panics
+    pub fn resolve(registers: &Registers) -> Result<Self, String> {
+        let roles = RegisterRole::ALL.map(|role| registers.look_up_role(role).copied());
+        let required = |role: RegisterRole| roles[role as usize].ok_or_else(|| format!("No register has the {} role", role.name()));
+        Ok(SpecialRegisters {
+            program_counter: required(RegisterRole::ProgramCounter)?,
+            accumulator: required(RegisterRole::Accumulator)?,
+            flags: roles[RegisterRole::Flags as usize],
+            memory_address: roles[RegisterRole::MemoryAddress as usize],
+            stack_pointer: Some(required(RegisterRole::StackPointer)?),
+            reg_a: roles[RegisterRole::OperandA as usize],
+            reg_b: roles[RegisterRole::OperandB as usize],
+            roles,
+        })
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub register_data: Vec<u8>,
+    pub special: SpecialRegisters,
     pub registers: Registers,
     pub memory: Memory,
     pub storage: Storage,
     pub instruction_set: HashMap<u8, Instruction>,
     pub cpu_data_size: u8,
+    pub endianness: Endianness,
+    pub mmu: Option<Mmu>,
+    // Physical address ranges backed by RAM, accesses outside them are bus errors. None maps all of memory
+    pub memory_map: Option<Vec<Range<usize>>>,
+    // Physical address ranges answered by devices instead of memory, see attach_device
+    pub devices: Vec<MappedDevice>,
+    pub cache: Option<Cache>,
+    pub branch_unit: Option<BranchUnit>,
+    // Runs whole pre-decoded instructions per clock instead of single micro-ops
+    pub block_cache: Option<BlockCache>,
+    // Clock cycles left to wait out before the next micro-op, charged by latencies and cache misses
+    pub stall_cycles: u32,
+    pub timing: TimingModel,
+    pub timing_report: TimingReport,
+    // Clock cycles run since reset, stalls included
+    pub cycles: u64,
+    // Cycle the current instruction was fetched in
+    instruction_start: u64,
+    // Instructions completed since reset, faulting ones are not counted
+    pub retired: u64,
+    // Program counter values the run methods stop at before fetching
+    pub breakpoints: HashSet<usize>,
+    // Cycle count and address of the last breakpoint stop, so running again from it executes the instruction
+    breakpoint_stop: Option<(u64, usize)>,
+    // Register file as it was when the current instruction was fetched, if a fault in it would trap
+    checkpoint: Vec<u8>,
+    checkpointed: bool,
+    // Address, old and new byte of every memory write made by the current instruction
+    undo: Vec<(usize, u8, u8)>,
     pub current_opcode: Option<u8>,
     pub current_sub_step: u8,
-    pub halted: bool
+    pub halted: bool,
+    pub fault: Option<Fault>,
+    pub privilege: Privilege,
+    // Mode to go back to on ReturnFromTrap
+    pub previous_privilege: Privilege,
 }
 
 impl CPU {
-    pub fn new(registers: Registers, memory: Memory, storage: Storage) -> Self {
+    pub fn new(registers: Registers, memory: Memory, storage: Storage) -> Result<Self, String> {
+        CPU::with_config(registers, memory, storage, MachineConfig::default())
+    }
+
+    pub fn with_config(mut registers: Registers, memory: Memory, storage: Storage, config: MachineConfig) -> Result<Self, String> {
+        if registers.endianness != config.endianness {
+            if registers.registers.iter().any(|register| register.parent.is_some()) {
+                return Err(format!("Register views were laid out {:?} endian but the CPU is {:?} endian", registers.endianness, config.endianness));
+            }
+            registers.endianness = config.endianness;
+        }
         let mut cpu = CPU { 
             register_data: vec![0; registers.total_length], 
+            special: SpecialRegisters::resolve(&registers)?,
             registers, 
             memory, 
             storage, 
             instruction_set: HashMap::new(),
-            cpu_data_size: 1,
+            cpu_data_size: config.data_size,
+            endianness: config.endianness,
+            mmu: None,
+            memory_map: None,
+            devices: Vec::new(),
+            cache: None,
+            branch_unit: None,
+            block_cache: None,
+            stall_cycles: 0,
+            timing: TimingModel::default(),
+            timing_report: TimingReport::default(),
+            cycles: 0,
+            instruction_start: 0,
+            retired: 0,
+            breakpoints: HashSet::new(),
+            breakpoint_stop: None,
+            checkpoint: Vec::new(),
+            checkpointed: false,
+            undo: Vec::new(),
             current_opcode: None,
             current_sub_step: 0,
-            halted: false
+            halted: false,
+            fault: None,
+            privilege: Privilege::Supervisor,
+            previous_privilege: Privilege::Supervisor,
         };
-        cpu.write_register_string("stack_pointer", &[cpu.memory.data.len() as u8]).unwrap();
-        cpu
+        let stack_pointer = cpu.special.stack_pointer.expect("Stack pointer resolved above");
+        cpu.set_value(stack_pointer, &BigUint::from(cpu.memory.data.len()));
+        Ok(cpu)
     }
 
     pub fn read_register_string(&self, name: &str) -> Option<&[u8]> {
@@ -333,9 +960,18 @@ impl CPU {
         })
     }
 
+    // Register contents decoded in the machine's byte order
+    pub fn read_register_value_string(&self, name: &str) -> Option<BigUint> {
+        self.registers.look_up_string(name).map(|reg| self.value(*reg))
+    }
+
+    pub fn read_register_value(&self, id: u8) -> Option<BigUint> {
+        self.registers.look_up_u8(id).map(|reg| self.value(*reg))
+    }
+
     pub fn write_register_string(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
         let reg = match self.registers.look_up_string(name) {
-            Some(r) => r.clone(),
+            Some(r) => *r,
             None => return Err(format!("Register '{}' not found", name)),
         };
         self.write_register_internal(&reg, data)
@@ -343,16 +979,33 @@ impl CPU {
 
     pub fn write_register(&mut self, id: u8, data: &[u8]) -> Result<(), String> {
         let reg = match self.registers.look_up_u8(id) {
-            Some(r) => r.clone(),
+            Some(r) => *r,
             None => return Err(format!("Register '{}' not found", id)),
         };
         self.write_register_internal(&reg, data)
     }
 
+    // Writes value zero padded to the register's size, wrapping anything wider
+    pub fn set_register_value_string(&mut self, name: &str, value: BigUint) -> Result<(), String> {
+        match self.registers.look_up_string(name) {
+            Some(reg) => {
+                self.set_value(*reg, &value);
+                Ok(())
+            }
+            None => Err(format!("Register '{}' not found", name)),
+        }
+    }
+
+    // Bit field views take data as the value to store, the bits around them are kept
     pub fn write_register_internal(&mut self, reg: &Register, data: &[u8]) -> Result<(), String> {
         if data.len() != reg.size {
             return Err(format!("Data size mismatch for register {}: expected {}, got {}", reg.location, reg.size, data.len()));
         }
+        if reg.field.is_some() {
+            let value = self.endianness.decode(data);
+            self.set_value(*reg, &value);
+            return Ok(());
+        }
         let start = reg.location;
         let end = start + reg.size;
         self.register_data[start..end].copy_from_slice(data);
@@ -379,73 +1032,462 @@ impl CPU {
         self.storage.write_chunk(start, chunk);
     }
 
-    pub fn read_program_memory(&mut self) -> u8 {
-        let counter = self.get_program_counter();
-        let address = counter.to_usize().expect("Program counter outside usize range");
-        self.memory.read(address)
+    // Physical address for a virtual one, identity when there is no MMU
+    pub fn translate(&self, address: usize, access: AccessKind) -> Result<usize, Fault> {
+        match &self.mmu {
+            Some(mmu) => {
+                let page_table_base = self.role(RegisterRole::PageTableBase).expect("Page table base register not found.");
+                let page_table_base = self.address_in(page_table_base)?;
+                mmu.translate(&self.memory, page_table_base, self.cpu_data_size as usize, self.endianness, address, access)
+            }
+            None => Ok(address),
+        }
+    }
+
+    // Maps range of physical addresses to device. Device addresses count as mapped whatever the memory map
+    // says, and may lie past the end of memory
+    pub fn attach_device(&mut self, name: &str, range: Range<usize>, device: Box<dyn Device>) -> Result<(), String> {
+        if range.is_empty() {
+            return Err(format!("Device {} has an empty range", name));
+        }
+        if let Some(other) = self.devices.iter().find(|other| other.name == name || (other.range.start < range.end && range.start < other.range.end)) {
+            return Err(format!("Device {} at {:#x}..{:#x} clashes with device {} at {:#x}..{:#x}", name, range.start, range.end, other.name, other.range.start, other.range.end));
+        }
+        self.devices.push(MappedDevice { name: name.to_string(), range, device });
+        Ok(())
+    }
+
+    // The device attached as name, None when there is none or it is not a T
+    pub fn device<T: Device>(&self, name: &str) -> Option<&T> {
+        let mapped = self.devices.iter().find(|mapped| mapped.name == name)?;
+        (mapped.device.as_ref() as &dyn Any).downcast_ref()
+    }
+
+    pub fn device_mut<T: Device>(&mut self, name: &str) -> Option<&mut T> {
+        let mapped = self.devices.iter_mut().find(|mapped| mapped.name == name)?;
+        (mapped.device.as_mut() as &mut dyn Any).downcast_mut()
     }
 
-    pub fn read_program_memory_offset(&self, offset: u8) -> u8 {
-        let counter = self.get_program_counter();
-        let total = counter + BigUint::from(offset);
-        let address = total.to_usize().expect("Program counter + offset outside usize range");
-        self.memory.read(address)
+    fn device_at(&self, physical: usize) -> Option<usize> {
+        if self.devices.is_empty() {
+            return None;
+        }
+        self.devices.iter().position(|mapped| mapped.range.contains(&physical))
+    }
+
+    // Reads physical from its device or from memory, after check_physical
+    fn read_physical(&mut self, physical: usize) -> u8 {
+        match self.device_at(physical) {
+            Some(index) => {
+                let mapped = &mut self.devices[index];
+                mapped.device.read(physical - mapped.range.start)
+            }
+            None => self.memory.read(physical),
+        }
+    }
+
+    // A bus error unless physical is a device's, or in memory and in a mapped region
+    fn check_physical(&self, physical: usize) -> Result<(), Fault> {
+        if self.device_at(physical).is_some() {
+            return Ok(());
+        }
+        let mapped = match &self.memory_map {
+            Some(regions) => regions.iter().any(|region| region.contains(&physical)),
+            None => true,
+        };
+        match mapped && physical < self.memory.size() {
+            true => Ok(()),
+            false => Err(Fault::BusError { address: physical }),
+        }
+    }
+
+    // Reads go through the MMU one byte at a time so accesses may cross page boundaries
+    pub fn read_memory(&mut self, address: usize, length: usize, access: AccessKind) -> Result<Vec<u8>, Fault> {
+        let mut data = Vec::with_capacity(length);
+        // Only the cache needs the physical addresses
+        let mut physical_addresses = Vec::new();
+        for virtual_address in address..address + length {
+            let physical = self.translate(virtual_address, access)?;
+            self.check_physical(physical)?;
+            data.push(self.read_physical(physical));
+            if self.cache.is_some() && self.device_at(physical).is_none() {
+                physical_addresses.push(physical);
+            }
+        }
+        self.stall_cycles += self.timing.memory_latency;
+        self.access_cache(&physical_addresses, false);
+        Ok(data)
+    }
+
+    // All bytes are translated before any is written, so a faulting write changes nothing
+    pub fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), Fault> {
+        let mut physical_addresses = Vec::with_capacity(data.len());
+        for virtual_address in address..address + data.len() {
+            let physical = self.translate(virtual_address, AccessKind::Write)?;
+            self.check_physical(physical)?;
+            physical_addresses.push(physical);
+        }
+        self.stall_cycles += self.timing.memory_latency;
+        if self.devices.is_empty() {
+            self.access_cache(&physical_addresses, true);
+        } else {
+            let cached: Vec<usize> = physical_addresses.iter().copied().filter(|physical| self.device_at(*physical).is_none()).collect();
+            self.access_cache(&cached, true);
+        }
+        let generation = self.memory.generation();
+        for (physical, value) in physical_addresses.iter().zip(data) {
+            if let Some(index) = self.device_at(*physical) {
+                let mapped = &mut self.devices[index];
+                mapped.device.write(physical - mapped.range.start, *value);
+                continue;
+            }
+            if self.current_opcode.is_some() && self.checkpointed {
+                self.undo.push((*physical, self.memory.read(*physical), *value));
+            }
+            self.memory.write(*physical, *value);
+        }
+        if let (Some(blocks), Some(first), Some(last)) = (self.block_cache.as_mut(), physical_addresses.first(), physical_addresses.last()) {
+            blocks.invalidate(*first.min(last), *first.max(last) + 1);
+            blocks.seen(generation, self.memory.generation());
+        }
+        Ok(())
+    }
+
+    // One cache access per line touched, each miss stalls the CPU for the miss penalty
+    fn access_cache(&mut self, physical_addresses: &[usize], write: bool) {
+        let Some(cache) = self.cache.as_mut() else {
+            return;
+        };
+        let mut previous_line = None;
+        for physical in physical_addresses {
+            let line = cache.line_of(*physical);
+            if previous_line == Some(line) {
+                continue;
+            }
+            previous_line = Some(line);
+            if !cache.access(*physical, write) {
+                self.stall_cycles += cache.config.miss_penalty;
+            }
+        }
+    }
+
+    // Charges a read of the instruction stream that block translation already made, as the micro-step
+    // engine's read of the same bytes would have been. Translated code never lies behind a device
+    pub(crate) fn charge_fetch(&mut self, address: usize, length: usize) {
+        self.stall_cycles += self.timing.memory_latency;
+        if self.cache.is_some() {
+            let addresses: Vec<usize> = (address..address + length).collect();
+            self.access_cache(&addresses, false);
+        }
+    }
+
+    // Single byte read for fetches and operands, avoids building a Vec
+    fn read_memory_byte(&mut self, address: usize, access: AccessKind) -> Result<u8, Fault> {
+        let physical = self.translate(address, access)?;
+        self.check_physical(physical)?;
+        self.stall_cycles += self.timing.memory_latency;
+        if self.device_at(physical).is_none() {
+            self.access_cache(&[physical], false);
+        }
+        Ok(self.read_physical(physical))
+    }
+
+    pub fn read_program_memory(&mut self) -> Result<u8, Fault> {
+        let address = self.program_counter_usize()?;
+        self.read_memory_byte(address, AccessKind::Execute)
+    }
+
+    pub fn read_program_memory_operand(&mut self, offset: u8, width: u8) -> Result<Vec<u8>, Fault> {
+        let address = self.program_counter_usize()? + offset as usize;
+        self.read_memory(address, width as usize, AccessKind::Execute)
+    }
+
+    pub fn read_program_memory_offset(&mut self, offset: u8) -> Result<u8, Fault> {
+        let address = self.program_counter_usize()? + offset as usize;
+        self.read_memory_byte(address, AccessKind::Execute)
+    }
+
+    // Fast path register access. Registers of up to 8 bytes are handled as native u64 words, wider ones
+    // fall back to BigUint, and both wrap identically when a value is written to a smaller register.
+
+    pub fn word(&self, reg: Register) -> Option<u64> {
+        if reg.size > 8 {
+            return None;
+        }
+        let raw = self.endianness.decode_u64(&self.register_data[reg.location..reg.location + reg.size]);
+        Some(match reg.field {
+            Some(field) => (raw >> field.shift) & field.mask(),
+            None => raw,
+        })
+    }
+
+    pub fn value(&self, reg: Register) -> BigUint {
+        let raw = self.endianness.decode(&self.register_data[reg.location..reg.location + reg.size]);
+        match reg.field {
+            Some(field) => (raw >> field.shift) & field.mask_big(),
+            None => raw,
+        }
+    }
+
+    pub fn register_usize(&self, reg: Register) -> Option<usize> {
+        match self.word(reg) {
+            Some(word) => usize::try_from(word).ok(),
+            None => self.value(reg).to_usize(),
+        }
+    }
+
+    pub fn set_word(&mut self, reg: Register, value: u64) {
+        let endianness = self.endianness;
+        let bytes = &mut self.register_data[reg.location..reg.location + reg.size];
+        match reg.field {
+            Some(field) if reg.size <= 8 => {
+                let mask = field.mask() << field.shift;
+                let raw = endianness.decode_u64(bytes) & !mask | (value << field.shift) & mask;
+                endianness.encode_u64_into(raw, bytes);
+            }
+            Some(_) => self.set_value(reg, &BigUint::from(value)),
+            None => endianness.encode_u64_into(value, bytes),
+        }
+    }
+
+    pub fn set_value(&mut self, reg: Register, value: &BigUint) {
+        let bytes = match reg.field {
+            Some(field) => {
+                let raw = self.endianness.decode(&self.register_data[reg.location..reg.location + reg.size]);
+                let mask = field.mask_big() << field.shift;
+                let kept = &raw - (&raw & &mask);
+                self.endianness.encode(&(kept | ((value << field.shift) & mask)), reg.size)
+            }
+            None => self.endianness.encode(value, reg.size),
+        };
+        self.register_data[reg.location..reg.location + reg.size].copy_from_slice(&bytes);
+    }
+
+    pub fn set_decoded(&mut self, reg: Register, bytes: &[u8], endianness: Endianness) {
+        if bytes.len() <= 8 {
+            self.set_word(reg, endianness.decode_u64(bytes));
+        } else {
+            self.set_value(reg, &endianness.decode(bytes));
+        }
+    }
+
+    // Copies the value, zero extending or wrapping it to the target's size
+    fn copy_register_value(&mut self, from: Register, to: Register) {
+        match self.word(from) {
+            Some(word) => self.set_word(to, word),
+            None => self.set_value(to, &self.value(from)),
+        }
+    }
+
+    // Copies the raw bytes between plain registers of the same size. Otherwise the value is copied,
+    // zero extended or wrapped to the destination, so moving a full word into AL sets the low byte of AX
+    // and storing the accumulator into a one byte flags register keeps its low byte
+    fn copy_register_bytes(&mut self, from: Register, to: Register) {
+        if from.parent.is_none() && to.parent.is_none() && from.size == to.size {
+            self.register_data.copy_within(from.location..from.location + from.size, to.location);
+        } else {
+            self.copy_register_value(from, to);
+        }
+    }
+
+    // Registers that do not exist read as 0
+    fn load_register_to_accumulator(&mut self, id: u8) {
+        match self.registers.look_up_u8(id).copied() {
+            Some(reg) => self.copy_register_value(reg, self.special.accumulator),
+            None => self.set_word(self.special.accumulator, 0),
+        }
+    }
+
+    // Value of the register an operand names, checked like LoadFromRegister
+    fn operand_register_value(&mut self, offset: u8) -> Result<BigUint, Fault> {
+        let register = self.read_program_memory_offset(offset)?;
+        self.check_register_access(register, AccessKind::Read)?;
+        Ok(self.registers.look_up_u8(register).map_or_else(BigUint::zero, |reg| self.value(*reg)))
+    }
+
+    // Widens a two's complement displacement to the accumulator, so adding it and wrapping subtracts
+    fn sign_extend(&self, bytes: &[u8], endianness: Endianness) -> BigUint {
+        let value = endianness.decode(bytes);
+        let bits = bytes.len() * 8;
+        let width = self.special.accumulator.size * 8;
+        if bits == 0 || bits >= width || !value.bit(bits as u64 - 1) {
+            return value;
+        }
+        value + (BigUint::one() << width) - (BigUint::one() << bits)
+    }
+
+    pub fn role(&self, role: RegisterRole) -> Option<Register> {
+        self.special.roles[role as usize]
+    }
+
+    fn alu_operands(&self) -> (Register, Register) {
+        (
+            self.special.reg_a.expect("reg_a register not found."),
+            self.special.reg_b.expect("reg_b register not found."),
+        )
+    }
+
+    fn flags_register(&self) -> Register {
+        self.special.flags.expect("Flags register not found.")
+    }
+
+    fn set_compare_flags(&mut self, ordering: Ordering) {
+        let flags_register = self.flags_register();
+        if let Some(mut flags) = self.word(flags_register) {
+            let zero = ZERO_FLAG.to_u64().unwrap();
+            let greater = GREATER_FLAG.to_u64().unwrap();
+            let all = FLAG_ALL.to_u64().unwrap();
+            match ordering {
+                Ordering::Equal => flags = (flags | zero) & (all - greater),
+                Ordering::Greater => flags = (flags | greater) & (all - zero),
+                Ordering::Less => flags &= all - (zero | greater),
+            }
+            self.set_word(flags_register, flags);
+            return;
+        }
+        let mut flags = self.value(flags_register);
+        match ordering {
+            Ordering::Equal => {
+                flags |= ZERO_FLAG.clone();
+                flags &= flag_invert(&GREATER_FLAG);
+            }
+            Ordering::Greater => {
+                flags |= GREATER_FLAG.clone();
+                flags &= flag_invert(&ZERO_FLAG);
+            }
+            Ordering::Less => flags &= flag_invert(&(ZERO_FLAG.clone() | GREATER_FLAG.clone())),
+        }
+        self.set_value(flags_register, &flags);
+    }
+
+    // All bits in true_mask set and all bits in false_mask clear
+    fn flags_match(&self, true_mask: &BigUint, false_mask: &BigUint) -> bool {
+        let flags_register = self.flags_register();
+        match (self.word(flags_register), true_mask.to_u64(), false_mask.to_u64()) {
+            (Some(flags), Some(true_mask), Some(false_mask)) => flags & true_mask == true_mask && flags & false_mask == 0,
+            _ => {
+                let flags = self.value(flags_register);
+                (flags.clone() & true_mask) == *true_mask && (flags & false_mask).is_zero()
+            }
+        }
+    }
+
+    // An address held in a register. Values too large for usize are past the end of any memory, so they
+    // are a bus error rather than a panic
+    fn address_in(&self, reg: Register) -> Result<usize, Fault> {
+        self.register_usize(reg).ok_or(Fault::BusError { address: usize::MAX })
+    }
+
+    fn program_counter_usize(&self) -> Result<usize, Fault> {
+        self.address_in(self.special.program_counter)
     }
 
     pub fn get_program_counter(&self) -> BigUint {
-        let bytes = self.read_register_string("program_counter").expect("Program Counter register not found.");
-        BigUint::from_bytes_be(bytes)
+        self.value(self.special.program_counter)
     }
 
     pub fn get_program_counter_bytes(&self) -> &[u8] {
-        self.read_register_string("program_counter").expect("Program Counter register not found.")
+        let reg = self.special.program_counter;
+        &self.register_data[reg.location..reg.location + reg.size]
+    }
+
+    // Copies a program into memory at address and points the program counter at it. Programs built only
+    // from relative branches and PC-relative accesses run the same wherever they are loaded
+    pub fn load_program(&mut self, address: usize, program: &[u8]) {
+        let generation = self.memory.generation();
+        self.memory.write_chunk(address, program);
+        if let Some(blocks) = self.block_cache.as_mut() {
+            blocks.invalidate(address, address + program.len());
+            blocks.seen(generation, self.memory.generation());
+        }
+        self.set_program_counter(address.into());
+    }
+
+    // Identifies this CPU's instruction set for executables, name is only informational
+    pub fn isa_id(&self, name: &str) -> IsaId {
+        IsaId::of(name, &self.instruction_set, self.cpu_data_size, self.endianness)
+    }
+
+    // Loads every segment, zero filling past its contents, and points the program counter at the entry.
+    // Nothing is written unless the executable was built for this instruction set and fits in memory.
+    // Segment permissions are not enforced here, mapping them is left to code that sets up page tables
+    pub fn load_executable(&mut self, executable: &Executable) -> Result<(), String> {
+        let isa = self.isa_id(&executable.isa.name);
+        if executable.isa.hash != isa.hash {
+            return Err(format!("Executable was built for ISA '{}' {:#018x}, this CPU has {:#018x}", executable.isa.name, executable.isa.hash, isa.hash));
+        }
+        if (executable.isa.data_size, executable.isa.endianness) != (isa.data_size, isa.endianness) {
+            return Err(format!(
+                "Executable expects {} byte {:?} endian data, this CPU has {} byte {:?} endian data",
+                executable.isa.data_size, executable.isa.endianness, isa.data_size, isa.endianness
+            ));
+        }
+        executable.check()?;
+        for segment in &executable.segments {
+            if segment.address + segment.size > self.memory.size() {
+                return Err(format!("Segment at {:#x} of {} bytes does not fit in {} bytes of memory", segment.address, segment.size, self.memory.size()));
+            }
+        }
+        for segment in &executable.segments {
+            let mut bytes = segment.contents.clone();
+            bytes.resize(segment.size, 0);
+            self.load_program(segment.address, &bytes);
+        }
+        self.set_program_counter(executable.entry.into());
+        Ok(())
+    }
+
+    pub fn load_executable_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.load_executable(&Executable::from_bytes(bytes)?)
     }
 
     pub fn set_program_counter(&mut self, value: BigUint) {
-        let bytes = value.to_bytes_be();
-        self.write_register_string("program_counter", &bytes).expect("Failed to write program counter");
+        self.set_value(self.special.program_counter, &value);
     }
 
     pub fn get_accumulator(&self) -> BigUint {
-        let bytes = self.read_register_string("accumulator").expect("Accumulator register not found.");
-        BigUint::from_bytes_be(bytes)
+        self.value(self.special.accumulator)
     }
 
     pub fn get_accumulator_bytes(&self) -> &[u8] {
-        self.read_register_string("accumulator").expect("Accumulator register not found.")
+        let reg = self.special.accumulator;
+        &self.register_data[reg.location..reg.location + reg.size]
     }
 
     pub fn set_accumulator(&mut self, value: BigUint) {
-        let bytes = value.to_bytes_be();
-        self.write_register_string("accumulator", &bytes).expect("Failed to write accumulator");
+        self.set_value(self.special.accumulator, &value);
+    }
+
+    pub fn get_memory_address(&self) -> Result<usize, Fault> {
+        let reg = self.special.memory_address.expect("Memory address register not found.");
+        self.address_in(reg)
     }
 
     pub fn get_flags_bytes(&self) -> &[u8] {
-        self.read_register_string("flags").expect("Flags register not found.")
+        let reg = self.flags_register();
+        &self.register_data[reg.location..reg.location + reg.size]
     }
 
     pub fn get_flags(&mut self) -> BigUint {
-        let bytes = self.get_flags_bytes();
-        BigUint::from_bytes_be(bytes)
+        self.value(self.flags_register())
     }
 
     pub fn set_flags(&mut self, value: BigUint) {
-        let bytes = value.to_bytes_be();
-        self.write_register_string("flags", &bytes).expect("Failed to write flags");
+        self.set_value(self.flags_register(), &value);
     }
 
     pub fn step(&mut self) {
-        let counter = self.get_program_counter();
-        self.set_program_counter(counter + BigUint::one());
+        self.step_size(1);
     }
 
     pub fn step_size(&mut self, size: u8) {
-        let counter: BigUint = self.get_program_counter();
-        self.set_program_counter(counter + BigUint::from(size));
+        let program_counter = self.special.program_counter;
+        match self.word(program_counter) {
+            Some(counter) => self.set_word(program_counter, counter.wrapping_add(size as u64)),
+            None => self.set_value(program_counter, &(self.value(program_counter) + size)),
+        }
     }
 
-    
-
     pub fn unhalt(&mut self) {
         self.halted = false;
     }
@@ -454,34 +1496,328 @@ impl CPU {
         self.halted
     }
 
+    pub fn is_faulted(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    // Abandons the current instruction, fault_address reports the faulting address. A user mode fault
+    // enters the trap vector when one is set, undoing what the instruction's earlier micro-ops did so it
+    // can be retried on return. Any other fault stops the CPU as the faulting micro-op left it
+    pub fn raise_fault(&mut self, fault: Fault) {
+        if self.current_opcode.is_some() && self.checkpointed {
+            self.roll_back();
+        }
+        if let Some(fault_address) = self.role(RegisterRole::FaultAddress) {
+            self.set_value(fault_address, &BigUint::from(fault.address()));
+        }
+        let trap_vector = self.role(RegisterRole::TrapVector).map(|reg| self.value(reg)).unwrap_or_default();
+        if self.privilege == Privilege::User && !trap_vector.is_zero() && self.can_trap() {
+            let return_address = self.get_program_counter();
+            self.trap(fault.cause(), return_address);
+            return;
+        }
+        self.fault = Some(fault);
+        self.current_opcode = None;
+    }
+
+    // Saves the state a fault in the instruction about to run goes back to. Only a fault that traps
+    // resumes the instruction, so nothing is saved while a fault would stop the CPU
+    fn checkpoint(&mut self) {
+        let trap_vector_set = self.role(RegisterRole::TrapVector).is_some_and(|reg| self.word(reg) != Some(0));
+        self.checkpointed = self.privilege == Privilege::User && trap_vector_set && self.can_trap();
+        if self.checkpointed {
+            self.checkpoint.clone_from(&self.register_data);
+            self.undo.clear();
+        }
+    }
+
+    // Restores the registers and memory saved by checkpoint. A byte another core has since overwritten
+    // keeps that core's value
+    fn roll_back(&mut self) {
+        self.register_data.clone_from(&self.checkpoint);
+        let generation = self.memory.generation();
+        while let Some((address, old, new)) = self.undo.pop() {
+            if self.memory.read(address) == new {
+                self.memory.write(address, old);
+            }
+            if let Some(blocks) = self.block_cache.as_mut() {
+                blocks.invalidate(address, address + 1);
+            }
+        }
+        if let Some(blocks) = self.block_cache.as_mut() {
+            blocks.seen(generation, self.memory.generation());
+        }
+    }
+
+    // Shows a conditional jump's outcome to the branch unit, the program counter is still on its opcode.
+    // A target too large for usize is predicted as usize::MAX, the fetch after the jump raises the bus error
+    pub fn resolve_branch(&mut self, taken: bool) -> Result<(), Fault> {
+        if self.branch_unit.is_none() {
+            return Ok(());
+        }
+        let address = self.program_counter_usize()?;
+        let target = self.address_in(self.special.accumulator).unwrap_or(usize::MAX);
+        if let Some(unit) = self.branch_unit.as_mut() {
+            if !unit.resolve(address, taken, target) {
+                self.stall_cycles += unit.misprediction_penalty;
+            }
+        }
+        Ok(())
+    }
+
+    // Read or Write access to a register named by an operand
+    pub fn check_register_access(&self, register: u8, access: AccessKind) -> Result<(), Fault> {
+        let needed = |reg: &Register| match access {
+            AccessKind::Write => reg.write_privilege,
+            _ => reg.privilege,
+        };
+        match self.registers.look_up_u8(register) {
+            Some(reg) if needed(reg) > self.privilege => {
+                Err(Fault::PrivilegeViolation { address: self.program_counter_usize()? })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn can_trap(&self) -> bool {
+        RegisterRole::TRAP.iter().all(|role| self.role(*role).is_some())
+    }
+
+    // Enters supervisor mode at trap_vector, leaving the cause in trap_cause and where to resume in trap_return.
+    // Coming from user mode also swaps in the supervisor's stack
+    pub fn trap(&mut self, cause: TrapCause, return_address: BigUint) {
+        if self.privilege == Privilege::User {
+            self.swap_stack_pointers();
+        }
+        self.previous_privilege = self.privilege;
+        self.privilege = Privilege::Supervisor;
+        if let Some(trap_cause) = self.role(RegisterRole::TrapCause) {
+            self.set_word(trap_cause, cause as u64);
+        }
+        if let Some(trap_return) = self.role(RegisterRole::TrapReturn) {
+            self.set_value(trap_return, &return_address);
+        }
+        let trap_vector = self.role(RegisterRole::TrapVector).expect("Callers check can_trap");
+        self.copy_register_value(trap_vector, self.special.program_counter);
+        self.current_opcode = None;
+    }
+
+    pub fn return_from_trap(&mut self) {
+        self.privilege = self.previous_privilege;
+        if self.privilege == Privilege::User {
+            self.swap_stack_pointers();
+        }
+        let trap_return = self.role(RegisterRole::TrapReturn).expect("Callers check can_trap");
+        self.copy_register_value(trap_return, self.special.program_counter);
+        self.current_opcode = None;
+    }
+
+    fn swap_stack_pointers(&mut self) {
+        let user = self.special.stack_pointer.expect("Stack pointer register not found.");
+        let supervisor = self.role(RegisterRole::SupervisorStackPointer).expect("Callers check can_trap");
+        let user_value = self.value(user);
+        self.copy_register_value(supervisor, user);
+        self.set_value(supervisor, &user_value);
+    }
+
     pub fn clock(&mut self) {
-        if self.is_halted() {
+        if self.is_halted() || self.is_faulted() {
+            return;
+        }
+        self.cycles += 1;
+        if self.stall_cycles > 0 {
+            self.stall_cycles -= 1;
+            return;
+        }
+        if self.current_opcode.is_none() && self.block_cache.is_some() && self.mmu.is_none() && self.clock_translated() {
             return;
         }
         match self.current_opcode {
             Some(op_code) => {
-                // Limit the scope of the immutable borrow
-                let (sub_instructions_len, sub_instruction) = {
-                    let op = self.instruction_set.get(&op_code).expect(format!("Invalid op code {}", op_code).as_str());
-                    (op.sub_instructions.len(), op.sub_instructions.get(self.current_sub_step as usize).cloned())
+                // The fetch checked the opcode, it only goes missing if the instruction set is swapped mid-instruction
+                let Some(instruction) = self.instruction_set.get(&op_code) else {
+                    let fault = match self.program_counter_usize() {
+                        Ok(address) => Fault::InvalidOpcode { address },
+                        Err(fault) => fault,
+                    };
+                    self.raise_fault(fault);
+                    return;
                 };
+                let sub_instructions_len = instruction.sub_instructions.len();
                 if self.current_sub_step as usize >= sub_instructions_len {
                     self.current_opcode = None;
                     self.step();
+                    self.retire(op_code);
                     return;
                 }
-                if let Some(sub_instruction) = sub_instruction {
-                    sub_instruction.execute(self);
+                // Moved out while the micro-op runs so it can borrow the CPU without being cloned
+                let instruction_set = std::mem::take(&mut self.instruction_set);
+                let sub_instruction = &instruction_set[&op_code].sub_instructions[self.current_sub_step as usize];
+                let latency = self.timing.latency(sub_instruction);
+                let result = sub_instruction.execute(self);
+                self.instruction_set = instruction_set;
+                if let Err(fault) = result {
+                    self.raise_fault(fault);
+                    return;
                 }
+                self.stall_cycles += latency - 1;
                 self.current_sub_step += 1;
+                // Jumps and traps end the instruction early
+                if self.current_opcode.is_none() || self.halted {
+                    self.retire(op_code);
+                }
             },
             None => {
-                self.current_opcode = Some(self.read_program_memory());
+                self.instruction_start = self.cycles;
                 self.current_sub_step = 0;
+                self.checkpoint();
+                if let Err(fault) = self.fetch() {
+                    self.raise_fault(fault);
+                }
             }
         }
-        
-        
+    }
+
+    // Reads the opcode at the program counter and checks the current privilege may run it
+    fn fetch(&mut self) -> Result<(), Fault> {
+        let address = self.program_counter_usize()?;
+        let op_code = self.read_memory_byte(address, AccessKind::Execute)?;
+        let Some(instruction) = self.instruction_set.get(&op_code) else {
+            return Err(Fault::InvalidOpcode { address });
+        };
+        if instruction.privileged && self.privilege == Privilege::User {
+            return Err(Fault::PrivilegeViolation { address });
+        }
+        self.current_opcode = Some(op_code);
+        Ok(())
+    }
+
+    // Runs the whole instruction at the program counter from the block cache, charging the cycles the
+    // micro-step engine would have taken for it. False when nothing could be decoded there
+    fn clock_translated(&mut self) -> bool {
+        let Ok(address) = self.program_counter_usize() else {
+            return false;
+        };
+        let Some(blocks) = self.block_cache.as_mut() else {
+            return false;
+        };
+        let Some((block, index)) = blocks.lookup(&self.memory, &self.instruction_set, address) else {
+            return false;
+        };
+        // Memory behind a device is not what a fetch would see, and code outside the memory map faults
+        if self.devices.iter().any(|mapped| mapped.range.start < block.end && block.start < mapped.range.end) {
+            return false;
+        }
+        if let Some(regions) = &self.memory_map {
+            if !regions.iter().any(|region| region.start <= block.start && block.end <= region.end) {
+                return false;
+            }
+        }
+        let instruction = &block.instructions[index];
+        self.instruction_start = self.cycles;
+        self.charge_fetch(address, 1);
+        if instruction.privileged && self.privilege == Privilege::User {
+            self.raise_fault(Fault::PrivilegeViolation { address });
+            return true;
+        }
+        self.checkpoint();
+        self.current_opcode = Some(instruction.opcode);
+        for op in &instruction.ops {
+            let latency = self.timing.latency(op.timed_as());
+            self.cycles += 1;
+            if let Err(fault) = op.execute(self) {
+                self.raise_fault(fault);
+                return true;
+            }
+            self.stall_cycles += latency - 1;
+            if self.current_opcode.is_none() || self.halted {
+                break;
+            }
+        }
+        if self.current_opcode.is_some() && !self.halted {
+            self.current_opcode = None;
+            self.cycles += 1;
+            self.step();
+        }
+        self.current_opcode = None;
+        self.retire(instruction.opcode);
+        self.cycles += self.stall_cycles as u64;
+        self.stall_cycles = 0;
+        if let (Ok(address), Some(blocks)) = (self.program_counter_usize(), self.block_cache.as_mut()) {
+            blocks.advance(block.clone(), index, address);
+        }
+        true
+    }
+
+    fn retire(&mut self, op_code: u8) {
+        self.retired += 1;
+        let cycles = self.cycles - self.instruction_start + 1 + self.stall_cycles as u64;
+        self.timing_report.record(op_code, cycles);
+    }
+
+    // Clocks until the CPU halts, faults or reaches a breakpoint, or max_cycles have passed
+    pub fn run_until_halt(&mut self, max_cycles: u64) -> StopReason {
+        self.run_until(max_cycles, |_| false)
+    }
+
+    // Clocks for exactly cycles cycles unless the CPU stops or reaches a breakpoint first
+    pub fn run_for(&mut self, cycles: u64) -> StopReason {
+        self.run_until(cycles, |_| false)
+    }
+
+    // Clocks until the instruction in flight, or the next one when between instructions, completes
+    pub fn step_instruction(&mut self) -> StopReason {
+        let retired = self.retired;
+        loop {
+            if let Some(reason) = self.stopped() {
+                return reason;
+            }
+            self.clock();
+            if self.retired > retired && self.at_instruction_boundary() {
+                return self.stopped().unwrap_or(StopReason::Stepped);
+            }
+        }
+    }
+
+    // Clocks until condition holds after a clock, checked every cycle, or the CPU stops, reaches a
+    // breakpoint or max_cycles have passed. Breakpoints stop the run before their instruction is fetched,
+    // the first one included. Running again from a breakpoint stop executes the instruction there
+    pub fn run_until(&mut self, max_cycles: u64, mut condition: impl FnMut(&CPU) -> bool) -> StopReason {
+        let start = self.cycles;
+        loop {
+            if let Some(reason) = self.stopped() {
+                return reason;
+            }
+            if !self.breakpoints.is_empty() && self.at_instruction_boundary() {
+                // A program counter past usize cannot be on a breakpoint, the next fetch faults instead
+                let address = self.program_counter_usize().unwrap_or(usize::MAX);
+                let resuming = self.breakpoint_stop == Some((self.cycles, address));
+                if self.breakpoints.contains(&address) && !resuming {
+                    self.breakpoint_stop = Some((self.cycles, address));
+                    return StopReason::Breakpoint(address);
+                }
+            }
+            if self.cycles - start >= max_cycles {
+                return StopReason::CycleLimit;
+            }
+            self.clock();
+            if condition(self) {
+                return StopReason::Condition;
+            }
+        }
+    }
+
+    fn stopped(&self) -> Option<StopReason> {
+        match self.fault {
+            Some(fault) => Some(StopReason::Fault(fault)),
+            None if self.halted => Some(StopReason::Halted),
+            None => None,
+        }
+    }
+
+    // Nothing in flight, the next clock fetches
+    fn at_instruction_boundary(&self) -> bool {
+        self.current_opcode.is_none() && self.stall_cycles == 0
     }
 
     pub fn set_instruction_set(&mut self, instruction_set: HashMap<u8, Instruction>){
@@ -491,25 +1827,79 @@ impl CPU {
 }
 
 pub fn create_default_cpu_with_memory(memory: Memory, storage: Storage) -> CPU {
-    let mut registers = Registers::new();
-    registers.add_register("program_counter".to_string(), 1, 0);
-    registers.add_register("accumulator".to_string(), 1, 1);
-    registers.add_register("flags".to_string(), 1, 2);
-    registers.add_register("stack_pointer".to_string(), 1, 3);
-    registers.add_register("base_pointer".to_string(), 1, 4);
-    registers.add_register("memory_pointer".to_string(), 1, 5);
-    registers.add_register("instruction_temp_0".to_string(), 1, 6);
-    registers.add_register("instruction_temp_1".to_string(), 1, 7);
-    registers.add_register("instruction_temp_2".to_string(), 1, 8);
-    registers.add_register("instruction_temp_3".to_string(), 1, 9);
-    registers.add_register("reg_a".to_string(), 1, 10);
-    registers.add_register("reg_b".to_string(), 1, 11);
-    registers.add_register("reg_c".to_string(), 1, 12);
-    registers.add_register("reg_0".to_string(), 1, 13);
-    registers.add_register("reg_1".to_string(), 1, 14);
-    registers.add_register("reg_2".to_string(), 1, 15);
-
-    CPU::new(registers, memory, storage)
+    create_default_cpu_with_config(memory, storage, MachineConfig::default()).expect("The default config is valid")
+}
+
+// Every register except flags is config.data_size bytes wide
+// Register ids are a byte, and the default file has 20 registers besides reg_0 upwards
+pub const MAX_GENERAL_REGISTERS: usize = 236;
+
+pub fn create_default_cpu_with_config(memory: Memory, storage: Storage, config: MachineConfig) -> Result<CPU, String> {
+    if config.general_registers as usize > MAX_GENERAL_REGISTERS {
+        return Err(format!("{} general registers requested, at most {} fit", config.general_registers, MAX_GENERAL_REGISTERS));
+    }
+    let size = config.data_size as usize;
+    let mut registers = Registers::with_endianness(config.endianness);
+    registers.add_register("program_counter".to_string(), size, 0);
+    registers.add_register("accumulator".to_string(), size, size);
+    registers.add_register("flags".to_string(), 1, size * 2);
+    let mut location = size * 2 + 1;
+    for name in [
+        "stack_pointer",
+        "base_pointer",
+        "memory_address",
+        "instruction_temp_0",
+        "instruction_temp_1",
+        "instruction_temp_2",
+        "instruction_temp_3",
+        "reg_a",
+        "reg_b",
+        "reg_c",
+    ] {
+        registers.add_register(name.to_string(), size, location);
+        location += size;
+    }
+    for index in 0..config.general_registers {
+        registers.add_register(format!("reg_{}", index), size, location);
+        location += size;
+    }
+    for name in [
+        "page_table_base",
+        "fault_address",
+        "supervisor_stack_pointer",
+        "trap_vector",
+        "trap_return",
+        "trap_cause",
+        "core_id",
+    ] {
+        registers.add_register(name.to_string(), size, location);
+        location += size;
+    }
+    // User code may not reconfigure the MMU or trap handling or touch the kernel's stack
+    for name in [
+        "page_table_base",
+        "fault_address",
+        "supervisor_stack_pointer",
+        "trap_vector",
+        "trap_return",
+        "trap_cause",
+    ] {
+        registers.set_privilege(name, Privilege::Supervisor)?;
+    }
+    // Nor renumber its core, though it may read which core it runs on
+    registers.set_write_privilege("core_id", Privilege::Supervisor)?;
+    // Every role's register is named after it, except the ALU operands and the scratch register
+    for role in RegisterRole::ALL {
+        let name = match role {
+            RegisterRole::OperandA => "reg_a",
+            RegisterRole::OperandB => "reg_b",
+            RegisterRole::Scratch => "reg_c",
+            other => other.name(),
+        };
+        registers.set_role(name, role)?;
+    }
+
+    CPU::with_config(registers, memory, storage, config)
 }
 
 pub fn create_default_cpu(memory_size: usize, storage_size: usize) -> CPU {
@@ -520,3 +1910,263 @@ pub fn create_default_cpu(memory_size: usize, storage_size: usize) -> CPU {
 
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::InstructionSet;
+
+    #[test]
+    fn endianness_round_trips_at_fixed_width() {
+        let value = BigUint::from(0x1234u32);
+        assert_eq!(Endianness::Big.encode(&value, 3), vec![0x00, 0x12, 0x34]);
+        assert_eq!(Endianness::Little.encode(&value, 3), vec![0x34, 0x12, 0x00]);
+        assert_eq!(Endianness::Little.decode(&[0x34, 0x12, 0x00]), value);
+        // Wider values wrap to the requested size
+        assert_eq!(Endianness::Big.encode(&BigUint::from(0x1_0001u32), 2), vec![0x00, 0x01]);
+    }
+
+    #[test]
+    fn native_words_match_biguint_encoding() {
+        for endianness in [Endianness::Big, Endianness::Little] {
+            for (value, size) in [(0x1234u64, 3), (0x1_0001, 2), (u64::MAX, 8), (u64::MAX, 10)] {
+                let mut out = vec![0xaa; size];
+                endianness.encode_u64_into(value, &mut out);
+                assert_eq!(out, endianness.encode(&BigUint::from(value), size));
+                if size <= 8 {
+                    assert_eq!(BigUint::from(endianness.decode_u64(&out)), endianness.decode(&out));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn words_wider_than_64_bits_use_biguint() {
+        let mut machine = TestMachine::with_data_size(512, 9);
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        machine.cpu.set_register_value_string("reg_0", BigUint::from(u64::MAX)).unwrap();
+        machine.set_register("reg_1", 1);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::AddReg, &[reg_0, reg_1])
+                .add_instruction(InstructionSet::PushReg, &[reg_0])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true).memory(512 - 9, &[1, 0, 0, 0, 0, 0, 0, 0, 0]).check();
+        assert_eq!(machine.register("reg_0"), BigUint::one() << 64);
+    }
+
+    #[test]
+    fn machines_without_trap_registers_fault_instead_of_trapping() {
+        let mut registers = Registers::new();
+        for (name, role) in [("pc", RegisterRole::ProgramCounter), ("acc", RegisterRole::Accumulator), ("sp", RegisterRole::StackPointer), ("vector", RegisterRole::TrapVector)] {
+            registers.add_register(name.to_string(), 1, registers.total_length);
+            registers.set_role(name, role).unwrap();
+        }
+        let mut cpu = CPU::new(registers, Memory::new(16), Storage::new(0)).unwrap();
+        assert!(!cpu.can_trap());
+        let mut writer = crate::writers::InstructionSetWriter::new(1);
+        writer.add_instruction(InstructionSet::SystemCall, &[]).add_sub_instruction(SubInstructions::SystemCall);
+        cpu.instruction_set = writer.build();
+        cpu.set_register_value_string("vector", 8u8.into()).unwrap();
+        cpu.privilege = Privilege::User;
+        cpu.memory.write(0, InstructionSet::SystemCall as u8);
+        cpu.memory.write(8, 0xee);
+        assert_eq!(cpu.run_until_halt(100), StopReason::Fault(Fault::InvalidOpcode { address: 0 }));
+
+        // A fault stops the CPU rather than entering a handler it could not return from
+        cpu.fault = None;
+        cpu.set_program_counter(8u8.into());
+        assert_eq!(cpu.run_until_halt(100), StopReason::Fault(Fault::InvalidOpcode { address: 8 }));
+        assert_eq!(cpu.privilege, Privilege::User);
+    }
+
+    #[test]
+    fn addresses_past_usize_are_bus_errors() {
+        let past_usize = (BigUint::one() << 72u32) - BigUint::one();
+        let bus_error = Some(Fault::BusError { address: usize::MAX });
+        for translated in [false, true] {
+            let mut machine = TestMachine::with_data_size(512, 9);
+            if translated {
+                machine.cpu.block_cache = Some(BlockCache::default());
+            }
+            machine.cpu.branch_unit = Some(BranchUnit::new(Box::new(crate::branch::TwoBit::new(64)), 3));
+            let (reg_0, reg_2) = (machine.reg("reg_0"), machine.reg("reg_2"));
+            machine.program(|p| {
+                p.add_instruction(InstructionSet::JumpNotEqual, &[reg_2, reg_0, 1]);
+            });
+            machine.cpu.set_register_value_string("reg_2", past_usize.clone()).unwrap();
+            machine.run(200);
+            machine.expect().fault(bus_error).check();
+            assert_eq!(machine.cpu.get_program_counter(), past_usize);
+        }
+
+        let mut machine = TestMachine::with_data_size(512, 9);
+        let (reg_0, reg_1) = (machine.reg("reg_0"), machine.reg("reg_1"));
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadFromMemoryReg, &[reg_0, reg_1]);
+        });
+        machine.cpu.set_register_value_string("reg_0", past_usize.clone()).unwrap();
+        machine.run(100);
+        machine.expect().fault(bus_error).check();
+
+        let mut machine = TestMachine::with_data_size(512, 9);
+        let reg_0 = machine.reg("reg_0");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::PopReg, &[reg_0]);
+        });
+        machine.cpu.set_register_value_string("stack_pointer", past_usize).unwrap();
+        machine.run(100);
+        machine.expect().fault(bus_error).check();
+    }
+
+    // Counts reg_0 up forever, the JumpNotEqual is at address 3
+    fn count_up(machine: &mut TestMachine) {
+        let reg_0 = machine.reg("reg_0");
+        let reg_2 = machine.reg("reg_2");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::AddImmediate, &[reg_0, 1])
+                .add_instruction(InstructionSet::JumpNotEqual, &[reg_2, reg_0, 0]);
+        });
+    }
+
+    #[test]
+    fn run_methods_report_why_they_stopped() {
+        let mut machine = TestMachine::default();
+        count_up(&mut machine);
+        assert_eq!(machine.cpu.run_for(100), StopReason::CycleLimit);
+        assert_eq!(machine.cpu.cycles, 100);
+        assert_eq!(machine.cpu.run_for(7), StopReason::CycleLimit);
+        assert_eq!(machine.cpu.cycles, 107);
+        let reason = machine.cpu.run_until(1000, |cpu| cpu.read_register_value_string("reg_0") == Some(BigUint::from(40u8)));
+        assert_eq!(reason, StopReason::Condition);
+
+        let mut machine = TestMachine::default();
+        machine.load(0, &[InstructionSet::NoOperation as u8, InstructionSet::Halt as u8]);
+        assert_eq!(machine.cpu.run_until_halt(100), StopReason::Halted);
+        assert_eq!(machine.cpu.run_until_halt(100), StopReason::Halted);
+
+        let mut machine = TestMachine::new(4);
+        machine.load(0, &[InstructionSet::Jump as u8, 9]);
+        assert_eq!(machine.cpu.run_until_halt(100), StopReason::Fault(Fault::BusError { address: 9 }));
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_fetch() {
+        let mut machine = TestMachine::default();
+        count_up(&mut machine);
+        machine.cpu.breakpoints.insert(3);
+        assert_eq!(machine.cpu.run_until_halt(1000), StopReason::Breakpoint(3));
+        machine.expect().register("reg_0", 1).check();
+        // Resuming from the breakpoint runs the instruction there before stopping again
+        assert_eq!(machine.cpu.run_until_halt(1000), StopReason::Breakpoint(3));
+        machine.expect().register("reg_0", 2).check();
+
+        // A breakpoint on the entry address stops before anything runs
+        let mut machine = TestMachine::default();
+        count_up(&mut machine);
+        machine.cpu.breakpoints.insert(0);
+        assert_eq!(machine.cpu.run_until_halt(1000), StopReason::Breakpoint(0));
+        assert_eq!(machine.cpu.cycles, 0);
+        machine.cpu.breakpoints.insert(3);
+        assert_eq!(machine.cpu.run_until_halt(1000), StopReason::Breakpoint(3));
+        machine.expect().register("reg_0", 1).check();
+    }
+
+    #[test]
+    fn step_instruction_runs_one_instruction() {
+        for translated in [false, true] {
+            let mut machine = TestMachine::default();
+            count_up(&mut machine);
+            if translated {
+                machine.cpu.block_cache = Some(crate::blocks::BlockCache::default());
+            }
+            assert_eq!(machine.cpu.step_instruction(), StopReason::Stepped);
+            assert_eq!(machine.cpu.get_program_counter(), BigUint::from(3u8));
+            assert_eq!(machine.cpu.step_instruction(), StopReason::Stepped);
+            assert_eq!(machine.cpu.get_program_counter(), BigUint::zero());
+            assert_eq!(machine.cpu.retired, 2);
+            machine.expect().register("reg_0", 1).check();
+        }
+    }
+
+    #[test]
+    fn byte_views_follow_endianness() {
+        for endianness in [Endianness::Big, Endianness::Little] {
+            let mut machine = TestMachine::with_config(64, MachineConfig { data_size: 2, endianness, ..MachineConfig::default() });
+            for (parent, high, low) in [("reg_0", "reg_0_high", "reg_0_low"), ("reg_1", "reg_1_high", "reg_1_low")] {
+                machine.cpu.registers.add_sub_register(high.to_string(), parent, 8, 8).unwrap();
+                machine.cpu.registers.add_sub_register(low.to_string(), parent, 0, 8).unwrap();
+            }
+            machine.set_register("reg_0", 0x1234);
+            machine.expect().register("reg_0_high", 0x12).register("reg_0_low", 0x34).check();
+            let (reg_0_high, reg_1_low) = (machine.reg("reg_0_high"), machine.reg("reg_1_low"));
+            machine.program(|p| {
+                p.add_instruction(InstructionSet::MoveRegister, &[reg_0_high, reg_1_low])
+                    .add_instruction(InstructionSet::Halt, &[]);
+            });
+            machine.set_register("reg_0_low", 0xff);
+            machine.run(100);
+            machine.expect().halted(true).register("reg_0", 0x12ff).register("reg_1", 0x0012).check();
+        }
+    }
+
+    #[test]
+    fn moves_between_registers_of_different_sizes_widen_or_wrap() {
+        for endianness in [Endianness::Big, Endianness::Little] {
+            let mut machine = TestMachine::with_config(64, MachineConfig { data_size: 2, endianness, ..MachineConfig::default() });
+            let (reg_0, reg_1, flags) = (machine.reg("reg_0"), machine.reg("reg_1"), machine.reg("flags"));
+            machine.program(|p| {
+                p.add_instruction(InstructionSet::MoveRegister, &[reg_0, flags])
+                    .add_instruction(InstructionSet::MoveRegister, &[flags, reg_1])
+                    .add_instruction(InstructionSet::Halt, &[]);
+            });
+            machine.set_register("reg_0", 0x0102).set_register("reg_1", 0xffff);
+            machine.run(100);
+            machine.expect().halted(true).register("flags", 0x02).register("reg_1", 0x0002).check();
+        }
+    }
+
+    #[test]
+    fn bit_field_writes_keep_neighbouring_bits() {
+        let mut machine = TestMachine::default();
+        machine.cpu.registers.add_sub_register("nibble".to_string(), "reg_0", 4, 4).unwrap();
+        machine.set_register("reg_0", 0b1010_0101);
+        machine.expect().register("nibble", 0b1010).check();
+        machine.set_register("nibble", 0b0011);
+        machine.expect().register("reg_0", 0b0011_0101).check();
+        assert!(machine.cpu.registers.add_sub_register("wide".to_string(), "reg_0", 4, 8).is_err());
+        assert!(machine.cpu.registers.add_sub_register("inner".to_string(), "nibble", 0, 2).is_err());
+
+        // Views are placed in the register file's byte order, so the CPU must share it
+        let mut registers = Registers::with_endianness(Endianness::Little);
+        for (name, role) in [("pc", RegisterRole::ProgramCounter), ("acc", RegisterRole::Accumulator), ("sp", RegisterRole::StackPointer)] {
+            registers.add_register(name.to_string(), 2, registers.total_length);
+            registers.set_role(name, role).unwrap();
+        }
+        assert_eq!(registers.set_role("missing", RegisterRole::Scratch), Err("Register 'missing' not found".to_string()));
+        registers.add_sub_register("acc_low".to_string(), "acc", 0, 8).unwrap();
+        let config = MachineConfig { data_size: 2, endianness: Endianness::Big, ..MachineConfig::default() };
+        assert!(CPU::with_config(registers, Memory::new(8), Storage::new(0), config).err().unwrap().contains("Little endian"));
+    }
+
+    // cargo test --release cycles_per_second -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn cycles_per_second() {
+        let mut machine = TestMachine::with_data_size(64, 4);
+        let reg_0 = machine.reg("reg_0") as u64;
+        let reg_2 = machine.reg("reg_2") as u64;
+        machine.program(|p| {
+            p.add_instruction_wide(InstructionSet::AddImmediate, &[reg_0, 1])
+                .add_instruction_wide(InstructionSet::JumpNotEqual, &[reg_2, reg_0, 0])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let cycles = 2_000_000;
+        let start = std::time::Instant::now();
+        machine.run(cycles);
+        let elapsed = start.elapsed();
+        println!("{} cycles in {:?}, {:.0} cycles per second", cycles, elapsed, cycles as f64 / elapsed.as_secs_f64());
+    }
+}
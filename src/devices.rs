@@ -0,0 +1,95 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+// Memory mapped devices.
+//
+// A device answers for a range of physical addresses in place of Memory. read_memory and write_memory
+// hand it each byte accessed in its range along with the byte's offset from the start of the range.
+// Device accesses are not cached, and they are not undone when a later micro-op of the same instruction
+// faults. Code is never translated from a device, the micro-step engine fetches it a byte at a time.
+// Each CPU has its own devices, cores of a MultiCore do not share them.
+//
+// cpu.attach_device("uart", 0x30..0x31, Box::new(Console::default()))?;
+// cpu.device::<Console>("uart").unwrap().output
+
+pub trait Device: Any {
+    fn read(&mut self, offset: usize) -> u8;
+    fn write(&mut self, offset: usize, value: u8);
+}
+
+pub struct MappedDevice {
+    pub name: String,
+    pub range: Range<usize>,
+    pub device: Box<dyn Device>,
+}
+
+// Kinds a machine description can name in device=<kind>
+pub const DEVICE_KINDS: &[&str] = &["console"];
+
+pub fn create_device(kind: &str) -> Option<Box<dyn Device>> {
+    match kind {
+        "console" => Some(Box::new(Console::default())),
+        _ => None,
+    }
+}
+
+// A character device at every offset: writes are collected in output, reads take the next byte of
+// input, or 0 once input is empty
+#[derive(Debug, Default)]
+pub struct Console {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl Device for Console {
+    fn read(&mut self, _offset: usize) -> u8 {
+        self.input.pop_front().unwrap_or(0)
+    }
+
+    fn write(&mut self, _offset: usize, value: u8) {
+        self.output.push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockCache;
+    use crate::harness::TestMachine;
+    use crate::InstructionSet;
+
+    #[test]
+    fn guest_stores_reach_the_console() {
+        for translated in [false, true] {
+            let mut machine = TestMachine::default();
+            if translated {
+                machine.cpu.block_cache = Some(BlockCache::default());
+            }
+            machine.cpu.attach_device("console", 0x30..0x31, Box::new(Console::default())).unwrap();
+            machine.cpu.device_mut::<Console>("console").unwrap().input.push_back(b'!');
+            let reg_0 = machine.reg("reg_0");
+            machine.program(|p| {
+                p.add_instruction(InstructionSet::LoadImmediate, &[reg_0, b'h'])
+                    .add_instruction(InstructionSet::StoreToMemory, &[reg_0, 0x30])
+                    .add_instruction(InstructionSet::LoadFromMemory, &[0x30, reg_0])
+                    .add_instruction(InstructionSet::StoreToMemory, &[reg_0, 0x30])
+                    .add_instruction(InstructionSet::Halt, &[]);
+            });
+            machine.run(200);
+            machine.expect().halted(true).register("reg_0", b'!' as u64).memory(0x30, &[0]).check();
+            assert_eq!(machine.cpu.device::<Console>("console").unwrap().output, b"h!");
+        }
+    }
+
+    #[test]
+    fn code_behind_a_device_is_fetched_from_it() {
+        let mut machine = TestMachine::default();
+        machine.cpu.block_cache = Some(BlockCache::default());
+        machine.cpu.attach_device("rom", 0..1, Box::new(Console::default())).unwrap();
+        machine.cpu.device_mut::<Console>("rom").unwrap().input.push_back(InstructionSet::Halt as u8);
+        machine.load(0, &[InstructionSet::NoOperation as u8]);
+        machine.run(50);
+        machine.expect().halted(true).check();
+    }
+}
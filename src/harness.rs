@@ -0,0 +1,212 @@
+use std::fmt::Write;
+
+use num_bigint::BigUint;
+
+use crate::computer::{Fault, MachineConfig, Privilege, CPU};
+use crate::create_machine;
+use crate::writers::ProgramWriter;
+
+// Harness for running small guest programs in tests.
+//
+// let mut machine = TestMachine::new(64);
+// let reg_0 = machine.reg("reg_0");
+// machine.program(|p| {
+//     p.add_instruction(InstructionSet::LoadImmediate, &[reg_0, 5])
+//         .add_instruction(InstructionSet::Halt, &[]);
+// });
+// machine.run(100);
+// machine.expect().register("reg_0", 5).halted(true).check();
+
+pub const DEFAULT_MEMORY_SIZE: usize = 64;
+
+pub struct TestMachine {
+    pub cpu: CPU,
+    pub cycles: u32,
+}
+
+impl TestMachine {
+    pub fn new(memory_size: usize) -> Self {
+        TestMachine::with_data_size(memory_size, 1)
+    }
+
+    pub fn with_data_size(memory_size: usize, data_size: u8) -> Self {
+        TestMachine::with_config(memory_size, MachineConfig { data_size, ..MachineConfig::default() })
+    }
+
+    pub fn with_config(memory_size: usize, config: MachineConfig) -> Self {
+        let cpu = create_machine(memory_size, 0, config).unwrap_or_else(|error| panic!("{}", error));
+        TestMachine { cpu, cycles: 0 }
+    }
+
+    pub fn reg(&mut self, name: &str) -> u8 {
+        self.cpu.registers.name_to_u8(name)
+    }
+
+    // Builds a program with a ProgramWriter and writes it at address 0
+    pub fn program(&mut self, build: impl FnOnce(&mut ProgramWriter)) -> &mut Self {
+        let mut program_writer = ProgramWriter::new(self.cpu.instruction_set.clone(), &self.cpu.registers);
+        program_writer.set_endianness(self.cpu.endianness).set_memory_size(self.cpu.memory.size());
+        build(&mut program_writer);
+        let program = program_writer.build();
+        self.load(0, &program)
+    }
+
+    pub fn load(&mut self, address: usize, bytes: &[u8]) -> &mut Self {
+        self.cpu.memory.write_chunk(address, bytes);
+        self
+    }
+
+    pub fn set_register(&mut self, name: &str, value: u64) -> &mut Self {
+        let size = self.cpu.registers.look_up_string(name)
+            .unwrap_or_else(|| panic!("Register '{}' not found", name))
+            .size;
+        assert!(size >= 8 || value >> (size * 8) == 0, "Value {} does not fit in {} byte(s)", value, size);
+        self.cpu.set_register_value_string(name, value.into()).unwrap();
+        self
+    }
+
+    // Clocks the CPU until it halts, faults or max_cycles is reached, returns the cycles used
+    pub fn run(&mut self, max_cycles: u32) -> u32 {
+        let start = self.cycles;
+        while !self.cpu.is_halted() && !self.cpu.is_faulted() && self.cycles - start < max_cycles {
+            self.cpu.clock();
+            self.cycles += 1;
+        }
+        self.cycles - start
+    }
+
+    pub fn register(&self, name: &str) -> BigUint {
+        self.cpu.read_register_value_string(name)
+            .unwrap_or_else(|| panic!("Register '{}' not found", name))
+    }
+
+    pub fn expect(&self) -> Expectations<'_> {
+        Expectations {
+            machine: self,
+            mismatches: Vec::new(),
+        }
+    }
+}
+
+impl Default for TestMachine {
+    fn default() -> Self {
+        TestMachine::new(DEFAULT_MEMORY_SIZE)
+    }
+}
+
+// Collects every failed expectation so a single panic reports all of them
+pub struct Expectations<'a> {
+    machine: &'a TestMachine,
+    mismatches: Vec<String>,
+}
+
+impl Expectations<'_> {
+    pub fn register(mut self, name: &str, expected: u64) -> Self {
+        let actual = self.machine.register(name);
+        if actual != BigUint::from(expected) {
+            self.mismatches.push(format!("register {}: expected {}, got {}", name, expected, actual));
+        }
+        self
+    }
+
+    pub fn flags(self, expected: &BigUint) -> Self {
+        let expected = u64::try_from(expected).expect("Flags wider than 64 bits");
+        self.register("flags", expected)
+    }
+
+    pub fn halted(mut self, expected: bool) -> Self {
+        let actual = self.machine.cpu.is_halted();
+        if actual != expected {
+            self.mismatches.push(format!(
+                "halted: expected {}, got {} after {} cycle(s)",
+                expected, actual, self.machine.cycles
+            ));
+        }
+        self
+    }
+
+    pub fn fault(mut self, expected: Option<Fault>) -> Self {
+        let actual = self.machine.cpu.fault;
+        if actual != expected {
+            self.mismatches.push(format!("fault: expected {:?}, got {:?}", expected, actual));
+        }
+        self
+    }
+
+    pub fn privilege(mut self, expected: Privilege) -> Self {
+        let actual = self.machine.cpu.privilege;
+        if actual != expected {
+            self.mismatches.push(format!("privilege: expected {:?}, got {:?}", expected, actual));
+        }
+        self
+    }
+
+    pub fn memory(mut self, start: usize, expected: &[u8]) -> Self {
+        let actual = self.machine.cpu.memory.read_chunk(start, start + expected.len());
+        if actual != expected {
+            self.mismatches.push(memory_diff(start, expected, actual));
+        }
+        self
+    }
+
+    pub fn check(self) {
+        if self.mismatches.is_empty() {
+            return;
+        }
+        let mut report = format!("{} expectation(s) failed:\n", self.mismatches.len());
+        for mismatch in &self.mismatches {
+            let _ = writeln!(report, "  - {}", mismatch);
+        }
+        panic!("{}", report);
+    }
+}
+
+// Renders expected and actual bytes side by side, one row of 8 per line, marking differing bytes
+fn memory_diff(start: usize, expected: &[u8], actual: &[u8]) -> String {
+    let mut diff = format!("memory {:#06x}..{:#06x} differs:", start, start + expected.len());
+    for (row, (expected_row, actual_row)) in expected.chunks(8).zip(actual.chunks(8)).enumerate() {
+        let address = start + row * 8;
+        let mut expected_text = String::new();
+        let mut actual_text = String::new();
+        let mut marker_text = String::new();
+        for (expected_byte, actual_byte) in expected_row.iter().zip(actual_row) {
+            let _ = write!(expected_text, "{:02x} ", expected_byte);
+            let _ = write!(actual_text, "{:02x} ", actual_byte);
+            marker_text.push_str(if expected_byte == actual_byte { "   " } else { "^^ " });
+        }
+        let _ = write!(diff, "\n      {:#06x} expected {}", address, expected_text.trim_end());
+        let _ = write!(diff, "\n             actual   {}", actual_text.trim_end());
+        if marker_text.contains('^') {
+            let _ = write!(diff, "\n                      {}", marker_text.trim_end());
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_diff_marks_differing_bytes() {
+        let diff = memory_diff(4, &[1, 2, 3], &[1, 9, 3]);
+        assert!(diff.contains("expected 01 02 03"), "{}", diff);
+        assert!(diff.contains("actual   01 09 03"), "{}", diff);
+        assert!(diff.contains("   ^^"), "{}", diff);
+    }
+
+    #[test]
+    #[should_panic(expected = "2 expectation(s) failed")]
+    fn check_reports_every_mismatch() {
+        let machine = TestMachine::default();
+        machine.expect().register("reg_0", 1).halted(true).check();
+    }
+
+    #[test]
+    fn run_stops_at_cycle_limit() {
+        // An empty program is all NoOperation, so it never halts
+        let mut machine = TestMachine::default();
+        assert_eq!(machine.run(10), 10);
+        machine.expect().halted(false).check();
+    }
+}
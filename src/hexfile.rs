@@ -0,0 +1,371 @@
+use std::fmt::Write;
+
+use crate::computer::{Memory, Storage};
+
+// Intel HEX and Motorola S-record images.
+//
+// Both are line based text formats of checksummed records, each carrying up to a few dozen bytes for
+// an address. A HexImage holds the bytes as contiguous chunks plus the optional start address, and is
+// moved in and out of Memory or Storage through write_chunk and read_chunk.
+//
+// let image = HexImage::parse(&std::fs::read_to_string("program.hex")?)?;
+// image.load_into(&mut cpu.memory)?;
+// let text = HexImage::dump(&cpu.memory, 0, 64)?.to_srecord()?;
+//
+// Intel HEX records 00 to 05 are understood, segment addresses included, as are S-records S0 to S9.
+// The S5/S6 record count is checked when present.
+
+// Bytes per data record when writing
+const RECORD_BYTES: usize = 16;
+
+// Memory and Storage as seen by the loaders
+pub trait ByteStore {
+    // For error messages
+    fn name(&self) -> &'static str;
+    fn size(&self) -> usize;
+    fn read_chunk(&self, start: usize, end: usize) -> &[u8];
+    fn write_chunk(&mut self, start: usize, data: &[u8]);
+}
+
+impl ByteStore for Memory {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn size(&self) -> usize {
+        Memory::size(self)
+    }
+
+    fn read_chunk(&self, start: usize, end: usize) -> &[u8] {
+        Memory::read_chunk(self, start, end)
+    }
+
+    fn write_chunk(&mut self, start: usize, data: &[u8]) {
+        Memory::write_chunk(self, start, data)
+    }
+}
+
+impl ByteStore for Storage {
+    fn name(&self) -> &'static str {
+        "storage"
+    }
+
+    fn size(&self) -> usize {
+        Storage::size(self)
+    }
+
+    fn read_chunk(&self, start: usize, end: usize) -> &[u8] {
+        Storage::read_chunk(self, start, end)
+    }
+
+    fn write_chunk(&mut self, start: usize, data: &[u8]) {
+        Storage::write_chunk(self, start, data)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HexImage {
+    // (address, bytes) in file order, records that follow on from each other are merged
+    pub chunks: Vec<(usize, Vec<u8>)>,
+    // Where execution starts, from a start address or termination record
+    pub start: Option<usize>,
+}
+
+impl HexImage {
+    // Picks the format from the first record
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.trim_start().chars().next() {
+            Some(':') => HexImage::parse_intel_hex(text),
+            Some('S') | Some('s') => HexImage::parse_srecord(text),
+            _ => Err("Neither an Intel HEX nor an S-record file".to_string()),
+        }
+    }
+
+    pub fn parse_intel_hex(text: &str) -> Result<Self, String> {
+        let mut image = HexImage::default();
+        let mut base = 0;
+        let mut ended = false;
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let error = |message: String| format!("line {}: {}", line, message);
+            let record = raw.trim();
+            if record.is_empty() {
+                continue;
+            }
+            if ended {
+                return Err(error("record after the end of file record".to_string()));
+            }
+            let Some(hex) = record.strip_prefix(':') else {
+                return Err(error("record does not start with ':'".to_string()));
+            };
+            let bytes = decode_checked(hex, |sum| sum == 0).map_err(error)?;
+            if bytes.len() < 5 || bytes[0] as usize != bytes.len() - 5 {
+                return Err(error(format!("length {} does not match the record", bytes.first().copied().unwrap_or(0))));
+            }
+            let offset = (bytes[1] as usize) << 8 | bytes[2] as usize;
+            let data = &bytes[4..bytes.len() - 1];
+            let value = data.iter().fold(0usize, |value, byte| value << 8 | *byte as usize);
+            match (bytes[3], data.len()) {
+                (0x00, _) => image.push(base + offset, data),
+                (0x01, 0) => ended = true,
+                (0x02, 2) => base = value << 4,
+                (0x03, 4) => image.start = Some((value >> 16 << 4) + (value & 0xffff)),
+                (0x04, 2) => base = value << 16,
+                (0x05, 4) => image.start = Some(value),
+                (kind @ 0x01..=0x05, length) => return Err(error(format!("record type {:02X} cannot hold {} byte(s)", kind, length))),
+                (kind, _) => return Err(error(format!("unknown record type {:02X}", kind))),
+            }
+        }
+        if !ended {
+            return Err("missing end of file record".to_string());
+        }
+        Ok(image)
+    }
+
+    pub fn parse_srecord(text: &str) -> Result<Self, String> {
+        let mut image = HexImage::default();
+        let mut data_records = 0;
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let error = |message: String| format!("line {}: {}", line, message);
+            let record = raw.trim();
+            if record.is_empty() {
+                continue;
+            }
+            let mut chars = record.chars();
+            let (Some('S' | 's'), Some(kind)) = (chars.next(), chars.next()) else {
+                return Err(error("record does not start with S and a type".to_string()));
+            };
+            let address_size = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                other => return Err(error(format!("unknown record type S{}", other))),
+            };
+            let bytes = decode_checked(&record[2..], |sum| sum == 0xff).map_err(error)?;
+            if bytes.len() < 1 + address_size + 1 || bytes[0] as usize != bytes.len() - 1 {
+                return Err(error(format!("count {} does not match the record", bytes.first().copied().unwrap_or(0))));
+            }
+            let address = bytes[1..1 + address_size].iter().fold(0usize, |value, byte| value << 8 | *byte as usize);
+            let data = &bytes[1 + address_size..bytes.len() - 1];
+            match kind {
+                '0' => {}
+                '1' | '2' | '3' => {
+                    image.push(address, data);
+                    data_records += 1;
+                }
+                '5' | '6' if address != data_records => {
+                    return Err(error(format!("count record says {} data records, there were {}", address, data_records)));
+                }
+                '5' | '6' => {}
+                _ => image.start = Some(address),
+            }
+        }
+        Ok(image)
+    }
+
+    // Reads start..end, failing if the range is outside the store
+    pub fn dump(store: &impl ByteStore, start: usize, end: usize) -> Result<Self, String> {
+        if start > end || end > store.size() {
+            return Err(format!("Range {:#x}..{:#x} is outside the {} byte {}", start, end, store.size(), store.name()));
+        }
+        let mut image = HexImage::default();
+        image.push(start, store.read_chunk(start, end));
+        Ok(image)
+    }
+
+    // Writes every chunk, nothing is written unless they all fit
+    pub fn load_into(&self, store: &mut impl ByteStore) -> Result<(), String> {
+        if let Some((address, bytes)) = self.chunks.iter().find(|(address, bytes)| address + bytes.len() > store.size()) {
+            return Err(format!("{} byte(s) at {:#x} are outside the {} byte {}", bytes.len(), address, store.size(), store.name()));
+        }
+        for (address, bytes) in &self.chunks {
+            store.write_chunk(*address, bytes);
+        }
+        Ok(())
+    }
+
+    pub fn to_intel_hex(&self) -> Result<String, String> {
+        self.check_addresses("Intel HEX")?;
+        let mut text = String::new();
+        let mut upper = 0;
+        for (address, offset, bytes) in self.records() {
+            let address = address + offset;
+            if address >> 16 != upper {
+                upper = address >> 16;
+                write_intel_hex_record(&mut text, 0, 0x04, &[(upper >> 8) as u8, upper as u8]);
+            }
+            write_intel_hex_record(&mut text, address & 0xffff, 0x00, bytes);
+        }
+        if let Some(start) = self.start {
+            write_intel_hex_record(&mut text, 0, 0x05, &(start as u32).to_be_bytes());
+        }
+        write_intel_hex_record(&mut text, 0, 0x01, &[]);
+        Ok(text)
+    }
+
+    // Uses the smallest address size that fits every record, S1, S2 or S3
+    pub fn to_srecord(&self) -> Result<String, String> {
+        self.check_addresses("S-records")?;
+        let highest = self.chunks.iter().map(|(address, bytes)| address + bytes.len()).chain(self.start).max().unwrap_or(0);
+        let (data_kind, end_kind, address_size) = match highest {
+            0..=0xffff => ('1', '9', 2),
+            0x10000..=0xff_ffff => ('2', '8', 3),
+            _ => ('3', '7', 4),
+        };
+        let mut text = String::new();
+        write_srecord(&mut text, '0', 0, 2, &[]);
+        let mut count = 0;
+        for (address, offset, bytes) in self.records() {
+            write_srecord(&mut text, data_kind, address + offset, address_size, bytes);
+            count += 1;
+        }
+        if count <= 0xffff {
+            write_srecord(&mut text, '5', count, 2, &[]);
+        }
+        write_srecord(&mut text, end_kind, self.start.unwrap_or(0), address_size, &[]);
+        Ok(text)
+    }
+
+    // Both formats carry at most 32 bit addresses
+    fn check_addresses(&self, format: &str) -> Result<(), String> {
+        if let Some((address, bytes)) = self.chunks.iter().find(|(address, bytes)| (address + bytes.len()) as u64 > 1 << 32) {
+            return Err(format!("{} byte(s) at {:#x} are past the 32 bit addresses of {}", bytes.len(), address, format));
+        }
+        match self.start {
+            Some(start) if start as u64 > u32::MAX as u64 => Err(format!("Start address {:#x} is past the 32 bit addresses of {}", start, format)),
+            _ => Ok(()),
+        }
+    }
+
+    fn push(&mut self, address: usize, data: &[u8]) {
+        match self.chunks.last_mut() {
+            Some((start, bytes)) if *start + bytes.len() == address => bytes.extend(data),
+            _ => self.chunks.push((address, data.to_vec())),
+        }
+    }
+
+    // Chunks split into (chunk address, offset, bytes) records of at most RECORD_BYTES. Intel HEX
+    // records also never cross a 64K boundary, their address only has 16 bits
+    fn records(&self) -> Vec<(usize, usize, &[u8])> {
+        let mut records = Vec::new();
+        for (address, bytes) in &self.chunks {
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let to_boundary = 0x10000 - (address + offset) % 0x10000;
+                let length = RECORD_BYTES.min(bytes.len() - offset).min(to_boundary);
+                records.push((*address, offset, &bytes[offset..offset + length]));
+                offset += length;
+            }
+        }
+        records
+    }
+}
+
+// Decodes hex pairs and checks the low byte of their sum, the checksum being the last pair
+fn decode_checked(hex: &str, valid: impl Fn(u8) -> bool) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("'{}' is not a whole number of hex bytes", hex));
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).map_err(|_| format!("'{}' is not hex", &hex[index..index + 2])))
+        .collect::<Result<Vec<u8>, String>>()?;
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    match valid(sum) {
+        true => Ok(bytes),
+        false => Err(format!("checksum {:02X} is wrong", bytes.last().copied().unwrap_or(0))),
+    }
+}
+
+fn write_intel_hex_record(text: &mut String, address: usize, kind: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(sum.wrapping_neg());
+    text.push(':');
+    for byte in bytes {
+        let _ = write!(text, "{:02X}", byte);
+    }
+    text.push('\n');
+}
+
+fn write_srecord(text: &mut String, kind: char, address: usize, address_size: usize, data: &[u8]) {
+    let mut bytes = vec![(address_size + data.len() + 1) as u8];
+    bytes.extend(&(address as u32).to_be_bytes()[4 - address_size..]);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(!sum);
+    text.push('S');
+    text.push(kind);
+    for byte in bytes {
+        let _ = write!(text, "{:02X}", byte);
+    }
+    text.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_intel_hex() {
+        let text = ":10010000214601360121470136007EFE09D2190140\n:00000001FF\n";
+        let image = HexImage::parse(text).unwrap();
+        assert_eq!(image.chunks, vec![(0x100, vec![0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7e, 0xfe, 0x09, 0xd2, 0x19, 0x01])]);
+        assert_eq!(image.to_intel_hex(), Ok(text.to_string()));
+
+        // Extended linear addresses above 64K and a start address
+        let image = HexImage { chunks: vec![(0xfff8, (0..24).collect())], start: Some(0x12345) };
+        assert_eq!(HexImage::parse(&image.to_intel_hex().unwrap()), Ok(image));
+    }
+
+    #[test]
+    fn reads_and_writes_srecords() {
+        let text = "S00F000068656C6C6F202020202000003C\nS11F00007C0802A6900100049421FFF07C6C1B787C8C23783C6000003863000026\nS5030001FB\nS9030000FC\n";
+        let image = HexImage::parse(text).unwrap();
+        assert_eq!(image.chunks[0].0, 0);
+        assert_eq!(image.chunks[0].1.len(), 28);
+        assert_eq!(image.start, Some(0));
+
+        let image = HexImage { chunks: vec![(0x1_0000, vec![1, 2, 3]), (0x20, vec![4])], start: Some(0x20) };
+        let written = image.to_srecord().unwrap();
+        assert!(written.lines().nth(1).unwrap().starts_with("S2"), "{}", written);
+        assert_eq!(HexImage::parse(&written), Ok(image));
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let error = HexImage::parse(":10010000214601360121470136007EFE09D2190141\n:00000001FF\n").unwrap_err();
+        assert_eq!(error, "line 1: checksum 41 is wrong");
+        assert_eq!(HexImage::parse(":0100000000FF\n").unwrap_err(), "missing end of file record");
+        assert_eq!(HexImage::parse(":0200000000FE\n").unwrap_err(), "line 1: length 2 does not match the record");
+        assert!(HexImage::parse("S1030000FC\nS5030002FA\n").unwrap_err().starts_with("line 2: count record says 2"));
+        assert!(HexImage::parse("SX030000FC\n").unwrap_err().contains("unknown record type SX"));
+    }
+
+    #[test]
+    fn rejects_addresses_past_32_bits() {
+        let image = HexImage { chunks: vec![(0xffff_fffe, vec![1, 2, 3])], start: None };
+        assert_eq!(image.to_intel_hex().unwrap_err(), "3 byte(s) at 0xfffffffe are past the 32 bit addresses of Intel HEX");
+        assert!(image.to_srecord().unwrap_err().contains("S-records"));
+        let image = HexImage { chunks: vec![(0xffff_fffe, vec![1, 2])], start: Some(1 << 32) };
+        assert_eq!(image.to_srecord().unwrap_err(), "Start address 0x100000000 is past the 32 bit addresses of S-records");
+        assert!(HexImage { start: None, ..image }.to_intel_hex().is_ok());
+    }
+
+    #[test]
+    fn loads_and_dumps_memory_and_storage() {
+        let mut memory = Memory::new(32);
+        let mut storage = Storage::new(8);
+        let image = HexImage::parse(":04001C00DEADBEEFA8\n:00000001FF\n").unwrap();
+        image.load_into(&mut memory).unwrap();
+        assert_eq!(memory.read_chunk(0x1c, 0x20), &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(image.load_into(&mut storage).unwrap_err(), "4 byte(s) at 0x1c are outside the 8 byte storage");
+
+        let dumped = HexImage::dump(&memory, 0x1c, 0x20).unwrap();
+        HexImage::parse(&dumped.to_srecord().unwrap()).unwrap().chunks.iter().for_each(|(address, bytes)| storage.write_chunk(address - 0x1c, bytes));
+        assert_eq!(storage.read_chunk(0, 4), &[0xde, 0xad, 0xbe, 0xef]);
+        assert!(HexImage::dump(&storage, 4, 9).is_err());
+    }
+}
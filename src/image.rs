@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+
+use crate::computer::{Endianness, Instruction, Operand, SubInstructions};
+use crate::linker::LinkedImage;
+use crate::mmu::{PAGE_EXECUTE, PAGE_READ, PAGE_WRITE};
+use crate::object::{endianness_from_u8, endianness_to_u8, put_bytes, put_u32, ByteReader, SectionKind};
+
+// Executable images.
+//
+// A container for a linked program: which instruction set it was built for, where to start and which
+// segments to load where. CPU::load_executable refuses an image whose ISA hash differs from the
+// CPU's instruction set, so a program built against other microcode fails before it runs.
+//
+// All numbers are little endian, names and byte strings are a u32 length followed by their bytes:
+//
+// "RCSX" version:u8 isa_name isa_hash:u64 data_size:u8 endianness:u8 entry:u32
+// segment_count:u32 { address:u32 size:u32 permissions:u8 contents }
+// symbol_count:u32 { name address:u32 }
+// has_debug:u8 [debug]
+//
+// A segment's size may exceed its contents, the rest is zero filled like bss. Permissions use the page
+// table bits PAGE_READ, PAGE_WRITE and PAGE_EXECUTE and are advisory: the MMU walks page tables in guest
+// memory, so CPU::load_executable never enforces them and a kernel that maps the image applies them.
+
+const MAGIC: &[u8; 4] = b"RCSX";
+const VERSION: u8 = 1;
+const SEGMENT_PERMISSIONS: u8 = (PAGE_READ | PAGE_WRITE | PAGE_EXECUTE) as u8;
+
+// Identifies an instruction set by its microcode, see isa_hash
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IsaId {
+    // Informational, e.g. the isa line of a machine description
+    pub name: String,
+    pub hash: u64,
+    pub data_size: u8,
+    pub endianness: Endianness,
+}
+
+impl IsaId {
+    pub fn of(name: &str, instruction_set: &HashMap<u8, Instruction>, data_size: u8, endianness: Endianness) -> Self {
+        IsaId { name: name.to_string(), hash: isa_hash(instruction_set), data_size, endianness }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoadSegment {
+    pub address: usize,
+    // Bytes the segment takes in memory, at least contents.len()
+    pub size: usize,
+    // PAGE_READ, PAGE_WRITE and PAGE_EXECUTE bits
+    pub permissions: u8,
+    pub contents: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Executable {
+    pub isa: IsaId,
+    pub entry: usize,
+    pub segments: Vec<LoadSegment>,
+    pub symbols: Vec<(String, usize)>,
+    // Free form, such as the linker map
+    pub debug: Option<Vec<u8>>,
+}
+
+// FNV-1a over each instruction's opcode, privilege, operands and micro-ops in opcode order, encoded
+// by isa_bytes. Stable across runs and builds of the simulator, unlike std's hasher or Debug output
+pub fn isa_hash(instruction_set: &HashMap<u8, Instruction>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in isa_bytes(instruction_set) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// Per instruction: opcode:u8 privileged:u8 data_size:u8 operand_count:u32 { operand:u8 }
+// op_count:u32 { tag:u8 fields }. The tags are fixed here so reordering or renaming the enums keeps
+// the hash, adding a variant needs a new tag
+fn isa_bytes(instruction_set: &HashMap<u8, Instruction>) -> Vec<u8> {
+    let mut opcodes: Vec<&u8> = instruction_set.keys().collect();
+    opcodes.sort();
+    let mut out = Vec::new();
+    for opcode in opcodes {
+        let instruction = &instruction_set[opcode];
+        out.extend([*opcode, instruction.privileged as u8, instruction.data_size]);
+        put_length(&mut out, instruction.operands.len());
+        out.extend(instruction.operands.iter().map(operand_tag));
+        put_length(&mut out, instruction.sub_instructions.len());
+        for op in &instruction.sub_instructions {
+            put_sub_instruction(&mut out, op);
+        }
+    }
+    out
+}
+
+// Lengths in isa_bytes only feed the hash, so one too large for a u32 saturates instead of failing
+fn put_length(out: &mut Vec<u8>, length: usize) {
+    out.extend(u32::try_from(length).unwrap_or(u32::MAX).to_le_bytes());
+}
+
+fn put_length_prefixed(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_length(out, bytes.len());
+    out.extend(bytes);
+}
+
+fn operand_tag(operand: &Operand) -> u8 {
+    match operand {
+        Operand::Reg => 0,
+        Operand::Imm8 => 1,
+        Operand::Imm16 => 2,
+        Operand::Imm => 3,
+        Operand::Addr => 4,
+        Operand::RegOrAddr => 5,
+    }
+}
+
+fn put_sub_instruction(out: &mut Vec<u8>, op: &SubInstructions) {
+    match op {
+        SubInstructions::NoOperation => out.push(0),
+        SubInstructions::Halt => out.push(1),
+        SubInstructions::LoadImmediate(offset) => out.extend([2, *offset]),
+        SubInstructions::LoadImmediateWide(offset, width, endianness) => out.extend([3, *offset, *width, endianness_to_u8(*endianness)]),
+        SubInstructions::LoadImmediateInternal(value) => out.extend([4, *value]),
+        SubInstructions::LoadFromMemory => out.push(5),
+        SubInstructions::LoadFromRegister(offset) => out.extend([6, *offset]),
+        SubInstructions::LoadFromRegisterInternal(register) => out.extend([7, *register]),
+        SubInstructions::SetMemoryAddress => out.push(8),
+        SubInstructions::StoreToMemory => out.push(9),
+        SubInstructions::StoreToRegister(offset) => out.extend([10, *offset]),
+        SubInstructions::StoreToRegisterInternal(register) => out.extend([11, *register]),
+        SubInstructions::StepProgramMemory(size) => out.extend([12, *size]),
+        SubInstructions::Add => out.push(13),
+        SubInstructions::Sub => out.push(14),
+        SubInstructions::PushToStack => out.push(15),
+        SubInstructions::PopFromStack => out.push(16),
+        SubInstructions::Jump => out.push(17),
+        SubInstructions::Compare => out.push(18),
+        SubInstructions::JumpIfFlag(true_mask, false_mask) => {
+            out.push(19);
+            put_length_prefixed(out, &true_mask.to_bytes_le());
+            put_length_prefixed(out, &false_mask.to_bytes_le());
+        }
+        SubInstructions::JumpIfNotFlag(true_mask, false_mask) => {
+            out.push(20);
+            put_length_prefixed(out, &true_mask.to_bytes_le());
+            put_length_prefixed(out, &false_mask.to_bytes_le());
+        }
+        SubInstructions::SystemCall => out.push(21),
+        SubInstructions::ReturnFromTrap => out.push(22),
+        SubInstructions::CompareAndSwap => out.push(23),
+        SubInstructions::FetchAdd => out.push(24),
+        SubInstructions::Fence => out.push(25),
+        SubInstructions::AddressOffset(base, displacement, width, endianness) => {
+            out.extend([26, *base, *displacement, *width, endianness_to_u8(*endianness)])
+        }
+        SubInstructions::AddressIndexed(base, index, scale) => out.extend([27, *base, *index, *scale]),
+        SubInstructions::AddressPcRelative(displacement, width, endianness) => out.extend([28, *displacement, *width, endianness_to_u8(*endianness)]),
+    }
+}
+
+impl Executable {
+    // Code is readable and executable, data and bss readable and writable. Symbols are kept, the map
+    // goes into the debug section
+    pub fn from_linked(image: &LinkedImage, isa: IsaId) -> Result<Self, String> {
+        if image.endianness != isa.endianness {
+            return Err(format!("Image is {:?} endian but the ISA is {:?} endian", image.endianness, isa.endianness));
+        }
+        let segments = image
+            .segments
+            .iter()
+            .map(|segment| {
+                let (permissions, contents): (usize, _) = match segment.section {
+                    SectionKind::Code => (PAGE_READ | PAGE_EXECUTE, segment.bytes.clone()),
+                    SectionKind::Data => (PAGE_READ | PAGE_WRITE, segment.bytes.clone()),
+                    SectionKind::Bss => (PAGE_READ | PAGE_WRITE, Vec::new()),
+                };
+                LoadSegment { address: segment.address, size: segment.bytes.len(), permissions: permissions as u8, contents }
+            })
+            .collect();
+        let symbols = image.symbols.iter().map(|symbol| (symbol.name.clone(), symbol.address)).collect();
+        let executable = Executable { isa, entry: image.entry, segments, symbols, debug: Some(image.map().into_bytes()) };
+        executable.check()?;
+        Ok(executable)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<usize> {
+        self.symbols.iter().find(|(symbol, _)| symbol == name).map(|(_, address)| *address)
+    }
+
+    // Segments must hold their contents, must not overlap and only use read, write and execute permissions
+    pub fn check(&self) -> Result<(), String> {
+        for (index, segment) in self.segments.iter().enumerate() {
+            if segment.contents.len() > segment.size {
+                return Err(format!("Segment at {:#x} has {} bytes of contents but a size of {}", segment.address, segment.contents.len(), segment.size));
+            }
+            if segment.permissions & !SEGMENT_PERMISSIONS != 0 {
+                return Err(format!("Segment at {:#x} has unknown permission bits {:#06b}", segment.address, segment.permissions & !SEGMENT_PERMISSIONS));
+            }
+            let end = segment.address.checked_add(segment.size).ok_or_else(|| format!("Segment at {:#x} of {} bytes ends past the address space", segment.address, segment.size))?;
+            if let Some(other) = self.segments[index + 1..].iter().find(|other| other.address < end && segment.address < other.address + other.size) {
+                return Err(format!("Segments at {:#x} and {:#x} overlap", segment.address, other.address));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        put_bytes(&mut out, self.isa.name.as_bytes(), "ISA name")?;
+        out.extend(self.isa.hash.to_le_bytes());
+        out.push(self.isa.data_size);
+        out.push(endianness_to_u8(self.isa.endianness));
+        put_u32(&mut out, self.entry, "Entry point")?;
+        put_u32(&mut out, self.segments.len(), "Segment count")?;
+        for segment in &self.segments {
+            put_u32(&mut out, segment.address, "Segment address")?;
+            put_u32(&mut out, segment.size, "Segment size")?;
+            out.push(segment.permissions);
+            put_bytes(&mut out, &segment.contents, "Segment contents")?;
+        }
+        put_u32(&mut out, self.symbols.len(), "Symbol count")?;
+        for (name, address) in &self.symbols {
+            put_bytes(&mut out, name.as_bytes(), "Symbol name")?;
+            put_u32(&mut out, *address, &format!("Address of symbol '{}'", name))?;
+        }
+        match &self.debug {
+            Some(debug) => {
+                out.push(1);
+                put_bytes(&mut out, debug, "Debug section")?;
+            }
+            None => out.push(0),
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = ByteReader::new(bytes, "Executable");
+        if reader.take(4)? != MAGIC {
+            return Err("Not an executable".to_string());
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("Executable version {} is not supported", version));
+        }
+        let name = reader.string()?;
+        let hash = reader.take(8)?;
+        let hash = u64::from_le_bytes(hash.try_into().expect("Eight bytes were taken"));
+        let data_size = reader.u8()?;
+        let endianness = endianness_from_u8(reader.u8()?)?;
+        let isa = IsaId { name, hash, data_size, endianness };
+        let entry = reader.u32()?;
+        let mut segments = Vec::new();
+        for _ in 0..reader.u32()? {
+            let address = reader.u32()?;
+            let size = reader.u32()?;
+            let permissions = reader.u8()?;
+            let contents = reader.bytes()?.to_vec();
+            segments.push(LoadSegment { address, size, permissions, contents });
+        }
+        let mut symbols = Vec::new();
+        for _ in 0..reader.u32()? {
+            symbols.push((reader.string()?, reader.u32()?));
+        }
+        let debug = match reader.u8()? {
+            0 => None,
+            _ => Some(reader.bytes()?.to_vec()),
+        };
+        reader.finish()?;
+        let executable = Executable { isa, entry, segments, symbols, debug };
+        executable.check()?;
+        Ok(executable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::linker::Linker;
+    use crate::mmu::PAGE_PRESENT;
+    use crate::writers::{InstructionSetWriter, ProgramWriter};
+    use crate::InstructionSet;
+    use num_bigint::BigUint;
+
+    fn executable(machine: &mut TestMachine) -> Executable {
+        let reg_0 = machine.reg("reg_0") as u64;
+        let mut program = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+        program
+            .add_address_instruction(InstructionSet::LoadFromMemory, 0, "value", &[reg_0])
+            .add_address_instruction(InstructionSet::StoreToMemory, 1, "copy", &[reg_0])
+            .add_instruction(InstructionSet::Halt, &[])
+            .data("value", &[7])
+            .reserve("copy", 1);
+        let object = program.try_build_object("main").unwrap();
+        let image = Linker::new().code_at(0x20).add(object).link().unwrap();
+        Executable::from_linked(&image, machine.cpu.isa_id("default")).unwrap()
+    }
+
+    #[test]
+    fn executables_round_trip_and_load() {
+        let mut machine = TestMachine::default();
+        let executable = executable(&mut machine);
+        assert_eq!(executable.segments.iter().map(|segment| segment.permissions as usize).collect::<Vec<_>>(), vec![
+            PAGE_READ | PAGE_EXECUTE,
+            PAGE_READ | PAGE_WRITE,
+            PAGE_READ | PAGE_WRITE
+        ]);
+        let bytes = executable.to_bytes().unwrap();
+        assert_eq!(Executable::from_bytes(&bytes), Ok(executable.clone()));
+        assert!(Executable::from_bytes(&bytes[..10]).unwrap_err().contains("truncated"));
+
+        // bss is cleared
+        machine.load(0x28, &[0xff]);
+        machine.cpu.load_executable_bytes(&bytes).unwrap();
+        machine.expect().register("program_counter", 0x20).memory(0x28, &[0]).check();
+        machine.run(200);
+        machine.expect().halted(true).register("reg_0", 7).memory(0x27, &[7, 7]).check();
+    }
+
+    #[test]
+    fn loading_checks_the_instruction_set() {
+        let mut machine = TestMachine::default();
+        let executable = executable(&mut machine);
+        let mut other = TestMachine::default();
+        other.cpu.instruction_set.remove(&(InstructionSet::Fence as u8));
+        assert_ne!(other.cpu.isa_id("default").hash, executable.isa.hash);
+        assert!(other.cpu.load_executable(&executable).unwrap_err().contains("ISA"));
+        let mut small = TestMachine::new(0x24);
+        assert!(small.cpu.load_executable(&executable).unwrap_err().contains("does not fit"));
+    }
+
+    #[test]
+    fn values_too_large_for_the_format_are_errors() {
+        let mut machine = TestMachine::default();
+        let executable = executable(&mut machine);
+        let mut big = executable.clone();
+        big.entry = u32::MAX as usize + 1;
+        assert_eq!(big.to_bytes().unwrap_err(), "Entry point 4294967296 does not fit in a 32 bit field");
+        let mut big = executable.clone();
+        big.segments[2].address = u32::MAX as usize + 1;
+        assert_eq!(big.to_bytes().unwrap_err(), "Segment address 4294967296 does not fit in a 32 bit field");
+        let mut big = executable.clone();
+        big.segments[2].size = u32::MAX as usize + 1;
+        assert_eq!(big.to_bytes().unwrap_err(), "Segment size 4294967296 does not fit in a 32 bit field");
+        let mut big = executable;
+        big.segments[2].address = usize::MAX;
+        assert!(big.check().unwrap_err().contains("ends past the address space"));
+    }
+
+    #[test]
+    fn segments_only_carry_page_permissions() {
+        let mut machine = TestMachine::default();
+        let mut executable = executable(&mut machine);
+        executable.segments[0].permissions |= PAGE_PRESENT as u8;
+        assert_eq!(executable.check().unwrap_err(), "Segment at 0x20 has unknown permission bits 0b0001");
+        let mut bytes = executable.to_bytes().unwrap();
+        assert!(machine.cpu.load_executable_bytes(&bytes).unwrap_err().contains("permission"));
+        executable.segments[0].permissions &= !(PAGE_PRESENT as u8);
+        bytes = executable.to_bytes().unwrap();
+        machine.cpu.load_executable_bytes(&bytes).unwrap();
+    }
+
+    fn branch_set(mask: u64) -> HashMap<u8, Instruction> {
+        let mut writer = InstructionSetWriter::new(2);
+        writer.add_instruction(InstructionSet::Halt, &[]).add_sub_instruction(SubInstructions::Halt);
+        writer
+            .add_instruction(InstructionSet::JumpEqual, &[Operand::Reg, Operand::Imm])
+            .add_sub_instruction(SubInstructions::LoadImmediateWide(2, 2, Endianness::Little))
+            .add_sub_instruction(SubInstructions::JumpIfFlag(BigUint::from(mask), BigUint::from(0u8)));
+        writer.build()
+    }
+
+    #[test]
+    fn isa_hash_is_pinned_to_the_microcode() {
+        // A changed value here means every executable built so far stops loading
+        assert_eq!(isa_hash(&branch_set(1)), 0x6fa7_6107_2b92_adb1);
+        assert_ne!(isa_hash(&branch_set(1)), isa_hash(&branch_set(2)));
+    }
+}
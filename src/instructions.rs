@@ -1,66 +1,72 @@
-use crate::computer::{flag_invert, SubInstructions, FLAG_NONE, ZERO_FLAG, GREATER_FLAG};
+use std::collections::HashMap;
+
+use crate::computer::{Endianness, Instruction, Operand, RegisterRole, Registers, SubInstructions, FLAG_NONE, ZERO_FLAG, GREATER_FLAG};
 use crate::writers::InstructionSetWriter;
 use crate::InstructionSet;
 
+// #Reg/Addr #Reg (A) #Imm (B)
+const JUMP_IMMEDIATE_OPERANDS: &[Operand] = &[Operand::RegOrAddr, Operand::Reg, Operand::Imm];
+// #Reg/Addr #Reg (A) #Reg (B)
+const JUMP_REGISTER_OPERANDS: &[Operand] = &[Operand::RegOrAddr, Operand::Reg, Operand::Reg];
+
 
+// Imm and Addr operands are cpu_data_size bytes wide, so offsets after them are shifted by data
+pub fn add_instructions(instruction_set_writer: &mut InstructionSetWriter, mut ref_reg: impl FnMut(RegisterRole) -> u8, cpu_data_size: u8, endianness: Endianness) {
 
-pub fn add_instructions(instruction_set_writer: &mut InstructionSetWriter, mut ref_reg: impl FnMut(&str) -> u8, cpu_data_size: u8) {
+    let data = cpu_data_size;
+    let endian = endianness;
 
-    let reg_a = ref_reg("reg_a");
-    let reg_b = ref_reg("reg_b");
-    let reg_c = ref_reg("reg_c");
-    let i_temp_0 = ref_reg("instruction_temp_0");
-    let i_temp_1 = ref_reg("instruction_temp_1");
-    let i_temp_2 = ref_reg("instruction_temp_2");
-    let i_temp_3 = ref_reg("instruction_temp_3");
-    let program_counter = ref_reg("program_counter");
-    let stack_pointer = ref_reg("stack_pointer");
-    let base_pointer = ref_reg("base_pointer");
-    let flags = ref_reg("flags");
+    let reg_a = ref_reg(RegisterRole::OperandA);
+    let reg_b = ref_reg(RegisterRole::OperandB);
+    let reg_c = ref_reg(RegisterRole::Scratch);
+    let program_counter = ref_reg(RegisterRole::ProgramCounter);
+    let base_pointer = ref_reg(RegisterRole::BasePointer);
+    let flags = ref_reg(RegisterRole::Flags);
 
-    instruction_set_writer.add_instruction(InstructionSet::NoOperation, 0);
+    instruction_set_writer.add_instruction(InstructionSet::NoOperation, &[]);
 
-    instruction_set_writer.add_instruction(InstructionSet::Halt, 0)
+    instruction_set_writer.add_instruction(InstructionSet::Halt, &[])
+        .privileged()
         .add_sub_instruction(SubInstructions::Halt);
 
-    instruction_set_writer.add_instruction(InstructionSet::LoadFromMemory, 2)
-        .add_sub_instruction(SubInstructions::LoadImmediate(1))
+    instruction_set_writer.add_instruction(InstructionSet::LoadFromMemory, &[Operand::Addr, Operand::Reg])
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(1, data, endian))
         .add_sub_instruction(SubInstructions::SetMemoryAddress)
         .add_sub_instruction(SubInstructions::LoadFromMemory)
-        .add_sub_instruction(SubInstructions::StoreToRegister(2))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(2));
+        .add_sub_instruction(SubInstructions::StoreToRegister(1 + data))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(1 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::StoreToMemory, 2)
-        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
-        .add_sub_instruction(SubInstructions::LoadImmediate(2))
+    instruction_set_writer.add_instruction(InstructionSet::StoreToMemory, &[Operand::Reg, Operand::Addr])
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(2, data, endian))
         .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::StoreToMemory)
-        .add_sub_instruction(SubInstructions::StepProgramMemory(2));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(1 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::LoadFromMemoryReg, 2)
+    instruction_set_writer.add_instruction(InstructionSet::LoadFromMemoryReg, &[Operand::RegOrAddr, Operand::Reg])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::SetMemoryAddress)
         .add_sub_instruction(SubInstructions::LoadFromMemory)
         .add_sub_instruction(SubInstructions::StoreToRegister(2))
         .add_sub_instruction(SubInstructions::StepProgramMemory(2));
 
-    instruction_set_writer.add_instruction(InstructionSet::StoreToMemoryReg, 2)
+    instruction_set_writer.add_instruction(InstructionSet::StoreToMemoryReg, &[Operand::Reg, Operand::RegOrAddr])
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::SetMemoryAddress)
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::StoreToMemory)
         .add_sub_instruction(SubInstructions::StepProgramMemory(2));
 
-    instruction_set_writer.add_instruction(InstructionSet::AddImmediate, 2)
+    instruction_set_writer.add_instruction(InstructionSet::AddImmediate, &[Operand::Reg, Operand::Imm])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(2))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(2, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Add)
         .add_sub_instruction(SubInstructions::StoreToRegister(1))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(2));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(1 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::AddReg, 2)
+    instruction_set_writer.add_instruction(InstructionSet::AddReg, &[Operand::Reg, Operand::Reg])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
@@ -69,16 +75,16 @@ pub fn add_instructions(instruction_set_writer: &mut InstructionSetWriter, mut r
         .add_sub_instruction(SubInstructions::StoreToRegister(1))
         .add_sub_instruction(SubInstructions::StepProgramMemory(2));
 
-    instruction_set_writer.add_instruction(InstructionSet::SubImmediate, 2)
+    instruction_set_writer.add_instruction(InstructionSet::SubImmediate, &[Operand::Reg, Operand::Imm])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(2))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(2, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Sub)
         .add_sub_instruction(SubInstructions::StoreToRegister(1))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(2));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(1 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::SubReg, 2)
+    instruction_set_writer.add_instruction(InstructionSet::SubReg, &[Operand::Reg, Operand::Reg])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
@@ -87,32 +93,32 @@ pub fn add_instructions(instruction_set_writer: &mut InstructionSetWriter, mut r
         .add_sub_instruction(SubInstructions::StoreToRegister(1))
         .add_sub_instruction(SubInstructions::StepProgramMemory(2));
 
-    instruction_set_writer.add_instruction(InstructionSet::LoadImmediate, 2)
-        .add_sub_instruction(SubInstructions::LoadImmediate(2))
+    instruction_set_writer.add_instruction(InstructionSet::LoadImmediate, &[Operand::Reg, Operand::Imm])
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(2, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegister(1))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(2));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(1 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::MoveRegister, 2)
+    instruction_set_writer.add_instruction(InstructionSet::MoveRegister, &[Operand::Reg, Operand::Reg])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::StoreToRegister(2))
         .add_sub_instruction(SubInstructions::StepProgramMemory(2));
 
-    instruction_set_writer.add_instruction(InstructionSet::PushImmediate, 1)
-        .add_sub_instruction(SubInstructions::LoadImmediate(1))
+    instruction_set_writer.add_instruction(InstructionSet::PushImmediate, &[Operand::Imm])
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(1, data, endian))
         .add_sub_instruction(SubInstructions::PushToStack)
-        .add_sub_instruction(SubInstructions::StepProgramMemory(1));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(data));
 
-    instruction_set_writer.add_instruction(InstructionSet::PushReg, 1)
+    instruction_set_writer.add_instruction(InstructionSet::PushReg, &[Operand::Reg])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::PushToStack)
         .add_sub_instruction(SubInstructions::StepProgramMemory(1));
 
-    instruction_set_writer.add_instruction(InstructionSet::PopReg, 1)
+    instruction_set_writer.add_instruction(InstructionSet::PopReg, &[Operand::Reg])
         .add_sub_instruction(SubInstructions::PopFromStack)
         .add_sub_instruction(SubInstructions::StoreToRegister(1))
         .add_sub_instruction(SubInstructions::StepProgramMemory(1));
 
-    instruction_set_writer.add_instruction(InstructionSet::Jump, 1)
+    instruction_set_writer.add_instruction(InstructionSet::Jump, &[Operand::Addr])
         // Start of address storing
         // saving base_pointer
         .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(base_pointer))
@@ -120,27 +126,44 @@ pub fn add_instructions(instruction_set_writer: &mut InstructionSetWriter, mut r
         // saving flags
         .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(flags))
         .add_sub_instruction(SubInstructions::PushToStack)
-        // calulating program counter at end of current instruction
+        // calulating program counter at start of next instruction (opcode + address)
         .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(program_counter))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediateInternal(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateInternal(1 + data))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Add)
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_c))
         // Return flags to saved state
         .add_sub_instruction(SubInstructions::PopFromStack)
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(flags))
         // Storing calculated return address
-        .add_sub_instruction(SubInstructions::LoadFromRegister(reg_a))
+        .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(reg_c))
         .add_sub_instruction(SubInstructions::PushToStack)
         // END of address storing
-        .add_sub_instruction(SubInstructions::LoadImmediate(1))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(1, data, endian))
         .add_sub_instruction(SubInstructions::Jump);
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpReg, 1)
-        // TODO: Maybe make use of the stack.
+    instruction_set_writer.add_instruction(InstructionSet::JumpReg, &[Operand::RegOrAddr])
         // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
+        // saving base_pointer
+        .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(base_pointer))
+        .add_sub_instruction(SubInstructions::PushToStack)
+        // saving flags
+        .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(flags))
+        .add_sub_instruction(SubInstructions::PushToStack)
+        // calulating program counter at start of next instruction (opcode + register)
+        .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(program_counter))
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
+        .add_sub_instruction(SubInstructions::LoadImmediateInternal(2))
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
+        .add_sub_instruction(SubInstructions::Add)
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_c))
+        // Return flags to saved state
+        .add_sub_instruction(SubInstructions::PopFromStack)
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(flags))
+        // Storing calculated return address
+        .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(reg_c))
+        .add_sub_instruction(SubInstructions::PushToStack)
         // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::Jump);
@@ -155,209 +178,976 @@ pub fn add_instructions(instruction_set_writer: &mut InstructionSetWriter, mut r
     let less_mask_false         = ZERO_FLAG.clone() | GREATER_FLAG.clone();
     let less_equal_mask_true    = FLAG_NONE.clone();
     let less_equal_mask_false   = GREATER_FLAG.clone();
-    let great_equal_mask_true   = less_equal_mask_true.clone();
-    let great_equal_mask_false  = less_equal_mask_false.clone();
+    // Greater or equal is the inverse of less than, so it is used with JumpIfNotFlag
+    let great_equal_mask_true   = less_mask_true.clone();
+    let great_equal_mask_false  = less_mask_false.clone();
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpEqual, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpEqual, JUMP_IMMEDIATE_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(3, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(equal_mask_true.clone(), equal_mask_false.clone()))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(3));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpEqualReg, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpEqualReg, JUMP_REGISTER_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(3))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(equal_mask_true.clone(), equal_mask_false.clone()))
         .add_sub_instruction(SubInstructions::StepProgramMemory(3));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpNotEqual, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpNotEqual, JUMP_IMMEDIATE_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(3, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(not_equal_mask_true.clone(), not_equal_mask_false.clone()))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(3));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpNotEqualReg, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpNotEqualReg, JUMP_REGISTER_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(3))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(not_equal_mask_true.clone(), not_equal_mask_false.clone()))
         .add_sub_instruction(SubInstructions::StepProgramMemory(3));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterThan, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterThan, JUMP_IMMEDIATE_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(3, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(greater_mask_true.clone(), greater_mask_false.clone()))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(3));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterThanReg, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterThanReg, JUMP_REGISTER_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(3))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(greater_mask_true.clone(), greater_mask_false.clone()))
         .add_sub_instruction(SubInstructions::StepProgramMemory(3));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpLessThan, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpLessThan, JUMP_IMMEDIATE_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(3, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(less_mask_true.clone(), less_mask_false.clone()))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(3));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpLessThanReg, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpLessThanReg, JUMP_REGISTER_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(3))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(less_mask_true.clone(), less_mask_false.clone()))
         .add_sub_instruction(SubInstructions::StepProgramMemory(3));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpLessEqual, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpLessEqual, JUMP_IMMEDIATE_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(3, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(less_equal_mask_true.clone(), less_equal_mask_false.clone()))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(3));
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpLessEqualReg, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpLessEqualReg, JUMP_REGISTER_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(3))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
         .add_sub_instruction(SubInstructions::JumpIfFlag(less_equal_mask_true.clone(), less_equal_mask_false.clone()))
         .add_sub_instruction(SubInstructions::StepProgramMemory(3));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterEqual, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterEqual, JUMP_IMMEDIATE_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediate(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateWide(3, data, endian))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // TODO: Maybe make use of the stack.
-        // Start of address storing
-        .add_sub_instruction(SubInstructions::LoadFromRegister(program_counter))
-        // .add_sub_instruction(SubInstructions::StoreToRegisterInternal(return_address))
-        // END of address storing
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
-        .add_sub_instruction(SubInstructions::JumpIfFlag(great_equal_mask_true.clone(), great_equal_mask_false.clone()))
-        .add_sub_instruction(SubInstructions::StepProgramMemory(3));
+        .add_sub_instruction(SubInstructions::JumpIfNotFlag(great_equal_mask_true.clone(), great_equal_mask_false.clone()))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
 
-    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterEqualReg, 3)
+    instruction_set_writer.add_instruction(InstructionSet::JumpGreaterEqualReg, JUMP_REGISTER_OPERANDS)
         .add_sub_instruction(SubInstructions::LoadFromRegister(2))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
         .add_sub_instruction(SubInstructions::LoadFromRegister(3))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Compare)
-        // Start of address storing
-        // saving base_pointer
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::JumpIfNotFlag(great_equal_mask_true.clone(), great_equal_mask_false.clone()))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(3));
+
+    // Relative branches compute their target with AddressPcRelative where the jumps above load it
+    instruction_set_writer.add_instruction(InstructionSet::Branch, &[Operand::Imm])
+        .add_sub_instruction(SubInstructions::AddressPcRelative(1, data, endian))
+        .add_sub_instruction(SubInstructions::Jump);
+
+    for (opcode, mask_true, mask_false, inverted) in [
+        (InstructionSet::BranchEqual, &equal_mask_true, &equal_mask_false, false),
+        (InstructionSet::BranchNotEqual, &not_equal_mask_true, &not_equal_mask_false, false),
+        (InstructionSet::BranchGreaterThan, &greater_mask_true, &greater_mask_false, false),
+        (InstructionSet::BranchLessThan, &less_mask_true, &less_mask_false, false),
+        (InstructionSet::BranchLessEqual, &less_equal_mask_true, &less_equal_mask_false, false),
+        (InstructionSet::BranchGreaterEqual, &great_equal_mask_true, &great_equal_mask_false, true),
+    ] {
+        let jump = match inverted {
+            false => SubInstructions::JumpIfFlag(mask_true.clone(), mask_false.clone()),
+            true => SubInstructions::JumpIfNotFlag(mask_true.clone(), mask_false.clone()),
+        };
+        instruction_set_writer.add_instruction(opcode, &[Operand::Imm, Operand::Reg, Operand::Imm])
+            .add_sub_instruction(SubInstructions::LoadFromRegister(1 + data))
+            .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
+            .add_sub_instruction(SubInstructions::LoadImmediateWide(2 + data, data, endian))
+            .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
+            .add_sub_instruction(SubInstructions::Compare)
+            .add_sub_instruction(SubInstructions::AddressPcRelative(1, data, endian))
+            .add_sub_instruction(jump)
+            .add_sub_instruction(SubInstructions::StepProgramMemory(1 + 2 * data));
+    }
+
+    instruction_set_writer.add_instruction(InstructionSet::CallRelative, &[Operand::Imm])
+        // Start of address storing, as for Jump
         .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(base_pointer))
         .add_sub_instruction(SubInstructions::PushToStack)
-        // saving flags
         .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(flags))
         .add_sub_instruction(SubInstructions::PushToStack)
-        // calulating program counter at end of current instruction
         .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(program_counter))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
-        .add_sub_instruction(SubInstructions::LoadImmediateInternal(3))
+        .add_sub_instruction(SubInstructions::LoadImmediateInternal(1 + data))
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
         .add_sub_instruction(SubInstructions::Add)
-        // Return flags to saved state
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_c))
         .add_sub_instruction(SubInstructions::PopFromStack)
         .add_sub_instruction(SubInstructions::StoreToRegisterInternal(flags))
-        // Storing calculated return address
-        .add_sub_instruction(SubInstructions::LoadFromRegister(reg_a))
+        .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(reg_c))
         .add_sub_instruction(SubInstructions::PushToStack)
         // END of address storing
+        .add_sub_instruction(SubInstructions::AddressPcRelative(1, data, endian))
+        .add_sub_instruction(SubInstructions::Jump);
+
+    instruction_set_writer.add_instruction(InstructionSet::Return, &[])
+        .add_sub_instruction(SubInstructions::PopFromStack) // Get return address
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_c))
+        .add_sub_instruction(SubInstructions::PopFromStack) // Recover saved base_pointer
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(base_pointer))
+        .add_sub_instruction(SubInstructions::LoadFromRegisterInternal(reg_c))
+        .add_sub_instruction(SubInstructions::Jump);
+
+    // Traps to trap_vector in supervisor mode, trap_return is the next instruction
+    instruction_set_writer.add_instruction(InstructionSet::SystemCall, &[])
+        .add_sub_instruction(SubInstructions::SystemCall);
+
+    instruction_set_writer.add_instruction(InstructionSet::ReturnFromTrap, &[])
+        .privileged()
+        .add_sub_instruction(SubInstructions::ReturnFromTrap);
+
+    // Swaps when memory matches B, B always ends up with the old memory value and the zero flag reports success
+    instruction_set_writer.add_instruction(InstructionSet::CompareAndSwap, &[Operand::RegOrAddr, Operand::Reg, Operand::Reg])
         .add_sub_instruction(SubInstructions::LoadFromRegister(1))
-        .add_sub_instruction(SubInstructions::JumpIfFlag(great_equal_mask_true.clone(), great_equal_mask_false.clone()))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(2))
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_a))
+        .add_sub_instruction(SubInstructions::LoadFromRegister(3))
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
+        .add_sub_instruction(SubInstructions::CompareAndSwap)
+        .add_sub_instruction(SubInstructions::StoreToRegister(2))
         .add_sub_instruction(SubInstructions::StepProgramMemory(3));
 
-    instruction_set_writer.add_instruction(InstructionSet::Return, 0)
-        .add_sub_instruction(SubInstructions::PopFromStack) // Get return address
-        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(program_counter))
-        .add_sub_instruction(SubInstructions::PopFromStack)//Recover from saved
-        .add_sub_instruction(SubInstructions::)
+    instruction_set_writer.add_instruction(InstructionSet::FetchAdd, &[Operand::RegOrAddr, Operand::Reg])
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(2))
+        .add_sub_instruction(SubInstructions::StoreToRegisterInternal(reg_b))
+        .add_sub_instruction(SubInstructions::FetchAdd)
+        .add_sub_instruction(SubInstructions::StoreToRegister(2))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2));
+
+    instruction_set_writer.add_instruction(InstructionSet::Fence, &[])
+        .add_sub_instruction(SubInstructions::Fence);
+
+    // The address micro-ops leave the effective address in the accumulator. Pointer updates are stored
+    // back before the access for pre-increment and after it for post-increment, so a load into the
+    // pointer itself keeps the loaded value
+
+    instruction_set_writer.add_instruction(InstructionSet::LoadOffset, &[Operand::Reg, Operand::Imm, Operand::Reg])
+        .add_sub_instruction(SubInstructions::AddressOffset(1, 2, data, endian))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromMemory)
+        .add_sub_instruction(SubInstructions::StoreToRegister(2 + data))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
+
+    instruction_set_writer.add_instruction(InstructionSet::StoreOffset, &[Operand::Reg, Operand::Reg, Operand::Imm])
+        .add_sub_instruction(SubInstructions::AddressOffset(2, 3, data, endian))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::StoreToMemory)
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
+
+    instruction_set_writer.add_instruction(InstructionSet::LoadIndexed, &[Operand::Reg, Operand::Reg, Operand::Imm8, Operand::Reg])
+        .add_sub_instruction(SubInstructions::AddressIndexed(1, 2, 3))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromMemory)
+        .add_sub_instruction(SubInstructions::StoreToRegister(4))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(4));
+
+    instruction_set_writer.add_instruction(InstructionSet::StoreIndexed, &[Operand::Reg, Operand::Reg, Operand::Reg, Operand::Imm8])
+        .add_sub_instruction(SubInstructions::AddressIndexed(2, 3, 4))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::StoreToMemory)
+        .add_sub_instruction(SubInstructions::StepProgramMemory(4));
+
+    instruction_set_writer.add_instruction(InstructionSet::LoadPreIncrement, &[Operand::Reg, Operand::Imm, Operand::Reg])
+        .add_sub_instruction(SubInstructions::AddressOffset(1, 2, data, endian))
+        .add_sub_instruction(SubInstructions::StoreToRegister(1))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromMemory)
+        .add_sub_instruction(SubInstructions::StoreToRegister(2 + data))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
+
+    instruction_set_writer.add_instruction(InstructionSet::LoadPostIncrement, &[Operand::Reg, Operand::Imm, Operand::Reg])
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::AddressOffset(1, 2, data, endian))
+        .add_sub_instruction(SubInstructions::StoreToRegister(1))
+        .add_sub_instruction(SubInstructions::LoadFromMemory)
+        .add_sub_instruction(SubInstructions::StoreToRegister(2 + data))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
+
+    instruction_set_writer.add_instruction(InstructionSet::StorePreIncrement, &[Operand::Reg, Operand::Reg, Operand::Imm])
+        .add_sub_instruction(SubInstructions::AddressOffset(2, 3, data, endian))
+        .add_sub_instruction(SubInstructions::StoreToRegister(2))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::StoreToMemory)
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
+
+    instruction_set_writer.add_instruction(InstructionSet::StorePostIncrement, &[Operand::Reg, Operand::Reg, Operand::Imm])
+        .add_sub_instruction(SubInstructions::LoadFromRegister(2))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::StoreToMemory)
+        .add_sub_instruction(SubInstructions::AddressOffset(2, 3, data, endian))
+        .add_sub_instruction(SubInstructions::StoreToRegister(2))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(2 + data));
+
+    instruction_set_writer.add_instruction(InstructionSet::LoadPcRelative, &[Operand::Imm, Operand::Reg])
+        .add_sub_instruction(SubInstructions::AddressPcRelative(1, data, endian))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromMemory)
+        .add_sub_instruction(SubInstructions::StoreToRegister(1 + data))
+        .add_sub_instruction(SubInstructions::StepProgramMemory(1 + data));
+
+    instruction_set_writer.add_instruction(InstructionSet::StorePcRelative, &[Operand::Reg, Operand::Imm])
+        .add_sub_instruction(SubInstructions::AddressPcRelative(2, data, endian))
+        .add_sub_instruction(SubInstructions::SetMemoryAddress)
+        .add_sub_instruction(SubInstructions::LoadFromRegister(1))
+        .add_sub_instruction(SubInstructions::StoreToMemory)
+        .add_sub_instruction(SubInstructions::StepProgramMemory(1 + data));
+}
+
+// Every InstructionSet opcode built for registers, refused when a role the microcode uses has no
+// register or any instruction fails validation
+pub fn default_instruction_set(registers: &Registers, cpu_data_size: u8, endianness: Endianness) -> Result<HashMap<u8, Instruction>, String> {
+    let mut missing = Vec::new();
+    let mut instruction_set_writer = InstructionSetWriter::new(cpu_data_size);
+    add_instructions(&mut instruction_set_writer, |role| {
+        registers.role(role).unwrap_or_else(|| {
+            missing.push(role.name());
+            0
+        })
+    }, cpu_data_size, endianness);
+    if !missing.is_empty() {
+        return Err(format!("Instruction set needs register role(s): {}", missing.join(", ")));
+    }
+    instruction_set_writer.build_checked(registers).map_err(|diagnostics| {
+        let messages: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect();
+        format!("Invalid instruction set: {}", messages.join("; "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::computer::{GREATER_FLAG, ZERO_FLAG};
+    use crate::computer::{Endianness, Fault, MachineConfig, Privilege, TrapCause};
+    use crate::harness::TestMachine;
+    use crate::writers::ProgramWriter;
+    use crate::InstructionSet;
+    use num_bigint::BigUint;
+    use num_traits::One;
+
+    #[test]
+    fn no_operation_advances_to_next_instruction() {
+        let mut machine = TestMachine::default();
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::NoOperation, &[])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(10);
+        machine.expect().halted(true).register("program_counter", 1).check();
+    }
+
+    #[test]
+    fn halt_stops_the_cpu() {
+        let mut machine = TestMachine::default();
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::Halt, &[]);
+        });
+        let cycles = machine.run(10);
+        assert_eq!(cycles, 2);
+        machine.expect().halted(true).register("program_counter", 0).check();
+    }
+
+    #[test]
+    fn load_immediate_and_move_register() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg_0, 42])
+                .add_instruction(InstructionSet::MoveRegister, &[reg_0, reg_1])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true).register("reg_0", 42).register("reg_1", 42).check();
+    }
+
+    #[test]
+    fn load_and_store_memory_by_address() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        machine.load(40, &[0x5a]);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadFromMemory, &[40, reg_0])
+                .add_instruction(InstructionSet::StoreToMemory, &[reg_0, 41])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true).register("reg_0", 0x5a).memory(40, &[0x5a, 0x5a]).check();
+    }
+
+    #[test]
+    fn load_and_store_memory_by_register() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        let reg_2 = machine.reg("reg_2");
+        machine.load(40, &[0x33]);
+        machine.set_register("reg_0", 40).set_register("reg_2", 44);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadFromMemoryReg, &[reg_0, reg_1])
+                .add_instruction(InstructionSet::StoreToMemoryReg, &[reg_1, reg_2])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true).register("reg_1", 0x33).memory(44, &[0x33]).check();
+    }
+
+    #[test]
+    fn base_offset_and_indexed_addressing() {
+        let mut machine = TestMachine::default();
+        let [reg_0, reg_1, reg_2, reg_3, reg_4] = ["reg_0", "reg_1", "reg_2", "reg_3", "reg_4"].map(|name| machine.reg(name));
+        machine.load(40, &[0x11, 0x22, 0x33, 0x44]);
+        machine.set_register("reg_0", 41).set_register("reg_2", 1);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadOffset, &[reg_0, 2, reg_1])
+                // 0xff is -1
+                .add_instruction(InstructionSet::LoadOffset, &[reg_0, 0xff, reg_3])
+                .add_instruction(InstructionSet::LoadIndexed, &[reg_0, reg_2, 2, reg_4])
+                .add_instruction(InstructionSet::StoreOffset, &[reg_1, reg_0, 9])
+                .add_instruction(InstructionSet::StoreIndexed, &[reg_3, reg_0, reg_2, 10])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(200);
+        machine.expect().halted(true)
+            .register("reg_1", 0x44)
+            .register("reg_3", 0x11)
+            .register("reg_4", 0x44)
+            .register("reg_0", 41)
+            .register("flags", 0)
+            .memory(50, &[0x44, 0x11])
+            .check();
+    }
+
+    #[test]
+    fn pre_and_post_increment_step_the_pointer() {
+        let mut machine = TestMachine::default();
+        let [reg_0, reg_1, reg_2, reg_3] = ["reg_0", "reg_1", "reg_2", "reg_3"].map(|name| machine.reg(name));
+        machine.load(40, &[0x11, 0x22, 0x33]);
+        machine.set_register("reg_0", 40).set_register("reg_1", 50);
+        machine.program(|p| {
+            // Copies two bytes forwards, then the last of them again with the pointers stepped back first
+            p.add_instruction(InstructionSet::LoadPostIncrement, &[reg_0, 1, reg_2])
+                .add_instruction(InstructionSet::StorePostIncrement, &[reg_2, reg_1, 1])
+                .add_instruction(InstructionSet::LoadPostIncrement, &[reg_0, 1, reg_2])
+                .add_instruction(InstructionSet::StorePostIncrement, &[reg_2, reg_1, 1])
+                .add_instruction(InstructionSet::LoadPreIncrement, &[reg_0, 0xff, reg_3])
+                .add_instruction(InstructionSet::StorePreIncrement, &[reg_3, reg_1, 2])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(200);
+        machine.expect().halted(true)
+            .register("reg_0", 41)
+            .register("reg_1", 54)
+            .register("reg_3", 0x22)
+            .memory(50, &[0x11, 0x22, 0x00, 0x00, 0x22])
+            .check();
+    }
+
+    #[test]
+    fn pc_relative_addressing_works_at_any_address() {
+        for start in [0u64, 0x100] {
+            let mut machine = TestMachine::with_data_size(512, 2);
+            let reg_0 = machine.reg("reg_0") as u64;
+            let mut program = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+            program
+                .add_instruction_wide(InstructionSet::LoadPcRelative, &[0x40, reg_0])
+                // -2 from its own opcode, over the first instruction's operand
+                .add_instruction_wide(InstructionSet::StorePcRelative, &[reg_0, 0xfffe])
+                .add_instruction(InstructionSet::Halt, &[]);
+            machine.load(start as usize, &program.build());
+            machine.load(start as usize + 0x40, &[0xbe, 0xef]);
+            machine.set_register("program_counter", start);
+            machine.run(200);
+            machine.expect().halted(true).register("reg_0", 0xbeef).memory(start as usize + 2, &[0xbe, 0xef]).check();
+        }
+    }
+
+    #[test]
+    fn relative_branches_run_at_any_address() {
+        for start in [0, 0x20] {
+            let mut machine = TestMachine::default();
+            let reg_0 = machine.reg("reg_0");
+            let reg_1 = machine.reg("reg_1");
+            let mut program = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+            program
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_0, 3])
+                .label("loop")
+                .add_instruction(InstructionSet::AddImmediate, &[reg_1, 2])
+                .add_instruction(InstructionSet::SubImmediate, &[reg_0, 1])
+                .add_relative_instruction(InstructionSet::BranchGreaterThan, "loop", &[reg_0 as u64, 0])
+                .add_relative_instruction(InstructionSet::Branch, "end", &[])
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_1, 0xff])
+                .label("end")
+                .add_instruction(InstructionSet::Halt, &[]);
+            machine.cpu.load_program(start, &program.build());
+            machine.run(500);
+            machine.expect().halted(true).register("reg_0", 0).register("reg_1", 6).check();
+        }
+    }
+
+    #[test]
+    fn call_relative_returns_after_the_call() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let mut program = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+        program
+            .add_relative_instruction(InstructionSet::CallRelative, "double", &[])
+            .add_instruction(InstructionSet::Halt, &[])
+            .label("double")
+            .add_instruction(InstructionSet::AddReg, &[reg_0, reg_0])
+            .add_instruction(InstructionSet::Return, &[]);
+        machine.set_register("reg_0", 21);
+        machine.cpu.load_program(0x10, &program.build());
+        machine.run(500);
+        machine.expect().halted(true).register("reg_0", 42).register("program_counter", 0x12).check();
+    }
+
+    #[test]
+    fn add_and_sub_immediate() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        machine.set_register("reg_0", 5).set_register("reg_1", 5);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::AddImmediate, &[reg_0, 3])
+                .add_instruction(InstructionSet::SubImmediate, &[reg_1, 3])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true).register("reg_0", 8).register("reg_1", 2).check();
+    }
+
+    #[test]
+    fn sub_below_zero_wraps() {
+        for data_size in [1, 9] {
+            let mut machine = TestMachine::with_data_size(64, data_size);
+            let reg_0 = machine.reg("reg_0");
+            machine.program(|p| {
+                p.add_instruction(InstructionSet::SubImmediate, &[reg_0, 1])
+                    .add_instruction(InstructionSet::Halt, &[]);
+            });
+            machine.run(50);
+            machine.expect().halted(true).fault(None).check();
+            assert_eq!(machine.register("reg_0"), (BigUint::one() << (8 * data_size as usize)) - 1u8);
+        }
+    }
+
+    #[test]
+    fn add_and_sub_register() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        let reg_2 = machine.reg("reg_2");
+        machine.set_register("reg_0", 10).set_register("reg_1", 4).set_register("reg_2", 10);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::AddReg, &[reg_0, reg_1])
+                .add_instruction(InstructionSet::SubReg, &[reg_2, reg_1])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true)
+            .register("reg_0", 14)
+            .register("reg_1", 4)
+            .register("reg_2", 6)
+            .check();
+    }
+
+    #[test]
+    fn push_and_pop_are_last_in_first_out() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        let reg_2 = machine.reg("reg_2");
+        machine.set_register("reg_2", 9);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::PushImmediate, &[1])
+                .add_instruction(InstructionSet::PushReg, &[reg_2])
+                .add_instruction(InstructionSet::PopReg, &[reg_0])
+                .add_instruction(InstructionSet::PopReg, &[reg_1])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true)
+            .register("reg_0", 9)
+            .register("reg_1", 1)
+            .register("stack_pointer", 64)
+            .memory(62, &[0, 0])
+            .check();
+    }
+
+    #[test]
+    fn push_writes_below_stack_pointer() {
+        let mut machine = TestMachine::default();
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::PushImmediate, &[7])
+                .add_instruction(InstructionSet::PushImmediate, &[8])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true).register("stack_pointer", 62).memory(62, &[8, 7]).check();
+    }
+
+    #[test]
+    fn jump_pushes_return_frame() {
+        let mut machine = TestMachine::default();
+        machine.set_register("base_pointer", 9);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::Jump, &[3])
+                .add_instruction(InstructionSet::NoOperation, &[])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true)
+            .register("program_counter", 3)
+            .register("stack_pointer", 62)
+            .memory(62, &[2, 9])
+            .check();
+    }
+
+    #[test]
+    fn jump_and_return() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let base_pointer = machine.reg("base_pointer");
+        machine.set_register("base_pointer", 9);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::Jump, &[3])                      // 0
+                .add_instruction(InstructionSet::Halt, &[])                     // 2
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_0, 7])    // 3
+                .add_instruction(InstructionSet::LoadImmediate, &[base_pointer, 0x22])
+                .add_instruction(InstructionSet::Return, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true)
+            .register("reg_0", 7)
+            .register("base_pointer", 9)
+            .register("program_counter", 2)
+            .register("stack_pointer", 64)
+            .check();
+    }
+
+    #[test]
+    fn jump_reg_and_return() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        machine.set_register("reg_1", 3);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::JumpReg, &[reg_1])               // 0
+                .add_instruction(InstructionSet::Halt, &[])                     // 2
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_0, 7])    // 3
+                .add_instruction(InstructionSet::Return, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true)
+            .register("reg_0", 7)
+            .register("program_counter", 2)
+            .register("stack_pointer", 64)
+            .check();
+    }
+
+    // Runs `opcode target_reg, reg_0, b` with reg_0 = a and reports whether the jump was taken.
+    // Register variants read b from reg_1.
+    fn conditional_jump_taken(opcode: InstructionSet, a: u64, b: u8, register_variant: bool) -> bool {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        let reg_2 = machine.reg("reg_2");
+        machine.set_register("reg_0", a).set_register("reg_1", b as u64).set_register("reg_2", 7);
+        let operand_b = if register_variant { reg_1 } else { b };
+        machine.program(|p| {
+            p.add_instruction(opcode, &[reg_2, reg_0, operand_b])           // 0
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_0, 0xee]) // 4, skipped when taken
+                .add_instruction(InstructionSet::Halt, &[]);                   // 7
+        });
+        machine.run(100);
+        machine.expect().halted(true).register("stack_pointer", 64).check();
+        machine.register("reg_0") != 0xeeu8.into()
+    }
+
+    fn check_condition(immediate: InstructionSet, register: InstructionSet, less: bool, equal: bool, greater: bool) {
+        let name = format!("{:?}", immediate);
+        for (a, b, expected) in [(1, 2, less), (2, 2, equal), (3, 2, greater)] {
+            assert_eq!(conditional_jump_taken(immediate, a, b, false), expected, "{} with {} vs {}", name, a, b);
+        }
+        let name = format!("{:?}", register);
+        for (a, b, expected) in [(1, 2, less), (2, 2, equal), (3, 2, greater)] {
+            assert_eq!(conditional_jump_taken(register, a, b, true), expected, "{} with {} vs {}", name, a, b);
+        }
+    }
+
+    #[test]
+    fn jump_equal() {
+        check_condition(InstructionSet::JumpEqual, InstructionSet::JumpEqualReg, false, true, false);
+    }
+
+    #[test]
+    fn jump_not_equal() {
+        check_condition(InstructionSet::JumpNotEqual, InstructionSet::JumpNotEqualReg, true, false, true);
+    }
+
+    #[test]
+    fn jump_greater_than() {
+        check_condition(InstructionSet::JumpGreaterThan, InstructionSet::JumpGreaterThanReg, false, false, true);
+    }
+
+    #[test]
+    fn jump_less_than() {
+        check_condition(InstructionSet::JumpLessThan, InstructionSet::JumpLessThanReg, true, false, false);
+    }
+
+    #[test]
+    fn jump_less_equal() {
+        check_condition(InstructionSet::JumpLessEqual, InstructionSet::JumpLessEqualReg, true, true, false);
+    }
+
+    #[test]
+    fn jump_greater_equal() {
+        check_condition(InstructionSet::JumpGreaterEqual, InstructionSet::JumpGreaterEqualReg, false, true, true);
+    }
+
+    #[test]
+    fn compare_sets_flags() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_2 = machine.reg("reg_2");
+        machine.set_register("reg_0", 5).set_register("reg_2", 4);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::JumpEqual, &[reg_2, reg_0, 5])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true).flags(&ZERO_FLAG).check();
+
+        let mut machine = TestMachine::default();
+        machine.set_register("reg_0", 6).set_register("reg_2", 4);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::JumpEqual, &[reg_2, reg_0, 5])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true).flags(&GREATER_FLAG).check();
+    }
+
+    #[test]
+    fn immediates_follow_data_size() {
+        let mut machine = TestMachine::with_data_size(512, 2);
+        let reg_0 = machine.reg("reg_0") as u64;
+        let reg_1 = machine.reg("reg_1") as u64;
+        machine.program(|p| {
+            p.add_instruction_wide(InstructionSet::LoadImmediate, &[reg_0, 0x1234])
+                .add_instruction_wide(InstructionSet::LoadImmediate, &[reg_1, 0x00ff])
+                .add_instruction_wide(InstructionSet::AddImmediate, &[reg_1, 0x0001])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(100);
+        machine.expect().halted(true)
+            .register("reg_0", 0x1234)
+            .register("reg_1", 0x0100)
+            .memory(0, &[InstructionSet::LoadImmediate as u8, reg_0 as u8, 0x12, 0x34])
+            .check();
+    }
+
+    #[test]
+    fn addresses_follow_data_size() {
+        let mut machine = TestMachine::with_data_size(512, 2);
+        let reg_0 = machine.reg("reg_0") as u64;
+        machine.load(0x0140, &[0xbe, 0xef]);
+        machine.program(|p| {
+            p.add_instruction_wide(InstructionSet::Jump, &[0x0120]);
+        });
+        let mut subroutine = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+        subroutine
+            .add_instruction_wide(InstructionSet::LoadFromMemory, &[0x0140, reg_0])
+            .add_instruction_wide(InstructionSet::StoreToMemory, &[reg_0, 0x0150])
+            .add_instruction_wide(InstructionSet::PushImmediate, &[0xabcd])
+            .add_instruction(InstructionSet::Halt, &[]);
+        machine.load(0x0120, &subroutine.build());
+        machine.run(200);
+        machine.expect().halted(true)
+            .register("reg_0", 0xbeef)
+            .register("stack_pointer", 512 - 6)
+            // pushed base_pointer, return address and the immediate
+            .memory(512 - 6, &[0xab, 0xcd, 0x00, 0x03, 0x00, 0x00])
+            .memory(0x0150, &[0xbe, 0xef])
+            .check();
+    }
+
+    #[test]
+    fn compares_whole_words() {
+        let mut machine = TestMachine::with_data_size(512, 2);
+        let reg_0 = machine.reg("reg_0") as u64;
+        let reg_2 = machine.reg("reg_2") as u64;
+        machine.set_register("reg_0", 0x0201).set_register("reg_2", 9);
+        machine.program(|p| {
+            // Equal in the low byte only, so the jump must not be taken
+            p.add_instruction_wide(InstructionSet::JumpEqual, &[reg_2, reg_0, 0x0101])  // 0
+                .add_instruction_wide(InstructionSet::LoadImmediate, &[reg_0, 0x00ee])  // 5
+                .add_instruction(InstructionSet::Halt, &[]);                            // 9
+        });
+        machine.run(100);
+        machine.expect().halted(true).register("reg_0", 0x00ee).check();
+    }
+
+    #[test]
+    fn little_endian_machine_stores_low_byte_first() {
+        let config = MachineConfig { data_size: 2, endianness: Endianness::Little, ..MachineConfig::default() };
+        let mut machine = TestMachine::with_config(512, config);
+        let reg_0 = machine.reg("reg_0") as u64;
+        machine.load(0x0140, &[0xef, 0xbe]);
+        machine.program(|p| {
+            p.add_instruction_wide(InstructionSet::Jump, &[0x0120]);
+        });
+        let mut subroutine = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+        subroutine
+            .set_endianness(Endianness::Little)
+            .add_instruction_wide(InstructionSet::LoadFromMemory, &[0x0140, reg_0])
+            .add_instruction_wide(InstructionSet::AddImmediate, &[reg_0, 0x0101])
+            .add_instruction_wide(InstructionSet::StoreToMemory, &[reg_0, 0x0150])
+            .add_instruction_wide(InstructionSet::PushImmediate, &[0xabcd])
+            .add_instruction(InstructionSet::Halt, &[]);
+        let subroutine = subroutine.build();
+        assert_eq!(&subroutine[..3], &[InstructionSet::LoadFromMemory as u8, 0x40, 0x01]);
+        machine.load(0x0120, &subroutine);
+        machine.run(200);
+        machine.expect().halted(true)
+            .register("reg_0", 0xbff0)
+            .register("stack_pointer", 512 - 6)
+            .register("program_counter", 0x0120 + 15)
+            .memory(512 - 6, &[0xcd, 0xab, 0x03, 0x00, 0x00, 0x00])
+            .memory(0x0150, &[0xf0, 0xbf])
+            .check();
+    }
+
+    // Runs the program in user mode with the supervisor's handler at 0x20 and its stack at 0x30
+    fn user_machine() -> TestMachine {
+        let mut machine = TestMachine::default();
+        machine.set_register("trap_vector", 0x20).set_register("supervisor_stack_pointer", 0x30);
+        machine.cpu.privilege = Privilege::User;
+        machine
+    }
+
+    #[test]
+    fn system_call_enters_supervisor_at_trap_vector() {
+        let mut machine = user_machine();
+        let reg_0 = machine.reg("reg_0");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg_0, 5])  // 0
+                .add_instruction(InstructionSet::SystemCall, &[]);          // 3
+        });
+        let mut handler = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+        handler
+            .add_instruction(InstructionSet::PushImmediate, &[9])
+            .add_instruction(InstructionSet::Halt, &[]);
+        machine.load(0x20, &handler.build());
+        machine.run(50);
+        machine.expect()
+            .halted(true)
+            .fault(None)
+            .privilege(Privilege::Supervisor)
+            .register("trap_cause", TrapCause::SystemCall as u64)
+            .register("trap_return", 4)
+            // The handler pushed onto the supervisor's stack, the user's is kept aside
+            .register("stack_pointer", 0x2f)
+            .register("supervisor_stack_pointer", 64)
+            .memory(0x2f, &[9])
+            .check();
+    }
+
+    #[test]
+    fn return_from_trap_resumes_user_mode() {
+        let mut machine = user_machine();
+        let reg_0 = machine.reg("reg_0");
+        let reg_2 = machine.reg("reg_2");
+        let trap_cause = machine.reg("trap_cause");
+        machine.set_register("reg_2", 0x30);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::SystemCall, &[])              // 0
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_0, 5]) // 1
+                .add_instruction(InstructionSet::Halt, &[]);                // 4
+        });
+        // The handler returns from system calls and halts on a privilege violation
+        let mut handler = ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers);
+        handler
+            .add_instruction(InstructionSet::JumpEqual, &[reg_2, trap_cause, TrapCause::PrivilegeViolation as u8])
+            .add_instruction(InstructionSet::ReturnFromTrap, &[]);
+        machine.load(0x20, &handler.build());
+        machine.load(0x30, &[InstructionSet::Halt as u8]);
+        machine.run(100);
+        machine.expect()
+            .halted(true)
+            .fault(None)
+            .privilege(Privilege::Supervisor)
+            .register("reg_0", 5)
+            .register("trap_cause", TrapCause::PrivilegeViolation as u64)
+            .register("trap_return", 4)
+            .register("fault_address", 4)
+            .check();
+    }
+
+    #[test]
+    fn faults_undo_the_faulting_instruction() {
+        // The first push of the jump's frame lands on its own opcode, the second one wraps off the end of memory
+        let mut machine = user_machine();
+        machine.set_register("stack_pointer", 1);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::Jump, &[0x10]);
+        });
+        machine.load(0x20, &[InstructionSet::Halt as u8]);
+        machine.run(100);
+        machine.expect()
+            .halted(true)
+            .fault(None)
+            .register("trap_cause", TrapCause::BusError as u64)
+            .register("trap_return", 0)
+            // The user's stack pointer as it was before the jump, now kept aside
+            .register("supervisor_stack_pointer", 1)
+            .memory(0, &[InstructionSet::Jump as u8, 0x10])
+            .check();
+    }
+
+    #[test]
+    fn faults_that_stop_the_cpu_leave_the_instruction_half_done() {
+        // Nothing resumes the jump, so the first push of its frame is left over its opcode
+        let mut machine = TestMachine::default();
+        machine.set_register("stack_pointer", 1);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::Jump, &[0x10]);
+        });
+        machine.run(100);
+        machine.expect()
+            .fault(Some(Fault::BusError { address: 255 }))
+            .register("stack_pointer", 0)
+            .memory(0, &[0, 0x10])
+            .check();
+    }
+
+    #[test]
+    fn system_calls_in_supervisor_mode_fault() {
+        let mut machine = TestMachine::default();
+        machine.set_register("trap_vector", 0x20).set_register("trap_return", 7);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::SystemCall, &[]);
+        });
+        machine.run(50);
+        machine.expect()
+            .fault(Some(Fault::PrivilegeViolation { address: 0 }))
+            .privilege(Privilege::Supervisor)
+            .register("trap_return", 7)
+            .register("program_counter", 0)
+            .check();
+    }
 
+    #[test]
+    fn user_mode_reads_its_core_id_but_not_trap_registers() {
+        let mut machine = TestMachine::default();
+        machine.set_register("core_id", 3);
+        machine.cpu.privilege = Privilege::User;
+        let core_id = machine.reg("core_id");
+        let trap_vector = machine.reg("trap_vector");
+        let reg_0 = machine.reg("reg_0");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::MoveRegister, &[core_id, reg_0])  // 0
+                .add_instruction(InstructionSet::MoveRegister, &[trap_vector, reg_0]); // 3
+        });
+        machine.run(50);
+        machine.expect()
+            .fault(Some(Fault::PrivilegeViolation { address: 3 }))
+            .register("reg_0", 3)
+            .check();
+    }
 
+    #[test]
+    fn user_mode_cannot_write_supervisor_registers() {
+        for name in ["trap_vector", "core_id"] {
+            let mut machine = TestMachine::default();
+            machine.cpu.privilege = Privilege::User;
+            let register = machine.reg(name);
+            machine.program(|p| {
+                p.add_instruction(InstructionSet::LoadImmediate, &[register, 0x10]);
+            });
+            machine.run(50);
+            machine.expect()
+                .halted(false)
+                .fault(Some(Fault::PrivilegeViolation { address: 0 }))
+                .register(name, 0)
+                .check();
+        }
+    }
 }
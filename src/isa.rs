@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use num_bigint::BigUint;
+
+use crate::computer::{Endianness, Instruction, Operand, Registers, SubInstructions};
+use crate::machine::parse_number;
+use crate::writers::InstructionSetWriter;
+
+// Instruction set description files.
+//
+// Lists each instruction's opcode, operands and microcode, so a machine description can name an
+// instruction set other than the built in one, see machines/minimal.isa. The operand widths and the
+// argument offsets in the microcode follow the machine's data_size, and registers are named as in the
+// machine's register file, so a file only suits machines laid out like the one it was written for.
+//
+// instruction <opcode> [<operand>...] [privileged]
+//     <micro-op> [<argument>...]
+//
+// Operands and micro-ops are named as in Operand and SubInstructions. Micro-op arguments are numbers,
+// register names for LoadFromRegisterInternal and StoreToRegisterInternal, and big or little for byte
+// orders. The whole set is checked by the validator before it is accepted.
+//
+// let instruction_set = parse_isa(&std::fs::read_to_string("machines/minimal.isa")?, &cpu.registers, 1)?;
+
+const OPERANDS: &[(&str, Operand)] = &[
+    ("Reg", Operand::Reg),
+    ("Imm8", Operand::Imm8),
+    ("Imm16", Operand::Imm16),
+    ("Imm", Operand::Imm),
+    ("Addr", Operand::Addr),
+    ("RegOrAddr", Operand::RegOrAddr),
+];
+
+struct Parsed {
+    opcode: u8,
+    operands: Vec<Operand>,
+    privileged: bool,
+    sub_instructions: Vec<SubInstructions>,
+}
+
+pub fn parse_isa(text: &str, registers: &Registers, data_size: u8) -> Result<HashMap<u8, Instruction>, String> {
+    let mut instructions: Vec<Parsed> = Vec::new();
+    let mut lines: HashMap<u8, usize> = HashMap::new();
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        let content = raw.split('#').next().unwrap_or("").trim();
+        let words: Vec<&str> = content.split_whitespace().collect();
+        let Some((keyword, args)) = words.split_first() else {
+            continue;
+        };
+        let error = |message: String| format!("line {}: {}", line, message);
+        if *keyword == "instruction" {
+            let Some((opcode, rest)) = args.split_first() else {
+                return Err(error("instruction needs an opcode".to_string()));
+            };
+            let opcode = u8::try_from(parse_number(opcode).map_err(error)?).map_err(|_| error(format!("opcode {} is not a byte", opcode)))?;
+            if let Some(first) = lines.insert(opcode, line) {
+                return Err(error(format!("opcode {} already defined on line {}", opcode, first)));
+            }
+            let (privileged, rest) = match rest.split_last() {
+                Some((&"privileged", rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            let operands = rest
+                .iter()
+                .map(|name| {
+                    OPERANDS.iter().find(|(known, _)| known == name).map(|(_, operand)| *operand).ok_or_else(|| {
+                        let names: Vec<&str> = OPERANDS.iter().map(|(known, _)| *known).collect();
+                        error(format!("unknown operand '{}', expected one of {}", name, names.join(", ")))
+                    })
+                })
+                .collect::<Result<Vec<Operand>, String>>()?;
+            instructions.push(Parsed { opcode, operands, privileged, sub_instructions: Vec::new() });
+            continue;
+        }
+        let Some(instruction) = instructions.last_mut() else {
+            return Err(error(format!("micro-op {} before any instruction", keyword)));
+        };
+        let sub_instruction = parse_sub_instruction(keyword, args, registers).map_err(error)?;
+        instruction.sub_instructions.push(sub_instruction);
+    }
+    let mut writer = InstructionSetWriter::new(data_size);
+    for parsed in instructions {
+        let builder = writer.add_instruction(parsed.opcode, &parsed.operands);
+        if parsed.privileged {
+            builder.privileged();
+        }
+        for sub_instruction in parsed.sub_instructions {
+            builder.add_sub_instruction(sub_instruction);
+        }
+    }
+    writer.build_checked(registers).map_err(|diagnostics| {
+        let messages: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect();
+        format!("Invalid instruction set: {}", messages.join("; "))
+    })
+}
+
+fn parse_sub_instruction(name: &str, args: &[&str], registers: &Registers) -> Result<SubInstructions, String> {
+    let expect = |count: usize| match args.len() == count {
+        true => Ok(()),
+        false => Err(format!("{} takes {} argument(s), got {}", name, count, args.len())),
+    };
+    let byte = |index: usize| -> Result<u8, String> {
+        let value = parse_number(args[index])?;
+        u8::try_from(value).map_err(|_| format!("{} is not a byte", args[index]))
+    };
+    let register = |index: usize| registers.id_of(args[index]);
+    let endianness = |index: usize| match args[index] {
+        "big" => Ok(Endianness::Big),
+        "little" => Ok(Endianness::Little),
+        other => Err(format!("unknown byte order '{}', expected big or little", other)),
+    };
+    let mask = |index: usize| {
+        let text = args[index];
+        let parsed = match text.strip_prefix("0x") {
+            Some(hex) => BigUint::parse_bytes(hex.as_bytes(), 16),
+            None => BigUint::parse_bytes(text.as_bytes(), 10),
+        };
+        parsed.ok_or_else(|| format!("'{}' is not a number", text))
+    };
+    let plain = |op: SubInstructions| expect(0).map(|_| op);
+    match name {
+        "NoOperation" => plain(SubInstructions::NoOperation),
+        "Halt" => plain(SubInstructions::Halt),
+        "LoadFromMemory" => plain(SubInstructions::LoadFromMemory),
+        "SetMemoryAddress" => plain(SubInstructions::SetMemoryAddress),
+        "StoreToMemory" => plain(SubInstructions::StoreToMemory),
+        "Add" => plain(SubInstructions::Add),
+        "Sub" => plain(SubInstructions::Sub),
+        "PushToStack" => plain(SubInstructions::PushToStack),
+        "PopFromStack" => plain(SubInstructions::PopFromStack),
+        "Jump" => plain(SubInstructions::Jump),
+        "Compare" => plain(SubInstructions::Compare),
+        "SystemCall" => plain(SubInstructions::SystemCall),
+        "ReturnFromTrap" => plain(SubInstructions::ReturnFromTrap),
+        "CompareAndSwap" => plain(SubInstructions::CompareAndSwap),
+        "FetchAdd" => plain(SubInstructions::FetchAdd),
+        "Fence" => plain(SubInstructions::Fence),
+        "LoadImmediate" => expect(1).and_then(|_| Ok(SubInstructions::LoadImmediate(byte(0)?))),
+        "LoadImmediateInternal" => expect(1).and_then(|_| Ok(SubInstructions::LoadImmediateInternal(byte(0)?))),
+        "LoadFromRegister" => expect(1).and_then(|_| Ok(SubInstructions::LoadFromRegister(byte(0)?))),
+        "StoreToRegister" => expect(1).and_then(|_| Ok(SubInstructions::StoreToRegister(byte(0)?))),
+        "StepProgramMemory" => expect(1).and_then(|_| Ok(SubInstructions::StepProgramMemory(byte(0)?))),
+        "LoadFromRegisterInternal" => expect(1).and_then(|_| Ok(SubInstructions::LoadFromRegisterInternal(register(0)?))),
+        "StoreToRegisterInternal" => expect(1).and_then(|_| Ok(SubInstructions::StoreToRegisterInternal(register(0)?))),
+        "LoadImmediateWide" => expect(3).and_then(|_| Ok(SubInstructions::LoadImmediateWide(byte(0)?, byte(1)?, endianness(2)?))),
+        "JumpIfFlag" => expect(2).and_then(|_| Ok(SubInstructions::JumpIfFlag(mask(0)?, mask(1)?))),
+        "JumpIfNotFlag" => expect(2).and_then(|_| Ok(SubInstructions::JumpIfNotFlag(mask(0)?, mask(1)?))),
+        "AddressOffset" => expect(4).and_then(|_| Ok(SubInstructions::AddressOffset(byte(0)?, byte(1)?, byte(2)?, endianness(3)?))),
+        "AddressIndexed" => expect(3).and_then(|_| Ok(SubInstructions::AddressIndexed(byte(0)?, byte(1)?, byte(2)?))),
+        "AddressPcRelative" => expect(3).and_then(|_| Ok(SubInstructions::AddressPcRelative(byte(0)?, byte(1)?, endianness(2)?))),
+        other => Err(format!("unknown micro-op '{}'", other)),
+    }
+}
+
+// The text parse_isa reads back as the same instruction set, in opcode order
+pub fn write_isa(instruction_set: &HashMap<u8, Instruction>, registers: &Registers) -> Result<String, String> {
+    let mut opcodes: Vec<&u8> = instruction_set.keys().collect();
+    opcodes.sort();
+    let mut text = String::new();
+    for opcode in opcodes {
+        let instruction = &instruction_set[opcode];
+        let _ = write!(text, "instruction {}", opcode);
+        for operand in &instruction.operands {
+            let (name, _) = OPERANDS.iter().find(|(_, known)| known == operand).expect("Every operand is listed");
+            let _ = write!(text, " {}", name);
+        }
+        if instruction.privileged {
+            text.push_str(" privileged");
+        }
+        text.push('\n');
+        for sub_instruction in &instruction.sub_instructions {
+            let _ = writeln!(text, "    {}", sub_instruction_text(sub_instruction, registers)?);
+        }
+    }
+    Ok(text)
+}
+
+fn sub_instruction_text(op: &SubInstructions, registers: &Registers) -> Result<String, String> {
+    let register = |id: u8| registers.name_of(id).map(str::to_string).ok_or_else(|| format!("register {} does not exist", id));
+    let order = |endianness: &Endianness| match endianness {
+        Endianness::Big => "big",
+        Endianness::Little => "little",
+    };
+    Ok(match op {
+        SubInstructions::NoOperation => "NoOperation".to_string(),
+        SubInstructions::Halt => "Halt".to_string(),
+        SubInstructions::LoadImmediate(offset) => format!("LoadImmediate {}", offset),
+        SubInstructions::LoadImmediateWide(offset, width, endianness) => format!("LoadImmediateWide {} {} {}", offset, width, order(endianness)),
+        SubInstructions::LoadImmediateInternal(value) => format!("LoadImmediateInternal {}", value),
+        SubInstructions::LoadFromMemory => "LoadFromMemory".to_string(),
+        SubInstructions::LoadFromRegister(offset) => format!("LoadFromRegister {}", offset),
+        SubInstructions::LoadFromRegisterInternal(id) => format!("LoadFromRegisterInternal {}", register(*id)?),
+        SubInstructions::SetMemoryAddress => "SetMemoryAddress".to_string(),
+        SubInstructions::StoreToMemory => "StoreToMemory".to_string(),
+        SubInstructions::StoreToRegister(offset) => format!("StoreToRegister {}", offset),
+        SubInstructions::StoreToRegisterInternal(id) => format!("StoreToRegisterInternal {}", register(*id)?),
+        SubInstructions::StepProgramMemory(size) => format!("StepProgramMemory {}", size),
+        SubInstructions::Add => "Add".to_string(),
+        SubInstructions::Sub => "Sub".to_string(),
+        SubInstructions::PushToStack => "PushToStack".to_string(),
+        SubInstructions::PopFromStack => "PopFromStack".to_string(),
+        SubInstructions::Jump => "Jump".to_string(),
+        SubInstructions::Compare => "Compare".to_string(),
+        SubInstructions::JumpIfFlag(true_mask, false_mask) => format!("JumpIfFlag {:#x} {:#x}", true_mask, false_mask),
+        SubInstructions::JumpIfNotFlag(true_mask, false_mask) => format!("JumpIfNotFlag {:#x} {:#x}", true_mask, false_mask),
+        SubInstructions::SystemCall => "SystemCall".to_string(),
+        SubInstructions::ReturnFromTrap => "ReturnFromTrap".to_string(),
+        SubInstructions::CompareAndSwap => "CompareAndSwap".to_string(),
+        SubInstructions::FetchAdd => "FetchAdd".to_string(),
+        SubInstructions::Fence => "Fence".to_string(),
+        SubInstructions::AddressOffset(base, displacement, width, endianness) => {
+            format!("AddressOffset {} {} {} {}", base, displacement, width, order(endianness))
+        }
+        SubInstructions::AddressIndexed(base, index, scale) => format!("AddressIndexed {} {} {}", base, index, scale),
+        SubInstructions::AddressPcRelative(displacement, width, endianness) => format!("AddressPcRelative {} {} {}", displacement, width, order(endianness)),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computer::{create_default_cpu, Registers};
+    use crate::image::isa_hash;
+    use crate::instructions::default_instruction_set;
+
+    #[test]
+    fn default_instruction_set_round_trips() {
+        let registers = create_default_cpu(64, 0).registers;
+        for (data_size, endianness) in [(1, Endianness::Big), (3, Endianness::Little)] {
+            let instruction_set = default_instruction_set(&registers, data_size, endianness).unwrap();
+            let text = write_isa(&instruction_set, &registers).unwrap();
+            let parsed = parse_isa(&text, &registers, data_size).unwrap();
+            assert_eq!(isa_hash(&parsed), isa_hash(&instruction_set));
+        }
+    }
+
+    #[test]
+    fn reports_invalid_files() {
+        let registers = create_default_cpu(64, 0).registers;
+        let error = |text: &str| parse_isa(text, &registers, 1).err().unwrap();
+        assert_eq!(error("Halt\n"), "line 1: micro-op Halt before any instruction");
+        assert_eq!(error("instruction 0\n    Stop\n"), "line 2: unknown micro-op 'Stop'");
+        assert_eq!(error("instruction 0\n    Halt 1\n"), "line 2: Halt takes 0 argument(s), got 1");
+        assert_eq!(error("instruction 0 Word\n"), "line 1: unknown operand 'Word', expected one of Reg, Imm8, Imm16, Imm, Addr, RegOrAddr");
+        assert_eq!(error("instruction 256\n"), "line 1: opcode 256 is not a byte");
+        assert_eq!(error("instruction 1\ninstruction 0x1\n"), "line 2: opcode 1 already defined on line 1");
+        assert!(error("instruction 0\n    LoadFromRegisterInternal nowhere\n").starts_with("line 2: "));
+        assert_eq!(error("instruction 0\n    LoadImmediateWide 1 1 middle\n"), "line 2: unknown byte order 'middle', expected big or little");
+        // Checked by the validator once the whole file is read
+        assert!(error("instruction 0 Reg\n    StepProgramMemory 1\n    LoadFromRegister 2\n").starts_with("Invalid instruction set: opcode 0"));
+        // A register set without the names the file uses
+        assert!(parse_isa("instruction 0\n    StoreToRegisterInternal reg_a\n", &Registers::new(), 1).is_err());
+    }
+}
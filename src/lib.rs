@@ -0,0 +1,235 @@
+// Embeddable computer simulator.
+//
+// let mut cpu = rust_computer_sim::create_machine(64, 0, MachineConfig::default())?;
+// let reg_0 = cpu.registers.id_of("reg_0")?;
+// let mut program = ProgramWriter::new(cpu.instruction_set.clone(), &cpu.registers);
+// program.set_endianness(cpu.endianness);
+// program.try_add_instruction(InstructionSet::LoadImmediate, &[reg_0, 5])?
+//     .try_add_instruction(InstructionSet::Halt, &[])?;
+// cpu.memory.write_chunk(0, &program.build());
+// let reason = cpu.run_until_halt(1000);
+
+pub mod computer;
+pub mod writers;
+pub mod instructions;
+pub mod validator;
+pub mod mmu;
+pub mod cache;
+pub mod timing;
+pub mod pipeline;
+pub mod branch;
+pub mod multicore;
+pub mod blocks;
+pub mod devices;
+pub mod isa;
+pub mod machine;
+pub mod object;
+pub mod linker;
+pub mod image;
+pub mod hexfile;
+pub mod assembler;
+#[cfg(test)]
+mod harness;
+
+pub use computer::{
+    create_default_cpu, create_default_cpu_with_config, Endianness, Fault, MachineConfig, Memory, Privilege, Register,
+    Registers, StopReason, Storage, CPU,
+};
+pub use writers::{InstructionSetWriter, ProgramWriter};
+pub use linker::{LinkedImage, Linker};
+pub use image::Executable;
+pub use object::ObjectFile;
+pub use hexfile::HexImage;
+pub use devices::{Console, Device};
+pub use assembler::Assembler;
+
+// Opcodes of the default instruction set, the numbering is fixed so programs stay valid across releases
+#[repr(u8)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum InstructionSet {
+    NoOperation = 0,          // No args
+    Halt = 1,                 // No args, supervisor only
+    LoadFromMemory = 2,       // #Addr #Reg
+    StoreToMemory = 3,        // #Reg #Addr
+    LoadFromMemoryReg = 4,    // #Reg/Addr #Reg
+    StoreToMemoryReg = 5,     // #Reg #Reg/Addr
+    AddImmediate = 6,         // #Reg #Imm
+    AddReg = 7,               // #Reg #Reg
+    SubImmediate = 8,         // #Reg #Imm
+    SubReg = 9,               // #Reg #Reg
+    LoadImmediate = 10,       // #Reg #Imm
+    MoveRegister = 11,        // #Reg #Reg
+    PushImmediate = 12,       // #Imm
+    PushReg = 13,             // #Reg
+    PopReg = 14,              // #Reg
+    Jump = 15,                // #Addr
+    JumpEqual = 16,           // #Reg/Addr #Reg (A) #Imm (B)
+    JumpNotEqual = 17,        // #Reg/Addr #Reg (A) #Imm (B)
+    JumpGreaterThan = 18,     // #Reg/Addr #Reg (A) #Imm (B)
+    JumpLessThan = 19,        // #Reg/Addr #Reg (A) #Imm (B)
+    JumpLessEqual = 20,       // #Reg/Addr #Reg (A) #Imm (B)
+    JumpGreaterEqual = 21,    // #Reg/Addr #Reg (A) #Imm (B)
+    JumpReg = 22,             // #Reg/Addr
+    JumpEqualReg = 23,        // #Reg/Addr #Reg (A) #Reg (B)
+    JumpNotEqualReg = 24,     // #Reg/Addr #Reg (A) #Reg (B)
+    JumpGreaterThanReg = 25,  // #Reg/Addr #Reg (A) #Reg (B)
+    JumpLessThanReg = 26,     // #Reg/Addr #Reg (A) #Reg (B)
+    JumpLessEqualReg = 27,    // #Reg/Addr #Reg (A) #Reg (B)
+    JumpGreaterEqualReg = 28, // #Reg/Addr #Reg (A) #Reg (B)
+    Return = 29,              // No args
+    SystemCall = 30,          // No args
+    ReturnFromTrap = 31,      // No args, supervisor only
+    CompareAndSwap = 32,      // #Reg/Addr #Reg (expected, gets the old value) #Reg (new)
+    FetchAdd = 33,            // #Reg/Addr #Reg (added, gets the old value)
+    Fence = 34,               // No args
+    // Register indirect addressing, offsets and steps are two's complement
+    LoadOffset = 35,          // #Reg (base) #Imm (offset) #Reg
+    StoreOffset = 36,         // #Reg #Reg (base) #Imm (offset)
+    LoadIndexed = 37,         // #Reg (base) #Reg (index) #Imm8 (scale) #Reg
+    StoreIndexed = 38,        // #Reg #Reg (base) #Reg (index) #Imm8 (scale)
+    LoadPreIncrement = 39,    // #Reg (pointer) #Imm (step) #Reg, steps the pointer then loads
+    LoadPostIncrement = 40,   // #Reg (pointer) #Imm (step) #Reg, loads then steps the pointer
+    StorePreIncrement = 41,   // #Reg #Reg (pointer) #Imm (step)
+    StorePostIncrement = 42,  // #Reg #Reg (pointer) #Imm (step)
+    LoadPcRelative = 43,      // #Imm (offset from the opcode) #Reg
+    StorePcRelative = 44,     // #Reg #Imm (offset from the opcode)
+    // Position independent control flow, the offset is from the branch's own opcode
+    Branch = 45,              // #Imm (offset)
+    BranchEqual = 46,         // #Imm (offset) #Reg (A) #Imm (B)
+    BranchNotEqual = 47,      // #Imm (offset) #Reg (A) #Imm (B)
+    BranchGreaterThan = 48,   // #Imm (offset) #Reg (A) #Imm (B)
+    BranchLessThan = 49,      // #Imm (offset) #Reg (A) #Imm (B)
+    BranchLessEqual = 50,     // #Imm (offset) #Reg (A) #Imm (B)
+    BranchGreaterEqual = 51,  // #Imm (offset) #Reg (A) #Imm (B)
+    CallRelative = 52,        // #Imm (offset), pushes the same return frame as Jump
+}
+//
+// Jump sets the return_address
+//
+
+impl InstructionSet {
+    pub const ALL: [InstructionSet; 53] = [
+        InstructionSet::NoOperation,
+        InstructionSet::Halt,
+        InstructionSet::LoadFromMemory,
+        InstructionSet::StoreToMemory,
+        InstructionSet::LoadFromMemoryReg,
+        InstructionSet::StoreToMemoryReg,
+        InstructionSet::AddImmediate,
+        InstructionSet::AddReg,
+        InstructionSet::SubImmediate,
+        InstructionSet::SubReg,
+        InstructionSet::LoadImmediate,
+        InstructionSet::MoveRegister,
+        InstructionSet::PushImmediate,
+        InstructionSet::PushReg,
+        InstructionSet::PopReg,
+        InstructionSet::Jump,
+        InstructionSet::JumpEqual,
+        InstructionSet::JumpNotEqual,
+        InstructionSet::JumpGreaterThan,
+        InstructionSet::JumpLessThan,
+        InstructionSet::JumpLessEqual,
+        InstructionSet::JumpGreaterEqual,
+        InstructionSet::JumpReg,
+        InstructionSet::JumpEqualReg,
+        InstructionSet::JumpNotEqualReg,
+        InstructionSet::JumpGreaterThanReg,
+        InstructionSet::JumpLessThanReg,
+        InstructionSet::JumpLessEqualReg,
+        InstructionSet::JumpGreaterEqualReg,
+        InstructionSet::Return,
+        InstructionSet::SystemCall,
+        InstructionSet::ReturnFromTrap,
+        InstructionSet::CompareAndSwap,
+        InstructionSet::FetchAdd,
+        InstructionSet::Fence,
+        InstructionSet::LoadOffset,
+        InstructionSet::StoreOffset,
+        InstructionSet::LoadIndexed,
+        InstructionSet::StoreIndexed,
+        InstructionSet::LoadPreIncrement,
+        InstructionSet::LoadPostIncrement,
+        InstructionSet::StorePreIncrement,
+        InstructionSet::StorePostIncrement,
+        InstructionSet::LoadPcRelative,
+        InstructionSet::StorePcRelative,
+        InstructionSet::Branch,
+        InstructionSet::BranchEqual,
+        InstructionSet::BranchNotEqual,
+        InstructionSet::BranchGreaterThan,
+        InstructionSet::BranchLessThan,
+        InstructionSet::BranchLessEqual,
+        InstructionSet::BranchGreaterEqual,
+        InstructionSet::CallRelative,
+    ];
+}
+
+impl From<InstructionSet> for u8 {
+    fn from(instruction: InstructionSet) -> u8 {
+        instruction as u8
+    }
+}
+
+impl TryFrom<u8> for InstructionSet {
+    type Error = String;
+
+    fn try_from(opcode: u8) -> Result<Self, Self::Error> {
+        InstructionSet::ALL
+            .get(opcode as usize)
+            .copied()
+            .ok_or_else(|| format!("Opcode {} is not in the default instruction set", opcode))
+    }
+}
+
+// A CPU with the default registers and every InstructionSet opcode loaded
+pub fn create_machine(memory_size: usize, storage_size: usize, config: MachineConfig) -> Result<CPU, String> {
+    let mut cpu = create_default_cpu_with_config(Memory::new(memory_size), Storage::new(storage_size), config)?;
+    let instruction_set = instructions::default_instruction_set(&cpu.registers, config.data_size, config.endianness)?;
+    cpu.set_instruction_set(instruction_set);
+    Ok(cpu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_round_trip() {
+        for (index, instruction) in InstructionSet::ALL.iter().enumerate() {
+            assert_eq!(u8::from(*instruction) as usize, index);
+            assert_eq!(InstructionSet::try_from(index as u8), Ok(*instruction));
+        }
+        assert!(InstructionSet::try_from(InstructionSet::ALL.len() as u8).is_err());
+    }
+
+    #[test]
+    fn embedded_machine_runs_a_program() {
+        let mut cpu = create_machine(64, 0, MachineConfig::default()).unwrap();
+        let reg_0 = cpu.registers.id_of("reg_0").unwrap();
+        let mut program = ProgramWriter::new(cpu.instruction_set.clone(), &cpu.registers);
+        program.try_add_instruction(InstructionSet::LoadImmediate, &[reg_0, 5]).unwrap()
+            .try_add_instruction(InstructionSet::Halt, &[]).unwrap();
+        cpu.memory.write_chunk(0, &program.build());
+        assert_eq!(cpu.run_until_halt(1000), StopReason::Halted);
+        assert_eq!(cpu.read_register_value_string("reg_0"), Some(5u8.into()));
+    }
+
+    #[test]
+    fn general_register_file_is_configurable() {
+        let cpu = create_machine(64, 0, MachineConfig { general_registers: 16, ..MachineConfig::default() }).unwrap();
+        assert!(cpu.registers.id_of("reg_15").is_ok());
+        assert!(cpu.registers.id_of("reg_16").is_err());
+        let too_many = MachineConfig { general_registers: computer::MAX_GENERAL_REGISTERS as u8 + 1, ..MachineConfig::default() };
+        assert!(create_machine(64, 0, too_many).is_err());
+        assert!(create_default_cpu_with_config(Memory::new(64), Storage::new(0), too_many).is_err());
+    }
+
+    #[test]
+    fn unknown_opcodes_fault() {
+        let mut cpu = create_machine(64, 0, MachineConfig::default()).unwrap();
+        cpu.memory.write_chunk(0, &[InstructionSet::NoOperation as u8, 0xff]);
+        assert_eq!(cpu.run_until_halt(1000), StopReason::Fault(Fault::InvalidOpcode { address: 1 }));
+        assert!(CPU::new(Registers::new(), Memory::new(8), Storage::new(0)).is_err());
+    }
+}
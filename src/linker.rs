@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use num_bigint::BigUint;
+use num_traits::One;
+
+use crate::computer::{Endianness, CPU};
+use crate::object::{displacement, ObjectFile, RelocationKind, SectionKind};
+
+// Static linker for ObjectFiles.
+//
+// The code sections of every object are laid out one after another in the order the objects were
+// added, then the data sections and then bss. Each kind starts where the previous one ended unless
+// it is given an address of its own. Relocations look a symbol up in their own module first and then
+// among the other modules' globals.
+//
+// let image = Linker::new().code_at(0x100).add(main).add(stdlib).entry("start").link()?;
+// image.load(&mut cpu)?;
+// println!("{}", image.map());
+
+#[derive(Default)]
+pub struct Linker {
+    objects: Vec<ObjectFile>,
+    addresses: HashMap<SectionKind, usize>,
+    entry: Option<String>,
+}
+
+// Where one module's section ended up
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub module: String,
+    pub section: SectionKind,
+    pub address: usize,
+    pub size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkedSymbol {
+    pub name: String,
+    pub module: String,
+    pub address: usize,
+    pub global: bool,
+}
+
+// The contents of one section kind across all modules, bss as zeros
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub section: SectionKind,
+    pub address: usize,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkedImage {
+    pub endianness: Endianness,
+    pub entry: usize,
+    // Empty sections are left out
+    pub segments: Vec<Segment>,
+    pub placements: Vec<Placement>,
+    pub symbols: Vec<LinkedSymbol>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Linker::default()
+    }
+
+    pub fn add(&mut self, object: ObjectFile) -> &mut Self {
+        self.objects.push(object);
+        self
+    }
+
+    // Code starts at 0 by default
+    pub fn code_at(&mut self, address: usize) -> &mut Self {
+        self.addresses.insert(SectionKind::Code, address);
+        self
+    }
+
+    pub fn data_at(&mut self, address: usize) -> &mut Self {
+        self.addresses.insert(SectionKind::Data, address);
+        self
+    }
+
+    pub fn bss_at(&mut self, address: usize) -> &mut Self {
+        self.addresses.insert(SectionKind::Bss, address);
+        self
+    }
+
+    // A global symbol to start at, the start of the code otherwise
+    pub fn entry(&mut self, symbol: &str) -> &mut Self {
+        self.entry = Some(symbol.to_string());
+        self
+    }
+
+    pub fn link(&self) -> Result<LinkedImage, String> {
+        let Some(first) = self.objects.first() else {
+            return Err("Nothing to link".to_string());
+        };
+        let endianness = first.endianness;
+        if let Some(other) = self.objects.iter().find(|object| object.endianness != endianness) {
+            return Err(format!("{} is {:?} endian but {} is {:?} endian", other.name, other.endianness, first.name, endianness));
+        }
+        for object in &self.objects {
+            object.check()?;
+        }
+
+        // Section base of every module, indexed like objects
+        let mut bases: Vec<HashMap<SectionKind, usize>> = vec![HashMap::new(); self.objects.len()];
+        let mut placements = Vec::new();
+        let mut segments = Vec::new();
+        let mut next = 0;
+        for section in SectionKind::ALL {
+            let start = self.addresses.get(&section).copied().unwrap_or(next);
+            let mut address = start;
+            for (index, object) in self.objects.iter().enumerate() {
+                let size = object.section_size(section);
+                bases[index].insert(section, address);
+                if size > 0 {
+                    placements.push(Placement { module: object.name.clone(), section, address, size });
+                }
+                address += size;
+            }
+            if address > start {
+                let bytes = match section {
+                    SectionKind::Code => self.objects.iter().flat_map(|object| object.code.iter().copied()).collect(),
+                    SectionKind::Data => self.objects.iter().flat_map(|object| object.data.iter().copied()).collect(),
+                    SectionKind::Bss => vec![0; address - start],
+                };
+                segments.push(Segment { section, address: start, bytes });
+            }
+            next = address;
+        }
+        for (index, segment) in segments.iter().enumerate() {
+            let end = segment.address + segment.bytes.len();
+            if let Some(other) = segments[index + 1..].iter().find(|other| other.address < end && segment.address < other.address + other.bytes.len()) {
+                return Err(format!("The {} and {} sections overlap", segment.section.name(), other.section.name()));
+            }
+        }
+
+        let mut symbols = Vec::new();
+        let mut globals: HashMap<&str, (usize, &str)> = HashMap::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            for symbol in &object.symbols {
+                let address = bases[index][&symbol.section] + symbol.offset;
+                if symbol.global {
+                    if let Some((_, module)) = globals.insert(&symbol.name, (address, &object.name)) {
+                        return Err(format!("Symbol '{}' is defined in both {} and {}", symbol.name, module, object.name));
+                    }
+                }
+                symbols.push(LinkedSymbol { name: symbol.name.clone(), module: object.name.clone(), address, global: symbol.global });
+            }
+        }
+
+        for (index, object) in self.objects.iter().enumerate() {
+            for relocation in &object.relocations {
+                let target = match object.symbol(&relocation.symbol) {
+                    Some(symbol) => bases[index][&symbol.section] + symbol.offset,
+                    None => globals
+                        .get(relocation.symbol.as_str())
+                        .map(|(address, _)| *address)
+                        .ok_or_else(|| format!("Undefined symbol '{}' referenced from {}", relocation.symbol, object.name))?,
+                };
+                let base = bases[index][&relocation.section];
+                let value = match relocation.kind {
+                    RelocationKind::Absolute => {
+                        let value = BigUint::from(target);
+                        if value >= BigUint::one() << (8 * relocation.width) {
+                            return Err(format!("Address {:#x} of '{}' does not fit in {} byte(s) in {}", target, relocation.symbol, relocation.width, object.name));
+                        }
+                        value
+                    }
+                    RelocationKind::Relative { origin } => displacement(target, base + origin, relocation.width)
+                        .map_err(|error| format!("{} to '{}' in {}", error, relocation.symbol, object.name))?,
+                };
+                let segment = segments.iter_mut().find(|segment| segment.section == relocation.section).expect("Relocated sections are laid out");
+                let start = base - segment.address + relocation.offset;
+                segment.bytes[start..start + relocation.width].copy_from_slice(&endianness.encode(&value, relocation.width));
+            }
+        }
+
+        let entry = match &self.entry {
+            Some(name) => globals.get(name.as_str()).map(|(address, _)| *address).ok_or_else(|| format!("Entry symbol '{}' is not a global", name))?,
+            None => self.addresses.get(&SectionKind::Code).copied().unwrap_or(0),
+        };
+        Ok(LinkedImage { endianness, entry, segments, placements, symbols })
+    }
+}
+
+impl LinkedImage {
+    pub fn symbol(&self, name: &str) -> Option<usize> {
+        self.symbols.iter().find(|symbol| symbol.name == name && symbol.global).or_else(|| self.symbols.iter().find(|symbol| symbol.name == name)).map(|symbol| symbol.address)
+    }
+
+    // Memory contents from the lowest segment address to the end of the highest, gaps zero filled
+    pub fn flatten(&self) -> (usize, Vec<u8>) {
+        let start = self.segments.iter().map(|segment| segment.address).min().unwrap_or(0);
+        let end = self.segments.iter().map(|segment| segment.address + segment.bytes.len()).max().unwrap_or(0);
+        let mut bytes = vec![0; end - start];
+        for segment in &self.segments {
+            bytes[segment.address - start..segment.address - start + segment.bytes.len()].copy_from_slice(&segment.bytes);
+        }
+        (start, bytes)
+    }
+
+    // Writes every segment into memory and points the program counter at the entry
+    pub fn load(&self, cpu: &mut CPU) -> Result<(), String> {
+        if self.endianness != cpu.endianness {
+            return Err(format!("Image is {:?} endian but the CPU is {:?} endian", self.endianness, cpu.endianness));
+        }
+        for segment in &self.segments {
+            if segment.address + segment.bytes.len() > cpu.memory.size() {
+                return Err(format!("The {} section at {:#x} does not fit in {} bytes of memory", segment.section.name(), segment.address, cpu.memory.size()));
+            }
+        }
+        for segment in &self.segments {
+            cpu.load_program(segment.address, &segment.bytes);
+        }
+        cpu.set_program_counter(self.entry.into());
+        Ok(())
+    }
+
+    // Human readable layout, one line per placed section and per symbol
+    pub fn map(&self) -> String {
+        let mut map = String::new();
+        let _ = writeln!(map, "Entry {:#06x}", self.entry);
+        let _ = writeln!(map, "\nSections");
+        for placement in &self.placements {
+            let _ = writeln!(map, "  {:#06x} {:>6} {:<5} {}", placement.address, placement.size, placement.section.name(), placement.module);
+        }
+        let _ = writeln!(map, "\nSymbols");
+        let mut symbols: Vec<&LinkedSymbol> = self.symbols.iter().collect();
+        symbols.sort_by_key(|symbol| (symbol.address, symbol.name.clone()));
+        for symbol in symbols {
+            let visibility = if symbol.global { "global" } else { "local" };
+            let _ = writeln!(map, "  {:#06x} {:<6} {} ({})", symbol.address, visibility, symbol.name, symbol.module);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::writers::ProgramWriter;
+    use crate::InstructionSet;
+
+    fn writer(machine: &TestMachine) -> ProgramWriter {
+        ProgramWriter::new(machine.cpu.instruction_set.clone(), &machine.cpu.registers)
+    }
+
+    // main loads a value from its data and calls add_one in the library, which keeps a copy in its bss
+    fn modules(machine: &mut TestMachine) -> (ObjectFile, ObjectFile) {
+        let reg_0 = machine.reg("reg_0") as u64;
+        let mut main = writer(machine);
+        main.global("start")
+            .label("start")
+            .add_address_instruction(InstructionSet::LoadFromMemory, 0, "value", &[reg_0])
+            .add_relative_instruction(InstructionSet::CallRelative, "add_one", &[])
+            .add_instruction(InstructionSet::Halt, &[])
+            .data("value", &[41]);
+        let mut library = writer(machine);
+        library.global("add_one")
+            .label("add_one")
+            .add_instruction_wide(InstructionSet::AddImmediate, &[reg_0, 1])
+            .add_address_instruction(InstructionSet::StoreToMemory, 1, "last", &[reg_0])
+            .add_instruction(InstructionSet::Return, &[])
+            .reserve("last", 1);
+        (main.try_build_object("main").unwrap(), library.try_build_object("library").unwrap())
+    }
+
+    #[test]
+    fn links_modules_across_symbols() {
+        let mut machine = TestMachine::default();
+        let (main, library) = modules(&mut machine);
+        // The call into the library is left for the linker
+        assert_eq!(main.relocations.len(), 2);
+        let library = ObjectFile::from_bytes(&library.to_bytes().unwrap()).unwrap();
+        let image = Linker::new().code_at(0x10).add(main).add(library).entry("start").link().unwrap();
+        assert_eq!(image.entry, 0x10);
+        assert_eq!(image.symbol("add_one"), Some(0x16));
+        assert_eq!(image.symbol("value"), Some(0x1d));
+        assert_eq!(image.symbol("last"), Some(0x1e));
+        image.load(&mut machine.cpu).unwrap();
+        machine.load(0x1e, &[0xff]);
+        machine.run(500);
+        machine.expect().halted(true).register("reg_0", 42).memory(0x1e, &[42]).check();
+
+        let map = image.map();
+        assert!(map.contains("0x0016 global add_one (library)"), "{}", map);
+        assert!(map.contains("0x001e      1 bss   library"), "{}", map);
+    }
+
+    #[test]
+    fn sections_can_be_placed_apart() {
+        let mut machine = TestMachine::default();
+        let (main, library) = modules(&mut machine);
+        let image = Linker::new().add(main).add(library).data_at(0x30).bss_at(0x38).link().unwrap();
+        assert_eq!(image.symbol("value"), Some(0x30));
+        assert_eq!(image.symbol("last"), Some(0x38));
+        image.load(&mut machine.cpu).unwrap();
+        machine.run(500);
+        machine.expect().halted(true).register("reg_0", 42).memory(0x38, &[42]).check();
+        assert!(Linker::new().add(modules(&mut machine).0).data_at(2).link().unwrap_err().contains("overlap"));
+    }
+
+    #[test]
+    fn reports_symbol_errors() {
+        let mut machine = TestMachine::default();
+        let (main, library) = modules(&mut machine);
+        let error = Linker::new().add(main.clone()).link().unwrap_err();
+        assert_eq!(error, "Undefined symbol 'add_one' referenced from main");
+        let error = Linker::new().add(library.clone()).add(library.clone()).add(main.clone()).link().unwrap_err();
+        assert_eq!(error, "Symbol 'add_one' is defined in both library and library");
+        let error = Linker::new().add(main.clone()).add(library.clone()).entry("missing").link().unwrap_err();
+        assert_eq!(error, "Entry symbol 'missing' is not a global");
+        // Padding puts add_one out of reach of main's one byte call offset
+        let mut padding = ObjectFile::new("padding", main.endianness);
+        padding.code = vec![0; 200];
+        let error = Linker::new().add(main).add(padding).add(library).link().unwrap_err();
+        assert_eq!(error, "Displacement 203 does not fit in 1 byte(s) to 'add_one' in main");
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::computer::{Endianness, MachineConfig, Memory, Privilege, RegisterRole, Registers, Storage, CPU};
+use crate::devices::{create_device, DEVICE_KINDS};
+use crate::instructions::default_instruction_set;
+use crate::isa::parse_isa;
+
+// Machine description files.
+//
+// A line based text format listing the register file, memory and storage sizes, the memory map and the
+// instruction set, built in or read from a file, see machines/default.machine. Descriptions are validated as a whole before any CPU
+// is built, so an overlapping register or a missing program counter is reported with its line rather
+// than as a panic once the machine runs.
+//
+// let cpu = MachineDescription::load("machines/default.machine")?.build()?;
+//
+// Regions list the RAM of the memory map. When a description has any, the CPU raises a bus error for
+// physical addresses outside them, without any all of memory is RAM. A region with device=<kind> attaches
+// a device of that kind from devices::DEVICE_KINDS there instead, and may lie past the end of memory.
+
+pub const DEFAULT_MACHINE: &str = include_str!("../machines/default.machine");
+
+// The CPU cannot be built without these
+const REQUIRED_ROLES: &[RegisterRole] = &[RegisterRole::ProgramCounter, RegisterRole::Accumulator, RegisterRole::StackPointer];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterSpec {
+    pub name: String,
+    pub size: usize,
+    pub location: usize,
+    pub role: Option<RegisterRole>,
+    pub privilege: Privilege,
+    // Needed on top of privilege to write the register through an operand
+    pub write_privilege: Privilege,
+    // Views into another register have a parent and the bits they cover as (low, width), but no size or location
+    pub parent: Option<String>,
+    pub bits: Option<(usize, usize)>,
+    // Line of the description the register came from, for error messages
+    pub line: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: usize,
+    pub size: usize,
+    // Kind of device answering for the region, None for RAM
+    pub device: Option<String>,
+    pub line: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct MachineDescription {
+    pub config: MachineConfig,
+    pub memory_size: usize,
+    pub storage_size: usize,
+    pub registers: Vec<RegisterSpec>,
+    pub memory_map: Vec<Region>,
+    // "default" for the InstructionSet opcodes, otherwise the path of an instruction set file read by
+    // build, see isa.rs. None leaves the instruction set empty
+    pub isa: Option<String>,
+}
+
+impl MachineDescription {
+    // A relative instruction set path is taken from the description's directory
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+        let mut description = MachineDescription::parse(&text).map_err(|error| format!("{}: {}", path, error))?;
+        if let (Some(isa), Some(directory)) = (&description.isa, Path::new(path).parent()) {
+            if isa != "default" {
+                description.isa = Some(directory.join(isa).to_string_lossy().into_owned());
+            }
+        }
+        Ok(description)
+    }
+
+    // Parses and validates a description
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut description = MachineDescription {
+            config: MachineConfig::default(),
+            memory_size: 0,
+            storage_size: 0,
+            registers: Vec::new(),
+            memory_map: Vec::new(),
+            isa: None,
+        };
+        // Register sizes default to data_size, which may come later in the file
+        let mut sizes: Vec<Option<usize>> = Vec::new();
+        let mut locations: Vec<Option<usize>> = Vec::new();
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let content = raw.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = content.split_whitespace().collect();
+            let Some((keyword, args)) = words.split_first() else {
+                continue;
+            };
+            let error = |message: String| format!("line {}: {}", line, message);
+            let single = || match args {
+                [value] => Ok(*value),
+                _ => Err(error(format!("{} takes one value", keyword))),
+            };
+            match *keyword {
+                "data_size" => {
+                    let size = parse_number(single()?).map_err(error)?;
+                    description.config.data_size = u8::try_from(size)
+                        .ok()
+                        .filter(|size| *size > 0)
+                        .ok_or_else(|| error(format!("data_size {} outside 1..=255", size)))?;
+                }
+                "endianness" => {
+                    description.config.endianness = match single()? {
+                        "big" => Endianness::Big,
+                        "little" => Endianness::Little,
+                        other => return Err(error(format!("unknown endianness '{}'", other))),
+                    };
+                }
+                "memory" => description.memory_size = parse_number(single()?).map_err(error)?,
+                "storage" => description.storage_size = parse_number(single()?).map_err(error)?,
+                "isa" => description.isa = Some(single()?.to_string()),
+                "register" => {
+                    let Some((name, attributes)) = args.split_first() else {
+                        return Err(error("register needs a name".to_string()));
+                    };
+                    let mut spec = RegisterSpec {
+                        name: name.to_string(),
+                        size: 0,
+                        location: 0,
+                        role: None,
+                        privilege: Privilege::User,
+                        write_privilege: Privilege::User,
+                        parent: None,
+                        bits: None,
+                        line,
+                    };
+                    let (mut size, mut location) = (None, None);
+                    for attribute in attributes {
+                        let (key, value) = attribute
+                            .split_once('=')
+                            .ok_or_else(|| error(format!("expected key=value, got '{}'", attribute)))?;
+                        match key {
+                            "size" => size = Some(parse_number(value).map_err(error)?),
+                            "location" => location = Some(parse_number(value).map_err(error)?),
+                            "parent" => spec.parent = Some(value.to_string()),
+                            "bits" => {
+                                let (low, high) = value
+                                    .split_once("..")
+                                    .ok_or_else(|| error(format!("expected bits=<low>..<high>, got '{}'", value)))?;
+                                let (low, high) = (parse_number(low).map_err(error)?, parse_number(high).map_err(error)?);
+                                if high <= low {
+                                    return Err(error(format!("bits {}..{} are empty", low, high)));
+                                }
+                                spec.bits = Some((low, high - low));
+                            }
+                            "role" => {
+                                let role = RegisterRole::from_name(value).ok_or_else(|| {
+                                    let names: Vec<&str> = RegisterRole::ALL.iter().map(RegisterRole::name).collect();
+                                    error(format!("unknown role '{}', expected one of {}", value, names.join(", ")))
+                                })?;
+                                spec.role = Some(role);
+                            }
+                            "privilege" => spec.privilege = parse_privilege(value).map_err(error)?,
+                            "write_privilege" => spec.write_privilege = parse_privilege(value).map_err(error)?,
+                            other => return Err(error(format!("unknown register attribute '{}'", other))),
+                        }
+                    }
+                    if spec.parent.is_some() != spec.bits.is_some() {
+                        return Err(error(format!("register {} needs both parent and bits to be a view", spec.name)));
+                    }
+                    if spec.parent.is_some() && (size.is_some() || location.is_some()) {
+                        return Err(error(format!("view {} takes its size and location from its parent", spec.name)));
+                    }
+                    description.registers.push(spec);
+                    sizes.push(size);
+                    locations.push(location);
+                }
+                "region" => {
+                    let [name, start, size, attributes @ ..] = args else {
+                        return Err(error("region needs a name, start and size".to_string()));
+                    };
+                    let device = match attributes {
+                        [] => None,
+                        [attribute] => match attribute.split_once('=') {
+                            Some(("device", kind)) if DEVICE_KINDS.contains(&kind) => Some(kind.to_string()),
+                            Some(("device", kind)) => {
+                                return Err(error(format!("unknown device '{}', expected one of {}", kind, DEVICE_KINDS.join(", "))))
+                            }
+                            _ => return Err(error(format!("unknown region attribute '{}'", attribute))),
+                        },
+                        _ => return Err(error("region takes at most one attribute".to_string())),
+                    };
+                    description.memory_map.push(Region {
+                        name: name.to_string(),
+                        start: parse_number(start).map_err(error)?,
+                        size: parse_number(size).map_err(error)?,
+                        device,
+                        line,
+                    });
+                }
+                other => return Err(error(format!("unknown keyword '{}'", other))),
+            }
+        }
+        let mut next_location = 0;
+        for ((spec, size), location) in description.registers.iter_mut().zip(sizes).zip(locations) {
+            if spec.parent.is_some() {
+                continue;
+            }
+            spec.size = size.unwrap_or(description.config.data_size as usize);
+            spec.location = location.unwrap_or(next_location);
+            // An overflow is reported by validate
+            next_location = spec.location.saturating_add(spec.size);
+        }
+        description.validate()?;
+        Ok(description)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.registers.len() > u8::MAX as usize + 1 {
+            return Err(format!("{} registers, ids only go up to {}", self.registers.len(), u8::MAX));
+        }
+        let mut names = HashMap::new();
+        let mut roles = HashMap::new();
+        for spec in &self.registers {
+            let error = |message: String| format!("line {}: {}", spec.line, message);
+            if let (Some(parent), Some((low, width))) = (&spec.parent, spec.bits) {
+                let Some(parent_spec) = self.registers.iter().find(|other| other.line < spec.line && other.name == *parent) else {
+                    return Err(error(format!("view {} needs its parent {} defined before it", spec.name, parent)));
+                };
+                if parent_spec.parent.is_some() {
+                    return Err(error(format!("view {} has a view, {}, as its parent", spec.name, parent)));
+                }
+                if low.checked_add(width).is_none_or(|end| end > parent_spec.size * 8) {
+                    return Err(error(format!("bits {}..{} of view {} are outside the {} bit {}", low, low.saturating_add(width), spec.name, parent_spec.size * 8, parent)));
+                }
+            } else if spec.size == 0 {
+                return Err(error(format!("register {} has size 0", spec.name)));
+            } else if spec.location.checked_add(spec.size).is_none() {
+                return Err(error(format!("register {} of size {} at {} ends past the largest address", spec.name, spec.size, spec.location)));
+            }
+            if let Some(first) = names.insert(spec.name.as_str(), spec.line) {
+                return Err(error(format!("register {} already defined on line {}", spec.name, first)));
+            }
+            if let Some(role) = spec.role {
+                if let Some(first) = roles.insert(role, spec.line) {
+                    return Err(error(format!("role {} already given on line {}", role.name(), first)));
+                }
+            }
+            // Views overlap their parent by design
+            let overlap = self.registers.iter().filter(|_| spec.parent.is_none()).find(|other| {
+                other.parent.is_none() && other.line < spec.line && other.location < spec.location + spec.size && spec.location < other.location + other.size
+            });
+            if let Some(other) = overlap {
+                return Err(error(format!(
+                    "register {} at {}..{} overlaps {} at {}..{}",
+                    spec.name, spec.location, spec.location + spec.size, other.name, other.location, other.location + other.size
+                )));
+            }
+        }
+        for role in REQUIRED_ROLES {
+            if !roles.contains_key(role) {
+                return Err(format!("no register has the {} role", role.name()));
+            }
+        }
+        for region in &self.memory_map {
+            let error = |message: String| format!("line {}: {}", region.line, message);
+            let Some(end) = region.start.checked_add(region.size) else {
+                return Err(error(format!("region {} of size {} at {} ends past the largest address", region.name, region.size, region.start)));
+            };
+            if region.size == 0 || (region.device.is_none() && end > self.memory_size) {
+                return Err(error(format!("region {} at {}..{} is outside the {} byte memory", region.name, region.start, end, self.memory_size)));
+            }
+            let overlap = self.memory_map.iter().find(|other| {
+                other.line < region.line && other.start < region.start + region.size && region.start < other.start + other.size
+            });
+            if let Some(other) = overlap {
+                return Err(error(format!("region {} overlaps {}", region.name, other.name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build_registers(&self) -> Result<Registers, String> {
+        let mut registers = Registers::with_endianness(self.config.endianness);
+        for spec in &self.registers {
+            match (&spec.parent, spec.bits) {
+                (Some(parent), Some((low, width))) => {
+                    registers.add_sub_register(spec.name.clone(), parent, low, width)?;
+                    // A view is never less privileged than its parent
+                    let (read, write) = registers.look_up_string(parent).map_or((Privilege::User, Privilege::User), |reg| (reg.privilege, reg.write_privilege));
+                    registers.set_privilege(&spec.name, spec.privilege.max(read))?;
+                    registers.set_write_privilege(&spec.name, spec.write_privilege.max(write))?;
+                }
+                _ => {
+                    registers.add_register(spec.name.clone(), spec.size, spec.location);
+                    registers.set_privilege(&spec.name, spec.privilege)?;
+                    registers.set_write_privilege(&spec.name, spec.write_privilege)?;
+                }
+            }
+        }
+        for spec in &self.registers {
+            if let Some(role) = spec.role {
+                registers.set_role(&spec.name, role)?;
+            }
+        }
+        Ok(registers)
+    }
+
+    pub fn build(&self) -> Result<CPU, String> {
+        let memory = Memory::new(self.memory_size);
+        let storage = Storage::new(self.storage_size);
+        let mut cpu = CPU::with_config(self.build_registers()?, memory, storage, self.config)?;
+        let ram: Vec<Range<usize>> = self.memory_map.iter().filter(|region| region.device.is_none()).map(|region| region.start..region.start + region.size).collect();
+        if !ram.is_empty() {
+            cpu.memory_map = Some(ram);
+        }
+        for region in &self.memory_map {
+            if let Some(kind) = &region.device {
+                let device = create_device(kind).ok_or_else(|| format!("unknown device '{}'", kind))?;
+                cpu.attach_device(&region.name, region.start..region.start + region.size, device)?;
+            }
+        }
+        match self.isa.as_deref() {
+            None => {}
+            Some("default") => {
+                let instruction_set = default_instruction_set(&cpu.registers, self.config.data_size, self.config.endianness)?;
+                cpu.set_instruction_set(instruction_set);
+            }
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+                let instruction_set = parse_isa(&text, &cpu.registers, self.config.data_size).map_err(|error| format!("{}: {}", path, error))?;
+                cpu.set_instruction_set(instruction_set);
+            }
+        }
+        Ok(cpu)
+    }
+}
+
+fn parse_privilege(text: &str) -> Result<Privilege, String> {
+    match text {
+        "user" => Ok(Privilege::User),
+        "supervisor" => Ok(Privilege::Supervisor),
+        other => Err(format!("unknown privilege '{}'", other)),
+    }
+}
+
+// Decimal, or hexadecimal with a 0x prefix
+pub(crate) fn parse_number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a number", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computer::{create_default_cpu, AccessKind, Fault};
+    use crate::devices::Console;
+
+    #[test]
+    fn default_description_matches_default_cpu() {
+        let cpu = MachineDescription::parse(DEFAULT_MACHINE).unwrap().build().unwrap();
+        let expected = create_default_cpu(64, 0);
+        assert_eq!(cpu.registers.total_length, expected.registers.total_length);
+        for (name, id) in &expected.registers.string_reference {
+            let register = cpu.registers.look_up_string(name).unwrap();
+            let expected_register = expected.registers.look_up_u8(*id).unwrap();
+            assert_eq!(
+                (register.size, register.location, register.privilege, register.write_privilege),
+                (expected_register.size, expected_register.location, expected_register.privilege, expected_register.write_privilege),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(cpu.instruction_set.len(), crate::InstructionSet::ALL.len());
+    }
+
+    #[test]
+    fn roles_find_renamed_registers() {
+        let description = MachineDescription::parse(
+            "memory 32\nregister pc size=2 role=program_counter\nregister acc size=2 role=accumulator\nregister sp size=2 role=stack_pointer\n",
+        ).unwrap();
+        let mut cpu = description.build().unwrap();
+        assert_eq!(cpu.read_register_value_string("sp"), Some(32u8.into()));
+        cpu.set_program_counter(7u8.into());
+        assert_eq!(cpu.read_register_value_string("pc"), Some(7u8.into()));
+    }
+
+    #[test]
+    fn views_share_their_parents_bytes() {
+        let text = "memory 32\nregister pc size=2 role=program_counter\nregister acc size=2 role=accumulator privilege=supervisor\n\
+                    register sp size=2 role=stack_pointer\nregister acc_high parent=acc bits=8..16\nregister carry parent=acc bits=0..1\n";
+        let description = MachineDescription::parse(text).unwrap();
+        assert_eq!(description.validate(), Ok(()));
+        let mut cpu = description.build().unwrap();
+        // Views take no space of their own
+        assert_eq!(cpu.registers.total_length, 6);
+        assert_eq!(cpu.registers.look_up_string("carry").unwrap().privilege, Privilege::Supervisor);
+        assert_eq!(cpu.registers.look_up_string("carry").unwrap().write_privilege, Privilege::Supervisor);
+        cpu.set_register_value_string("acc", 0xab01u32.into()).unwrap();
+        assert_eq!(cpu.read_register_value_string("acc_high"), Some(0xabu8.into()));
+        assert_eq!(cpu.read_register_value_string("carry"), Some(1u8.into()));
+
+        let outside = MachineDescription::parse(&text.replace("bits=0..1", "bits=12..20"));
+        assert!(outside.unwrap_err().contains("outside"));
+        let before_parent = MachineDescription::parse(&format!("register early parent=acc bits=0..8\n{}", text));
+        assert!(before_parent.unwrap_err().contains("defined before"));
+        assert!(MachineDescription::parse("register half parent=acc bits=0..8 size=1\n").is_err());
+    }
+
+    #[test]
+    fn addresses_outside_the_regions_are_bus_errors() {
+        let text = "memory 32\nregister pc role=program_counter\nregister acc role=accumulator\nregister sp role=stack_pointer\n\
+                    region low 0 16\nregion high 24 8\n";
+        let mut cpu = MachineDescription::parse(text).unwrap().build().unwrap();
+        assert_eq!(cpu.write_memory(14, &[1, 2]), Ok(()));
+        assert_eq!(cpu.write_memory(15, &[1, 2]), Err(Fault::BusError { address: 16 }));
+        assert_eq!(cpu.read_memory(24, 8, AccessKind::Read), Ok(vec![0; 8]));
+        assert_eq!(cpu.read_memory(20, 1, AccessKind::Read), Err(Fault::BusError { address: 20 }));
+        // Without regions all of memory is RAM
+        let mut cpu = MachineDescription::parse(&text.replace("region", "# region")).unwrap().build().unwrap();
+        assert_eq!(cpu.read_memory(20, 1, AccessKind::Read), Ok(vec![0]));
+    }
+
+    #[test]
+    fn instruction_sets_load_from_files() {
+        let mut cpu = MachineDescription::load("machines/minimal.machine").unwrap().build().unwrap();
+        assert_eq!(cpu.instruction_set.len(), 4);
+        // r0 counts to 5 in steps of r1, jumping back through the address in r2
+        let (r0, r1, r2) = (6, 7, 8);
+        cpu.memory.write_chunk(0, &[1, r1, 1, 1, r2, 6, 2, r0, r1, 3, r2, r0, 5, 0]);
+        assert_eq!(cpu.run_until_halt(1000), crate::StopReason::Halted);
+        assert_eq!(cpu.read_register_value_string("r0"), Some(5u8.into()));
+
+        let mut description = MachineDescription::load("machines/minimal.machine").unwrap();
+        description.isa = Some("machines/missing.isa".to_string());
+        assert!(description.build().err().unwrap().starts_with("machines/missing.isa: "));
+        description.registers.retain(|spec| spec.name != "left");
+        description.isa = Some("machines/minimal.isa".to_string());
+        assert!(description.build().err().unwrap().starts_with("machines/minimal.isa: line 19: "));
+    }
+
+    #[test]
+    fn device_regions_attach_devices() {
+        // The console sits past the end of memory, RAM accesses still go to memory
+        let text = "memory 32\nregister pc role=program_counter\nregister acc role=accumulator\nregister sp role=stack_pointer\n\
+                    region ram 0 32\nregion uart 0x40 1 device=console\n";
+        let mut cpu = MachineDescription::parse(text).unwrap().build().unwrap();
+        cpu.device_mut::<Console>("uart").unwrap().input.extend(b"ok");
+        assert_eq!(cpu.write_memory(0x40, b"hi"), Err(Fault::BusError { address: 0x41 }));
+        assert_eq!(cpu.write_memory(0x40, b"h"), Ok(()));
+        assert_eq!(cpu.write_memory(0x40, b"i"), Ok(()));
+        assert_eq!(cpu.read_memory(0x40, 1, AccessKind::Read), Ok(vec![b'o']));
+        assert_eq!(cpu.read_memory(0x40, 1, AccessKind::Read), Ok(vec![b'k']));
+        assert_eq!(cpu.read_memory(0x40, 1, AccessKind::Read), Ok(vec![0]));
+        assert_eq!(cpu.device::<Console>("uart").unwrap().output, b"hi");
+        assert_eq!(cpu.memory.read_chunk(0, 32), &[0; 32]);
+        assert!(cpu.attach_device("other", 0x40..0x48, Box::new(Console::default())).unwrap_err().contains("clashes with device uart"));
+    }
+
+    #[test]
+    fn default_microcode_follows_roles() {
+        // The ALU inputs and the program counter get new names, and the program counter moves to the end
+        let text = DEFAULT_MACHINE
+            .replace("register reg_a ", "register left ")
+            .replace("register reg_b ", "register right ")
+            .replace("register program_counter role=program_counter\n", "")
+            + "register ip size=2 role=program_counter\n";
+        let mut cpu = MachineDescription::parse(&text).unwrap().build().unwrap();
+        assert_eq!(cpu.registers.role_of(cpu.registers.id_of("ip").unwrap()), Some(RegisterRole::ProgramCounter));
+        let reg_0 = cpu.registers.id_of("reg_0").unwrap();
+        let reg_1 = cpu.registers.id_of("reg_1").unwrap();
+        let mut program = crate::ProgramWriter::new(cpu.instruction_set.clone(), &cpu.registers);
+        program.add_instruction(crate::InstructionSet::LoadImmediate, &[reg_0, 20])
+            .add_instruction(crate::InstructionSet::LoadImmediate, &[reg_1, 22])
+            .add_instruction(crate::InstructionSet::AddReg, &[reg_0, reg_1])
+            .add_instruction(crate::InstructionSet::Halt, &[]);
+        cpu.memory.write_chunk(0, &program.build());
+        assert_eq!(cpu.run_until_halt(1000), crate::StopReason::Halted);
+        assert_eq!(cpu.read_register_value_string("reg_0"), Some(42u8.into()));
+        // Halt does not step past itself
+        assert_eq!(cpu.read_register_value_string("ip"), Some(9u8.into()));
+    }
+
+    #[test]
+    fn reports_invalid_descriptions() {
+        let error = |text: &str| MachineDescription::parse(text).unwrap_err();
+        let roles = "register pc role=program_counter\nregister acc role=accumulator\nregister sp role=stack_pointer\n";
+        assert_eq!(
+            error("register pc role=program_counter\nregister acc location=0 role=accumulator\n"),
+            "line 2: register acc at 0..1 overlaps pc at 0..1"
+        );
+        assert_eq!(error("register pc role=program_counter\nregister sp role=stack_pointer\n"), "no register has the accumulator role");
+        assert!(error(&format!("{}register x role=pc\n", roles)).starts_with("line 4: unknown role 'pc', expected one of program_counter, accumulator"));
+        assert_eq!(error(&format!("memory 16\n{}region ram 8 16\n", roles)), "line 5: region ram at 8..24 is outside the 16 byte memory");
+        assert_eq!(error("data_size lots\n"), "line 1: 'lots' is not a number");
+        assert_eq!(error(&format!("{}region uart 8 4 device=uart\n", roles)), "line 4: unknown device 'uart', expected one of console");
+        assert_eq!(error(&format!("{}region uart 8 4 speed=9600\n", roles)), "line 4: unknown region attribute 'speed=9600'");
+        // Ends past usize::MAX
+        assert_eq!(
+            error(&format!("{}register x location=0xffffffffffffffff size=2\n", roles)),
+            "line 4: register x of size 2 at 18446744073709551615 ends past the largest address"
+        );
+        assert_eq!(
+            error(&format!("memory 16\n{}region r 0xffffffffffffffff 2\n", roles)),
+            "line 5: region r of size 2 at 18446744073709551615 ends past the largest address"
+        );
+        // Microcode names registers the description must provide, trapping included
+        let missing = MachineDescription::parse(&format!("isa default\n{}", roles)).unwrap().build().err().unwrap();
+        assert!(missing.starts_with("Instruction set needs register role(s): operand_a, operand_b, scratch"), "{}", missing);
+        let alu = "register flags size=1 role=flags\nregister mar role=memory_address\nregister a role=operand_a\nregister b role=operand_b\nregister c role=scratch\nregister bp role=base_pointer\n";
+        let missing = MachineDescription::parse(&format!("isa default\n{}{}", roles, alu)).unwrap().build().err().unwrap();
+        assert!(missing.contains("opcode 30 step 0: no register has the trap_vector role"), "{}", missing);
+        assert!(missing.contains("opcode 31 step 0: no register has the supervisor_stack_pointer role"), "{}", missing);
+    }
+}
@@ -1,92 +1,55 @@
-// use computer::*;
+// Demo that single steps a small program, printing the machine after every clock
 use colored::*;
-mod computer;
-mod writers;
-mod instructions;
-
-use computer::{Memory, Storage, CPU, Instruction, SubInstructions};
+use rust_computer_sim::computer::{Instruction, Operand};
+use rust_computer_sim::machine::MachineDescription;
+use rust_computer_sim::{create_machine, InstructionSet, MachineConfig, ProgramWriter, StopReason, CPU};
 use num_bigint::BigUint;
 use num_traits::{One, Zero};
-use std::{collections::HashMap, mem, net::AddrParseError, time::Duration};
-use instructions::add_instructions;
-// Define a constant for the sub_instructions Vec
+use std::time::Duration;
 
-#[repr(u8)]
-#[derive(Hash, Eq, PartialEq, Debug)]
-enum InstructionSet {
-    NoOperation,        // No args
-    Halt,               // No args
-    LoadFromMemory,     // #Addr #Reg
-    StoreToMemory,      // #Reg #Addr
-    LoadFromMemoryReg,  // #Reg/Addr #Reg
-    StoreToMemoryReg,   // #Reg #Reg/Addr
-    AddImmediate,       // #Reg #Imm
-    AddReg,             // #Reg #Reg
-    SubImmediate,       // #Reg #Imm
-    SubReg,             // #Reg #Reg
-    LoadImmediate,      // #Reg #Imm
-    MoveRegister,       // #Reg #Reg
-    PushImmediate,      // #Imm
-    PushReg,            // #Reg
-    PopReg,             // #Reg
-    Jump,               // #Addr
-    JumpEqual,          // #Reg/Addr #Reg (A) #Imm (B)
-    JumpNotEqual,       // #Reg/Addr #Reg (A) #Imm (B)
-    JumpGreaterThan,    // #Reg/Addr #Reg (A) #Imm (B)
-    JumpLessThan,       // #Reg/Addr #Reg (A) #Imm (B)
-    JumpLessEqual,      // #Reg/Addr #Reg (A) #Imm (B)
-    JumpGreaterEqual,   // #Reg/Addr #Reg (A) #Imm (B)
-    JumpReg,            // #Reg/Addr
-    JumpEqualReg,       // #Reg/Addr #Reg (A) #Reg (B)
-    JumpNotEqualReg,    // #Reg/Addr #Reg (A) #Reg (B)
-    JumpGreaterThanReg, // #Reg/Addr #Reg (A) #Reg (B)
-    JumpLessThanReg,    // #Reg/Addr #Reg (A) #Reg (B)
-    JumpLessEqualReg,   // #Reg/Addr #Reg (A) #Reg (B)
-    JumpGreaterEqualReg,// #Reg/Addr #Reg (A) #Reg (B)
-    Return,             // No args
-}
-//
-// Jump sets the return_address
-//
+// Where the demo program is loaded, it only uses relative control flow so any address works
+const LOAD_ADDRESS: usize = 8;
 
-impl Into<u8> for InstructionSet {
-    fn into(self) -> u8 {
-        self as u8
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("{}", error);
+        std::process::exit(1);
     }
 }
 
-fn main() {
-    let mut cpu = computer::create_default_cpu(64, 0);
-    let mut ref_reg = |name: &str| {
-        cpu.registers.name_to_u8(&name)
+fn run() -> Result<(), String> {
+    // An optional machine description replaces the default machine
+    let mut cpu = match std::env::args().nth(1) {
+        Some(path) => MachineDescription::load(&path)?.build()?,
+        None => create_machine(64, 0, MachineConfig::default())?,
     };
-    let mut instruction_set_writer = writers::InstructionSetWriter::new();
-
-    add_instructions(&mut instruction_set_writer, &mut ref_reg, cpu.cpu_data_size);
+    let reg_0 = cpu.registers.id_of("reg_0")?;
+    let reg_1 = cpu.registers.id_of("reg_1")?;
+    let reg_2 = cpu.registers.id_of("reg_2")?;
 
-    let reg_0 = ref_reg("reg_0");
-    let reg_1 = ref_reg("reg_1");
-    let reg_2 = ref_reg("reg_2");
-
-    cpu.set_instruction_set(instruction_set_writer.build());
-
-    let mut program_writer = writers::ProgramWriter::new(cpu.instruction_set.clone());
+    let mut program_writer = ProgramWriter::new(cpu.instruction_set.clone(), &cpu.registers);
+    program_writer.set_endianness(cpu.endianness);
 
     program_writer
-        .add_instruction(InstructionSet::LoadImmediate, &[reg_0, 0])    // Load 0 into reg_0
-        .add_instruction(InstructionSet::LoadImmediate, &[reg_1, 1]);    // Load 1 into reg_1
+        .try_add_instruction(InstructionSet::LoadImmediate, &[reg_0, 0])?    // Load 0 into reg_0
+        .try_add_instruction(InstructionSet::LoadImmediate, &[reg_1, 1])?;   // Load 1 into reg_1
     
     program_writer
-        .add_instruction(InstructionSet::PushImmediate, &[1])
-        .add_instruction(InstructionSet::PushImmediate, &[2])
-        .add_instruction(InstructionSet::PushImmediate, &[3])
-        .add_instruction(InstructionSet::PopReg, &[reg_2]);
+        .try_add_instruction(InstructionSet::PushImmediate, &[1])?
+        .try_add_instruction(InstructionSet::PushImmediate, &[2])?
+        .try_add_instruction(InstructionSet::PushImmediate, &[3])?
+        .try_add_instruction(InstructionSet::PopReg, &[reg_2])?;
 
     program_writer
-        .add_instruction(InstructionSet::Halt, &[]);                                       // Halt the program
+        .try_label("countdown")?
+        .try_add_instruction(InstructionSet::SubImmediate, &[reg_2, 1])?                  // Count reg_2 down to 0
+        .try_add_relative_instruction(InstructionSet::BranchNotEqual, "countdown", &[reg_2 as u64, 0])?;
 
-    let program = program_writer.build();
-    cpu.memory.write_chunk(0, program.as_slice());
+    program_writer
+        .try_add_instruction(InstructionSet::Halt, &[])?;                                  // Halt the program
+
+    let program = program_writer.try_build()?;
+    cpu.load_program(LOAD_ADDRESS, program.as_slice());
     let bytes_per_row = BigUint::from(8u32);
     let print_at_end_of_op = false;
     let clear_screen = true;
@@ -94,17 +57,15 @@ fn main() {
     let sleep_time_after_op = Duration::from_millis(1000);
     let sleep_time_after_sub_op = Duration::from_millis(100);
     let mut instruction = None;
-    let mut op_code_address = BigUint::from(0u32);
+    let mut op_code_address = BigUint::from(LOAD_ADDRESS);
 
     
-    for i in 1..50 {
-        cpu.clock();
+    for i in 1..200 {
+        let reason = cpu.run_for(1);
         
         if let Some(opcode) = cpu.current_opcode {
             if let Some(instruct) = cpu.instruction_set.get(&opcode) {
                 instruction.replace(instruct.clone());
-            } else {
-                panic!("Invalid opcode encountered: {}", opcode);
             }
         }else {
             // Update Op Code Address
@@ -117,21 +78,22 @@ fn main() {
             wait_after_step(cpu.current_opcode.is_none(), sleep_time_after_op, sleep_time_after_sub_op);
         }
 
-        if cpu.is_halted() {
+        if reason != StopReason::CycleLimit {
+            println!("Stopped: {:?}", reason);
             break;
         }
     }
+
+    println!("\nCycles: {}", cpu.cycles);
+    for (opcode, timing) in cpu.timing_report.rows() {
+        println!("Opcode {:>2}: {} retired, {} cycles, CPI {:.1}", opcode, timing.retired, timing.cycles, timing.cpi());
+    }
+    Ok(())
 }
 
 fn print_status(cpu: &CPU, i: u32, print_at_end_of_op: bool, clear_screen: bool, instruction: Option<&Instruction>, op_code_address: &BigUint, bytes_per_row: &BigUint) {
     let pc_color = Color::BrightYellow;
     let op_code_color = Color::Red;
-    let arg_colors = vec![
-        Color::Blue,
-        Color::Green,
-        Color::Yellow,
-        Color::Cyan
-    ];
     if !print_at_end_of_op || (cpu.current_opcode.is_none()){
         if clear_screen {
             clearscreen::clear().expect("failed to clear screen");
@@ -140,17 +102,40 @@ fn print_status(cpu: &CPU, i: u32, print_at_end_of_op: bool, clear_screen: bool,
         let accumulator = cpu.get_accumulator();
         println!("Cycle {}", i);
         println!("Halted: {}", cpu.is_halted());
+        println!("Fault: {:?}", cpu.fault);
+        println!("Privilege: {:?}", cpu.privilege);
+        if let Some(cache) = &cpu.cache {
+            let stats = cache.stats;
+            println!("Cache: {} hits, {} misses, {} evictions, {} memory writes, {:.1}% hit rate",
+                stats.hits, stats.misses, stats.evictions, stats.memory_writes, stats.hit_rate() * 100.0);
+        }
         println!("Program Counter: {}", counter);
         println!("Current Opcode: {:?}", cpu.current_opcode);
         println!("Current Sub Step: {} / {}", cpu.current_sub_step, instruction.map_or(0, |instr| instr.sub_instructions.len()));
         println!("Accumulator: {:?}", accumulator);
-        println!("Registers: {:?}", cpu.register_data);
-        let memory_snapshot = cpu.memory.read_chunk(0, 64);
+        println!("Registers:");
+        for id in 0..cpu.registers.registers.len() as u8 {
+            let register = cpu.registers.registers[id as usize];
+            if register.parent.is_some() {
+                continue;
+            }
+            // Views are listed after the register they look into
+            let views: Vec<String> = cpu.registers.children(id).into_iter()
+                .map(|child| format!("{}={}", cpu.registers.name_of(child).unwrap_or("?"), cpu.value(cpu.registers.registers[child as usize])))
+                .collect();
+            let name = cpu.registers.name_of(id).unwrap_or("?");
+            if views.is_empty() {
+                println!("  {}={}", name, cpu.value(register));
+            } else {
+                println!("  {}={} ({})", name, cpu.value(register), views.join(", "));
+            }
+        }
+        let memory_snapshot = cpu.memory.read_chunk(0, cpu.memory.size().min(64));
         println!("Memory Snapshot:\n");
         let mut arg_index = 0;
-        let arg_count = match instruction {
-            Some(ref instr) => instr.args,
-            None => 0,
+        let operand_layout = match instruction {
+            Some(instr) => instr.operand_layout(),
+            None => Vec::new(),
         };
         let mut memory_counter = BigUint::zero();
         for byte in memory_snapshot{
@@ -160,9 +145,9 @@ fn print_status(cpu: &CPU, i: u32, print_at_end_of_op: bool, clear_screen: bool,
             }
             if &memory_counter == op_code_address {
                 byte_text.fgcolor = Some(op_code_color);
-            }else if &memory_counter > op_code_address && arg_index < arg_count as usize {
-                byte_text.fgcolor = Some(arg_colors[arg_index]);
-                arg_index = (arg_index + 1) % arg_colors.len();
+            }else if &memory_counter > op_code_address && arg_index < operand_layout.len() {
+                byte_text.fgcolor = Some(operand_color(operand_layout[arg_index]));
+                arg_index += 1;
             }
             memory_counter += BigUint::one();
             if &memory_counter % bytes_per_row == BigUint::zero() {
@@ -171,10 +156,26 @@ fn print_status(cpu: &CPU, i: u32, print_at_end_of_op: bool, clear_screen: bool,
                 print!("{}", byte_text);
             }
         }
+        println!(
+            "Operands: {} {} {} {}",
+            "Reg".color(operand_color(Operand::Reg)),
+            "Imm".color(operand_color(Operand::Imm)),
+            "Addr".color(operand_color(Operand::Addr)),
+            "Reg/Addr".color(operand_color(Operand::RegOrAddr))
+        );
         println!("Reg 0: {:?}, Reg 1: {:?}, Reg 2: {:?}\n", cpu.read_register_string("reg_0"), cpu.read_register_string("reg_1"), cpu.read_register_string("reg_2"));
     }
 }
 
+fn operand_color(operand: Operand) -> Color {
+    match operand {
+        Operand::Reg => Color::Blue,
+        Operand::Imm8 | Operand::Imm16 | Operand::Imm => Color::Green,
+        Operand::Addr => Color::Yellow,
+        Operand::RegOrAddr => Color::Cyan,
+    }
+}
+
 fn wait_after_step(op_step: bool, sleep_time_after_op: Duration, sleep_time_after_sub_op: Duration) {
     if op_step {
         if sleep_time_after_op > Duration::from_millis(0) {
@@ -0,0 +1,152 @@
+use num_traits::ToPrimitive;
+
+use crate::computer::{AccessKind, Endianness, Fault, Memory};
+
+// Single level paging.
+//
+// The page table lives in guest memory at the physical address held in `page_table_base`, with one
+// cpu_data_size entry per virtual page. An entry holds the page aligned physical address of the
+// frame, with the permission bits below stored in its low bits.
+//
+// Entry | frame address ... | EXECUTE | WRITE | READ | PRESENT |
+
+pub const PAGE_PRESENT: usize = 0b0001;
+pub const PAGE_READ: usize    = 0b0010;
+pub const PAGE_WRITE: usize   = 0b0100;
+pub const PAGE_EXECUTE: usize = 0b1000;
+pub const PAGE_FLAGS: usize   = 0b1111;
+
+#[derive(Clone, Debug)]
+pub struct Mmu {
+    pub page_size: usize,
+}
+
+impl Mmu {
+    pub fn new(page_size: usize) -> Self {
+        assert!(page_size.is_power_of_two() && page_size > PAGE_FLAGS, "Page size must be a power of two above {}", PAGE_FLAGS);
+        Mmu { page_size }
+    }
+
+    // Builds the entry value for a frame, to be written into the page table
+    pub fn entry(&self, frame_address: usize, flags: usize) -> usize {
+        assert!(frame_address.is_multiple_of(self.page_size), "Frame address {} is not page aligned", frame_address);
+        frame_address | (flags & PAGE_FLAGS)
+    }
+
+    pub fn translate(
+        &self,
+        memory: &Memory,
+        page_table_base: usize,
+        entry_size: usize,
+        endianness: Endianness,
+        address: usize,
+        access: AccessKind,
+    ) -> Result<usize, Fault> {
+        let page = address / self.page_size;
+        let offset = address % self.page_size;
+        let entry_address = page_table_base + page * entry_size;
+        if entry_address + entry_size > memory.size() {
+            return Err(Fault::BusError { address: entry_address });
+        }
+        let entry = endianness
+            .decode(memory.read_chunk(entry_address, entry_address + entry_size))
+            .to_usize()
+            .ok_or(Fault::BusError { address: entry_address })?;
+        let needed = match access {
+            AccessKind::Read => PAGE_READ,
+            AccessKind::Write => PAGE_WRITE,
+            AccessKind::Execute => PAGE_EXECUTE,
+        };
+        if entry & PAGE_PRESENT == 0 || entry & needed == 0 {
+            return Err(Fault::PageFault { address, access });
+        }
+        Ok((entry & !(self.page_size - 1)) + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::InstructionSet;
+
+    const PAGE_TABLE: usize = 0xe0;
+
+    // 256 bytes of memory in 16 byte pages, the page table sits in the last page
+    fn paged_machine() -> TestMachine {
+        let mut machine = TestMachine::new(256);
+        machine.cpu.mmu = Some(Mmu::new(16));
+        machine.set_register("page_table_base", PAGE_TABLE as u64);
+        machine
+    }
+
+    fn map(machine: &mut TestMachine, page: usize, frame: usize, flags: usize) {
+        let entry = machine.cpu.mmu.as_ref().unwrap().entry(frame * 16, flags);
+        machine.load(PAGE_TABLE + page, &[entry as u8]);
+    }
+
+    #[test]
+    fn translates_through_page_table() {
+        let mut machine = paged_machine();
+        let reg_0 = machine.reg("reg_0");
+        map(&mut machine, 0, 0, PAGE_PRESENT | PAGE_READ | PAGE_EXECUTE);
+        // Virtual page 3 is backed by physical frame 9
+        map(&mut machine, 3, 9, PAGE_PRESENT | PAGE_READ | PAGE_WRITE);
+        machine.load(0x95, &[0x42]);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadFromMemory, &[0x35, reg_0])
+                .add_instruction(InstructionSet::StoreToMemory, &[reg_0, 0x36])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().halted(true).fault(None).register("reg_0", 0x42).memory(0x95, &[0x42, 0x42]).check();
+    }
+
+    #[test]
+    fn write_to_read_only_page_faults() {
+        let mut machine = paged_machine();
+        let reg_0 = machine.reg("reg_0");
+        map(&mut machine, 0, 0, PAGE_PRESENT | PAGE_READ | PAGE_EXECUTE);
+        map(&mut machine, 3, 9, PAGE_PRESENT | PAGE_READ);
+        machine.set_register("reg_0", 7);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::StoreToMemory, &[reg_0, 0x36])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect()
+            .halted(false)
+            .fault(Some(Fault::PageFault { address: 0x36, access: AccessKind::Write }))
+            .register("fault_address", 0x36)
+            .memory(0x96, &[0])
+            .check();
+    }
+
+    #[test]
+    fn fetch_from_page_without_execute_faults() {
+        let mut machine = paged_machine();
+        map(&mut machine, 0, 0, PAGE_PRESENT | PAGE_READ);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        machine.expect().fault(Some(Fault::PageFault { address: 0, access: AccessKind::Execute })).check();
+    }
+
+    #[test]
+    fn unmapped_stack_faults() {
+        let mut machine = paged_machine();
+        map(&mut machine, 0, 0, PAGE_PRESENT | PAGE_READ | PAGE_EXECUTE);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::PushImmediate, &[1])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(50);
+        // The stack pointer wraps from 0 to the end of memory, so the push lands on page 15
+        machine.expect()
+            .fault(Some(Fault::PageFault { address: 0xff, access: AccessKind::Write }))
+            .register("fault_address", 0xff)
+            .register("stack_pointer", 0)
+            .check();
+    }
+}
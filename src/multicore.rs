@@ -0,0 +1,193 @@
+use crate::computer::{Memory, RegisterRole, CPU};
+
+// Several cores sharing one Memory.
+//
+// Each core keeps its own registers, MMU, cache and so on. The shared memory is moved into a core for
+// the clock it runs and moved back out afterwards, so a parked core's own `memory` is empty. Cores are
+// interleaved one clock at a time, a micro-op is never split, which makes the single micro-op atomics
+// (CompareAndSwap, FetchAdd) atomic while ordinary load, add, store sequences can race.
+//
+// Scheduling is fully deterministic, the same program and schedule always interleave the same way.
+
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    // Each running core gets quantum clocks in turn
+    RoundRobin { quantum: u32 },
+    // Core indices to clock, repeated from the start once exhausted, stopped cores are skipped
+    Pattern(Vec<usize>),
+}
+
+pub struct MultiCore {
+    pub cores: Vec<CPU>,
+    pub memory: Memory,
+    pub schedule: Schedule,
+    pub cycles: u64,
+    position: usize,
+    quantum_used: u32,
+}
+
+impl MultiCore {
+    // Replaces each core's own memory with the shared one and numbers the cores in core_id
+    pub fn new(memory: Memory, mut cores: Vec<CPU>, schedule: Schedule) -> Self {
+        assert!(!cores.is_empty(), "A machine needs at least one core");
+        match &schedule {
+            Schedule::RoundRobin { quantum } => assert!(*quantum > 0, "Quantum must be at least one clock"),
+            Schedule::Pattern(pattern) => assert!(
+                !pattern.is_empty() && pattern.iter().all(|core| *core < cores.len()),
+                "Pattern must name existing cores"
+            ),
+        }
+        for (index, core) in cores.iter_mut().enumerate() {
+            core.memory = Memory::new(0);
+            let core_id = core.role(RegisterRole::CoreId).expect("Core id register not found.");
+            core.set_word(core_id, index as u64);
+        }
+        MultiCore { cores, memory, schedule, cycles: 0, position: 0, quantum_used: 0 }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.cores.iter().all(|core| core.is_halted() || core.is_faulted())
+    }
+
+    // Clocks the next core in the schedule, returns its index, or None once every core has stopped
+    pub fn clock(&mut self) -> Option<usize> {
+        if self.is_stopped() {
+            return None;
+        }
+        let index = self.next_core();
+        let core = &mut self.cores[index];
+        std::mem::swap(&mut self.memory, &mut core.memory);
+        core.clock();
+        std::mem::swap(&mut self.memory, &mut core.memory);
+        self.cycles += 1;
+        Some(index)
+    }
+
+    // Clocks until every core stops or max_cycles is reached, returns the clocks used
+    pub fn run(&mut self, max_cycles: u64) -> u64 {
+        let start = self.cycles;
+        while self.cycles - start < max_cycles && self.clock().is_some() {}
+        self.cycles - start
+    }
+
+    fn runnable(&self, index: usize) -> bool {
+        !self.cores[index].is_halted() && !self.cores[index].is_faulted()
+    }
+
+    fn next_core(&mut self) -> usize {
+        match &self.schedule {
+            Schedule::RoundRobin { quantum } => {
+                let quantum = *quantum;
+                if self.quantum_used >= quantum || !self.runnable(self.position) {
+                    self.quantum_used = 0;
+                    self.position = (self.position + 1) % self.cores.len();
+                    while !self.runnable(self.position) {
+                        self.position = (self.position + 1) % self.cores.len();
+                    }
+                }
+                self.quantum_used += 1;
+                self.position
+            }
+            Schedule::Pattern(pattern) => loop {
+                let index = pattern[self.position % pattern.len()];
+                self.position = (self.position + 1) % pattern.len();
+                if self.runnable(index) {
+                    break index;
+                }
+                // A pattern naming only stopped cores falls back to the first running one
+                if pattern.iter().all(|core| !self.runnable(*core)) {
+                    break (0..self.cores.len()).find(|core| self.runnable(*core)).unwrap();
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigUint;
+    use crate::computer::ZERO_FLAG;
+    use crate::harness::TestMachine;
+    use crate::writers::ProgramWriter;
+    use crate::InstructionSet;
+
+    const COUNTER: u8 = 0x30;
+
+    // Every core runs the same program from address 0, each with its own stack
+    fn machine(cores: usize, schedule: Schedule, build: impl FnOnce(&mut ProgramWriter, &dyn Fn(&str) -> u8)) -> MultiCore {
+        let template = TestMachine::default();
+        let reg = |name: &str| template.cpu.registers.string_reference[name];
+        let mut program = ProgramWriter::new(template.cpu.instruction_set.clone(), &template.cpu.registers);
+        build(&mut program, &reg);
+        let mut memory = Memory::new(64);
+        memory.write_chunk(0, &program.build());
+        let cores = (0..cores)
+            .map(|core| {
+                let mut machine = TestMachine::default();
+                machine.set_register("stack_pointer", 64 - 4 * core as u64);
+                machine.cpu
+            })
+            .collect();
+        MultiCore::new(memory, cores, schedule)
+    }
+
+    fn increment_non_atomic(p: &mut ProgramWriter, reg: &dyn Fn(&str) -> u8) {
+        p.add_instruction(InstructionSet::LoadFromMemory, &[COUNTER, reg("reg_0")])
+            .add_instruction(InstructionSet::AddImmediate, &[reg("reg_0"), 1])
+            .add_instruction(InstructionSet::StoreToMemory, &[reg("reg_0"), COUNTER])
+            .add_instruction(InstructionSet::Halt, &[]);
+    }
+
+    #[test]
+    fn interleaved_increments_lose_an_update() {
+        let mut machine = machine(2, Schedule::RoundRobin { quantum: 1 }, increment_non_atomic);
+        machine.run(1000);
+        assert!(machine.is_stopped());
+        assert_eq!(machine.memory.read(COUNTER as usize), 1);
+    }
+
+    #[test]
+    fn long_quantum_serialises_increments() {
+        let mut machine = machine(2, Schedule::RoundRobin { quantum: 100 }, increment_non_atomic);
+        machine.run(1000);
+        assert_eq!(machine.memory.read(COUNTER as usize), 2);
+    }
+
+    #[test]
+    fn fetch_add_is_atomic() {
+        let mut machine = machine(3, Schedule::RoundRobin { quantum: 1 }, |p, reg| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg("reg_0"), COUNTER])
+                .add_instruction(InstructionSet::LoadImmediate, &[reg("reg_1"), 1])
+                .add_instruction(InstructionSet::FetchAdd, &[reg("reg_0"), reg("reg_1")])
+                .add_instruction(InstructionSet::Fence, &[])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(1000);
+        assert_eq!(machine.memory.read(COUNTER as usize), 3);
+        // Each core saw a different old value
+        let mut seen: Vec<BigUint> = machine.cores.iter().map(|core| core.read_register_value_string("reg_1").unwrap()).collect();
+        seen.sort();
+        assert_eq!(seen, vec![BigUint::from(0u8), BigUint::from(1u8), BigUint::from(2u8)]);
+    }
+
+    #[test]
+    fn compare_and_swap_succeeds_once() {
+        // Every core tries to swap the counter from 0 to its core id + 1
+        let mut machine = machine(2, Schedule::Pattern(vec![0, 1]), |p, reg| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg("reg_0"), COUNTER])
+                .add_instruction(InstructionSet::LoadImmediate, &[reg("reg_1"), 0])
+                .add_instruction(InstructionSet::MoveRegister, &[reg("core_id"), reg("reg_2")])
+                .add_instruction(InstructionSet::AddImmediate, &[reg("reg_2"), 1])
+                .add_instruction(InstructionSet::CompareAndSwap, &[reg("reg_0"), reg("reg_1"), reg("reg_2")])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        machine.run(1000);
+        // Core 0 comes first in the pattern so it wins the race
+        assert_eq!(machine.memory.read(COUNTER as usize), 1);
+        assert_eq!(machine.cores[0].get_flags() & &*ZERO_FLAG, *ZERO_FLAG);
+        assert_eq!(machine.cores[1].get_flags() & &*ZERO_FLAG, BigUint::from(0u8));
+        // The loser learns the current value
+        assert_eq!(machine.cores[1].read_register_value_string("reg_1"), Some(BigUint::from(1u8)));
+    }
+}
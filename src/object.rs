@@ -0,0 +1,325 @@
+use num_bigint::BigUint;
+use num_traits::One;
+
+use crate::computer::Endianness;
+
+// Relocatable object files.
+//
+// An object holds one module's code, data and bss sections, the symbols it defines and the relocations
+// its sections need once the linker knows where every symbol ends up. ProgramWriter::try_build_object
+// produces them and the Linker combines them into a loadable image.
+//
+// Objects can be saved with to_bytes and read back with from_bytes, all numbers little endian:
+//
+// "RCSO" version:u8 endianness:u8 name code data bss_size:u32
+// symbol_count:u32 { name section:u8 offset:u32 global:u8 }
+// relocation_count:u32 { section:u8 offset:u32 width:u8 symbol kind:u8 origin:u32 }
+//
+// where names and sections are a u32 length followed by their bytes.
+
+const MAGIC: &[u8; 4] = b"RCSO";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SectionKind {
+    Code,
+    Data,
+    // Zero filled when loaded, only its size is stored
+    Bss,
+}
+
+impl SectionKind {
+    pub const ALL: [SectionKind; 3] = [SectionKind::Code, SectionKind::Data, SectionKind::Bss];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SectionKind::Code => "code",
+            SectionKind::Data => "data",
+            SectionKind::Bss => "bss",
+        }
+    }
+
+    fn from_u8(value: u8) -> Result<Self, String> {
+        SectionKind::ALL.get(value as usize).copied().ok_or_else(|| format!("Unknown section {}", value))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: SectionKind,
+    pub offset: usize,
+    // Globals are visible to the other modules, everything else only to relocations of this module
+    pub global: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocationKind {
+    // The symbol's address
+    Absolute,
+    // The symbol's address minus the address of origin, an offset into the same section. Stored as two's
+    // complement, the difference must fit the field
+    Relative { origin: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub section: SectionKind,
+    // Where the field starts in the section
+    pub offset: usize,
+    pub width: usize,
+    pub symbol: String,
+    pub kind: RelocationKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectFile {
+    // Module name for the map file and error messages
+    pub name: String,
+    // Byte order of the relocated fields
+    pub endianness: Endianness,
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_size: usize,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl ObjectFile {
+    pub fn new(name: &str, endianness: Endianness) -> Self {
+        ObjectFile {
+            name: name.to_string(),
+            endianness,
+            code: Vec::new(),
+            data: Vec::new(),
+            bss_size: 0,
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+        }
+    }
+
+    pub fn section_size(&self, section: SectionKind) -> usize {
+        match section {
+            SectionKind::Code => self.code.len(),
+            SectionKind::Data => self.data.len(),
+            SectionKind::Bss => self.bss_size,
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // Fails when a size, offset or count is too large for its 32 bit field or a relocation is wider
+    // than 255 bytes, the format would otherwise silently truncate it
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(endianness_to_u8(self.endianness));
+        put_bytes(&mut out, self.name.as_bytes(), "Object name")?;
+        put_bytes(&mut out, &self.code, "Code section")?;
+        put_bytes(&mut out, &self.data, "Data section")?;
+        put_u32(&mut out, self.bss_size, "Bss size")?;
+        put_u32(&mut out, self.symbols.len(), "Symbol count")?;
+        for symbol in &self.symbols {
+            put_bytes(&mut out, symbol.name.as_bytes(), "Symbol name")?;
+            out.push(symbol.section as u8);
+            put_u32(&mut out, symbol.offset, &format!("Offset of symbol '{}'", symbol.name))?;
+            out.push(symbol.global as u8);
+        }
+        put_u32(&mut out, self.relocations.len(), "Relocation count")?;
+        for relocation in &self.relocations {
+            let what = format!("Relocation for '{}'", relocation.symbol);
+            out.push(relocation.section as u8);
+            put_u32(&mut out, relocation.offset, &format!("{} offset", what))?;
+            let width = u8::try_from(relocation.width).map_err(|_| format!("{} width {} does not fit in a byte", what, relocation.width))?;
+            out.push(width);
+            put_bytes(&mut out, relocation.symbol.as_bytes(), "Symbol name")?;
+            let (kind, origin) = match relocation.kind {
+                RelocationKind::Absolute => (0, 0),
+                RelocationKind::Relative { origin } => (1, origin),
+            };
+            out.push(kind);
+            put_u32(&mut out, origin, &format!("{} origin", what))?;
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = ByteReader::new(bytes, "Object file");
+        if reader.take(4)? != MAGIC {
+            return Err("Not an object file".to_string());
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("Object file version {} is not supported", version));
+        }
+        let endianness = endianness_from_u8(reader.u8()?)?;
+        let mut object = ObjectFile::new(&reader.string()?, endianness);
+        object.code = reader.bytes()?.to_vec();
+        object.data = reader.bytes()?.to_vec();
+        object.bss_size = reader.u32()?;
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let section = SectionKind::from_u8(reader.u8()?)?;
+            let offset = reader.u32()?;
+            let global = reader.u8()? != 0;
+            object.symbols.push(Symbol { name, section, offset, global });
+        }
+        for _ in 0..reader.u32()? {
+            let section = SectionKind::from_u8(reader.u8()?)?;
+            let offset = reader.u32()?;
+            let width = reader.u8()? as usize;
+            let symbol = reader.string()?;
+            let kind = match (reader.u8()?, reader.u32()?) {
+                (0, _) => RelocationKind::Absolute,
+                (1, origin) => RelocationKind::Relative { origin },
+                (other, _) => return Err(format!("Unknown relocation kind {}", other)),
+            };
+            object.relocations.push(Relocation { section, offset, width, symbol, kind });
+        }
+        reader.finish()?;
+        object.check()?;
+        Ok(object)
+    }
+
+    // Symbols and relocations must point inside their sections
+    pub fn check(&self) -> Result<(), String> {
+        for symbol in &self.symbols {
+            if symbol.offset > self.section_size(symbol.section) {
+                return Err(format!("{}: symbol '{}' is outside its {} section", self.name, symbol.name, symbol.section.name()));
+            }
+        }
+        for relocation in &self.relocations {
+            if relocation.section == SectionKind::Bss {
+                return Err(format!("{}: relocation for '{}' in the bss section", self.name, relocation.symbol));
+            }
+            if relocation.width == 0 || relocation.offset + relocation.width > self.section_size(relocation.section) {
+                return Err(format!("{}: relocation for '{}' is outside its {} section", self.name, relocation.symbol, relocation.section.name()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// target - origin as two's complement in width bytes, failing if the signed difference needs more
+pub(crate) fn displacement(target: usize, origin: usize, width: usize) -> Result<BigUint, String> {
+    let difference = target as i128 - origin as i128;
+    if width < 16 {
+        let limit = 1i128 << (8 * width - 1);
+        if difference < -limit || difference >= limit {
+            return Err(format!("Displacement {} does not fit in {} byte(s)", difference, width));
+        }
+    }
+    let modulus = BigUint::one() << (8 * width);
+    Ok((BigUint::from(target) + &modulus - BigUint::from(origin) % &modulus) % &modulus)
+}
+
+pub(crate) fn endianness_to_u8(endianness: Endianness) -> u8 {
+    match endianness {
+        Endianness::Big => 0,
+        Endianness::Little => 1,
+    }
+}
+
+pub(crate) fn endianness_from_u8(value: u8) -> Result<Endianness, String> {
+    match value {
+        0 => Ok(Endianness::Big),
+        1 => Ok(Endianness::Little),
+        other => Err(format!("Unknown endianness {}", other)),
+    }
+}
+
+// Fails rather than truncating a value the field cannot hold, what names the field in the error
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: usize, what: &str) -> Result<(), String> {
+    let value = u32::try_from(value).map_err(|_| format!("{} {} does not fit in a 32 bit field", what, value))?;
+    out.extend(value.to_le_bytes());
+    Ok(())
+}
+
+pub(crate) fn put_bytes(out: &mut Vec<u8>, bytes: &[u8], what: &str) -> Result<(), String> {
+    put_u32(out, bytes.len(), &format!("{} length", what))?;
+    out.extend(bytes);
+    Ok(())
+}
+
+// Reads the little endian fields of the object and image formats, running out of bytes is an error
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // What is being read, for error messages
+    what: &'static str,
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        ByteReader { bytes, position: 0, what }
+    }
+
+    pub(crate) fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.position.checked_add(count).filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| format!("{} is truncated at byte {}", self.what, self.position))?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let length = self.u32()?;
+        self.take(length)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let what = self.what;
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| format!("{} has a name that is not UTF-8", what))
+    }
+
+    pub(crate) fn finish(&self) -> Result<(), String> {
+        match self.position == self.bytes.len() {
+            true => Ok(()),
+            false => Err(format!("{} has {} unexpected trailing byte(s)", self.what, self.bytes.len() - self.position)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn objects_round_trip_through_bytes() {
+        let mut object = ObjectFile::new("lib", Endianness::Little);
+        object.code = vec![1, 2, 0, 0];
+        object.data = vec![9];
+        object.bss_size = 16;
+        object.symbols.push(Symbol { name: "entry".to_string(), section: SectionKind::Code, offset: 0, global: true });
+        object.symbols.push(Symbol { name: "buffer".to_string(), section: SectionKind::Bss, offset: 4, global: false });
+        object.relocations.push(Relocation { section: SectionKind::Code, offset: 2, width: 2, symbol: "buffer".to_string(), kind: RelocationKind::Absolute });
+        object.relocations.push(Relocation { section: SectionKind::Code, offset: 1, width: 1, symbol: "far".to_string(), kind: RelocationKind::Relative { origin: 0 } });
+        let bytes = object.to_bytes().unwrap();
+        assert_eq!(ObjectFile::from_bytes(&bytes), Ok(object));
+        assert!(ObjectFile::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err().contains("truncated"));
+        assert_eq!(ObjectFile::from_bytes(b"ELF!").unwrap_err(), "Not an object file");
+    }
+
+    #[test]
+    fn values_too_large_for_the_format_are_errors() {
+        let mut object = ObjectFile::new("big", Endianness::Little);
+        object.bss_size = u32::MAX as usize + 1;
+        assert_eq!(object.to_bytes().unwrap_err(), "Bss size 4294967296 does not fit in a 32 bit field");
+        object.bss_size = 0;
+        object.relocations.push(Relocation { section: SectionKind::Code, offset: 0, width: 256, symbol: "far".to_string(), kind: RelocationKind::Absolute });
+        assert_eq!(object.to_bytes().unwrap_err(), "Relocation for 'far' width 256 does not fit in a byte");
+        object.relocations[0] = Relocation { section: SectionKind::Code, offset: 0, width: 1, symbol: "far".to_string(), kind: RelocationKind::Relative { origin: usize::MAX } };
+        assert!(object.to_bytes().unwrap_err().starts_with("Relocation for 'far' origin 18446744073709551615 does not fit"));
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use num_traits::ToPrimitive;
+
+use crate::computer::{AccessKind, RegisterRole, SubInstructions, CPU};
+
+// Classic 5 stage pipeline timing mode.
+//
+// Each instruction still executes on the sequential micro-op engine, which stays the reference for
+// results. The pipeline only schedules it: the registers each instruction reads before writing are
+// its sources, a source written by an older instruction still in flight is a data hazard, and a taken
+// jump is resolved in EX and flushes the instructions fetched behind it. With a branch unit on the CPU,
+// conditional jumps only flush when they were mispredicted.
+//
+// With forwarding a result reaches EX of the next instruction straight from EX, or from MEM for
+// instructions that read memory, so only a load followed by a use stalls. Without forwarding a source
+// is read in ID, no earlier than the cycle its producer is in WB.
+
+pub const STAGES: [&str; 5] = ["IF", "ID", "EX", "ME", "WB"];
+const IF: usize = 0;
+const ID: usize = 1;
+const EX: usize = 2;
+const MEM: usize = 3;
+const WB: usize = 4;
+
+// Instructions fetched on the wrong path before a jump resolves in EX
+const BRANCH_PENALTY: u64 = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineConfig {
+    pub forwarding: bool,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig { forwarding: true }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub cycles: u64,
+    pub instructions: u64,
+    // Bubbles inserted for data hazards, an instruction held up behind a stalled one adds none
+    pub stalls: u64,
+    pub flushes: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct PipelineRecord {
+    pub address: usize,
+    pub opcode: u8,
+    // Cycle the instruction enters each stage, 1 based
+    pub stages: [u64; 5],
+    pub flushed: u64,
+}
+
+// Cycles at which a register written by an in flight instruction can be used
+#[derive(Clone, Copy)]
+struct Ready {
+    forwarded: u64,
+    written_back: u64,
+}
+
+pub struct Pipeline {
+    pub config: PipelineConfig,
+    pub records: Vec<PipelineRecord>,
+    pub stats: PipelineStats,
+    ready: HashMap<u8, Ready>,
+    // Earliest cycle the next fetch may happen in, set by taken jumps
+    fetch_after: u64,
+}
+
+// Registers an instruction reads before writing them, registers it writes, and whether it reads memory
+struct Effects {
+    sources: Vec<u8>,
+    destinations: Vec<u8>,
+    loads: bool,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        Pipeline {
+            config,
+            records: Vec::new(),
+            stats: PipelineStats::default(),
+            ready: HashMap::new(),
+            fetch_after: 0,
+        }
+    }
+
+    // Runs until the CPU halts or faults or max_instructions have been issued, returns the number issued
+    pub fn run(&mut self, cpu: &mut CPU, max_instructions: usize) -> usize {
+        let mut issued = 0;
+        while issued < max_instructions && self.step(cpu) {
+            issued += 1;
+        }
+        issued
+    }
+
+    // Executes one instruction and schedules it, false once the CPU has halted or faulted
+    pub fn step(&mut self, cpu: &mut CPU) -> bool {
+        if cpu.is_halted() || cpu.is_faulted() {
+            return false;
+        }
+        let address = cpu.get_program_counter().to_usize();
+        let Some((address, opcode)) = address.and_then(|address| peek(cpu, address).map(|opcode| (address, opcode))) else {
+            // Let the reference engine raise the fault, a program counter past usize included
+            cpu.clock();
+            return false;
+        };
+        let effects = effects(cpu, address, opcode);
+        if let Some(unit) = cpu.branch_unit.as_mut() {
+            unit.last_correct = None;
+        }
+        let sequential_next = address + 1 + cpu.instruction_set.get(&opcode).map_or(0, |op| op.args as usize);
+
+        execute_instruction(cpu);
+        if cpu.is_faulted() {
+            return false;
+        }
+        // A program counter past usize is a jump away, the next step faults on it
+        let next = cpu.get_program_counter().to_usize().unwrap_or(usize::MAX);
+        let taken = !cpu.is_halted() && next != sequential_next;
+        let flush = match cpu.branch_unit.as_mut().and_then(|unit| unit.last_correct.take()) {
+            Some(correct) => !correct,
+            None => taken,
+        };
+        self.schedule(address, opcode, &effects, flush);
+        true
+    }
+
+    fn schedule(&mut self, address: usize, opcode: u8, effects: &Effects, flush: bool) {
+        let previous = self.records.last().map(|record| record.stages);
+        // An instruction enters a stage once the one ahead of it has moved on
+        let after_previous = |stage: usize| previous.map_or(0, |stages| stages[stage + 1]);
+        let after_previous_wb = previous.map_or(0, |stages| stages[WB] + 1);
+
+        let mut stages = [0u64; 5];
+        stages[IF] = (previous.map_or(1, |stages| stages[IF] + 1)).max(after_previous(IF)).max(self.fetch_after);
+        stages[ID] = (stages[IF] + 1).max(after_previous(ID));
+        if !self.config.forwarding {
+            for source in &effects.sources {
+                if let Some(ready) = self.ready.get(source) {
+                    stages[ID] = stages[ID].max(ready.written_back);
+                }
+            }
+        }
+        stages[EX] = (stages[ID] + 1).max(after_previous(EX));
+        if self.config.forwarding {
+            for source in &effects.sources {
+                if let Some(ready) = self.ready.get(source) {
+                    stages[EX] = stages[EX].max(ready.forwarded);
+                }
+            }
+        }
+        stages[MEM] = (stages[EX] + 1).max(after_previous(MEM));
+        stages[WB] = (stages[MEM] + 1).max(after_previous_wb);
+
+        let forwarded = if effects.loads { stages[MEM] + 1 } else { stages[EX] + 1 };
+        for destination in &effects.destinations {
+            self.ready.insert(*destination, Ready { forwarded, written_back: stages[WB] });
+        }
+
+        let flushed = if flush { BRANCH_PENALTY } else { 0 };
+        if flush {
+            self.fetch_after = stages[EX] + 1;
+        }
+        self.stats.instructions += 1;
+        let unstalled_ex = (stages[IF] + 2).max(previous.map_or(0, |stages| stages[EX] + 1));
+        self.stats.stalls += stages[EX] - unstalled_ex;
+        self.stats.flushes += flushed;
+        self.stats.cycles = stages[WB];
+        self.records.push(PipelineRecord { address, opcode, stages, flushed });
+    }
+
+    // One row per instruction and one column per cycle, -- marks a cycle spent waiting in a stage
+    pub fn diagram(&self) -> String {
+        let mut diagram = String::from("address  op |");
+        for cycle in 1..=self.stats.cycles {
+            let _ = write!(diagram, "{:>3}", cycle);
+        }
+        for record in &self.records {
+            let _ = write!(diagram, "\n{:#06x} {:>4} |", record.address, record.opcode);
+            for cycle in 1..=self.stats.cycles {
+                let text = match record.stages.iter().position(|entered| *entered == cycle) {
+                    Some(stage) => STAGES[stage],
+                    None if cycle > record.stages[IF] && cycle < record.stages[WB] => "--",
+                    None => "",
+                };
+                let _ = write!(diagram, "{:>3}", text);
+            }
+            if record.flushed > 0 {
+                let _ = write!(diagram, "  {} flushed", record.flushed);
+            }
+        }
+        diagram
+    }
+}
+
+// Instruction bytes are read without touching the cache or timing of the reference engine
+fn peek(cpu: &CPU, address: usize) -> Option<u8> {
+    let physical = cpu.translate(address, AccessKind::Execute).ok()?;
+    (physical < cpu.memory.size()).then(|| cpu.memory.read(physical))
+}
+
+fn effects(cpu: &CPU, address: usize, opcode: u8) -> Effects {
+    let mut effects = Effects { sources: Vec::new(), destinations: Vec::new(), loads: false };
+    let Some(instruction) = cpu.instruction_set.get(&opcode) else {
+        return effects;
+    };
+    let reg = |role: RegisterRole| cpu.registers.role(role);
+    let accumulator = reg(RegisterRole::Accumulator);
+    let operand = |offset: u8| peek(cpu, address + offset as usize);
+    for sub_instruction in &instruction.sub_instructions {
+        let (reads, writes): (Vec<Option<u8>>, Vec<Option<u8>>) = match sub_instruction {
+            SubInstructions::LoadImmediate(_)
+            | SubInstructions::LoadImmediateWide(..)
+            | SubInstructions::LoadImmediateInternal(_) => (vec![], vec![accumulator]),
+            SubInstructions::LoadFromMemory => {
+                effects.loads = true;
+                (vec![reg(RegisterRole::MemoryAddress)], vec![accumulator])
+            }
+            SubInstructions::LoadFromRegister(offset) => (vec![operand(*offset)], vec![accumulator]),
+            SubInstructions::LoadFromRegisterInternal(register) => (vec![Some(*register)], vec![accumulator]),
+            SubInstructions::SetMemoryAddress => (vec![accumulator], vec![reg(RegisterRole::MemoryAddress)]),
+            SubInstructions::StoreToMemory => (vec![reg(RegisterRole::MemoryAddress), accumulator], vec![]),
+            SubInstructions::StoreToRegister(offset) => (vec![accumulator], vec![operand(*offset)]),
+            SubInstructions::StoreToRegisterInternal(register) => (vec![accumulator], vec![Some(*register)]),
+            SubInstructions::Add | SubInstructions::Sub => (vec![reg(RegisterRole::OperandA), reg(RegisterRole::OperandB)], vec![accumulator]),
+            SubInstructions::PushToStack => (vec![reg(RegisterRole::StackPointer), accumulator], vec![reg(RegisterRole::StackPointer)]),
+            SubInstructions::PopFromStack => {
+                effects.loads = true;
+                (vec![reg(RegisterRole::StackPointer)], vec![reg(RegisterRole::StackPointer), accumulator])
+            }
+            SubInstructions::Jump => (vec![accumulator], vec![]),
+            // Compare and CompareAndSwap keep the flag bits they do not set, but that merge happens in order
+            // in EX, so it is not a read that has to wait for an earlier instruction's flags
+            SubInstructions::Compare => (vec![reg(RegisterRole::OperandA), reg(RegisterRole::OperandB)], vec![reg(RegisterRole::Flags)]),
+            SubInstructions::JumpIfFlag(..) | SubInstructions::JumpIfNotFlag(..) => (vec![reg(RegisterRole::Flags), accumulator], vec![]),
+            SubInstructions::SystemCall => (vec![reg(RegisterRole::TrapVector)], vec![reg(RegisterRole::TrapReturn), reg(RegisterRole::TrapCause)]),
+            SubInstructions::ReturnFromTrap => (vec![reg(RegisterRole::TrapReturn)], vec![]),
+            SubInstructions::CompareAndSwap => {
+                effects.loads = true;
+                (vec![reg(RegisterRole::MemoryAddress), reg(RegisterRole::OperandA), reg(RegisterRole::OperandB)], vec![reg(RegisterRole::Flags), accumulator])
+            }
+            SubInstructions::FetchAdd => {
+                effects.loads = true;
+                (vec![reg(RegisterRole::MemoryAddress), reg(RegisterRole::OperandB)], vec![accumulator])
+            }
+            SubInstructions::AddressOffset(base, ..) => (vec![operand(*base)], vec![accumulator]),
+            SubInstructions::AddressIndexed(base, index, _) => (vec![operand(*base), operand(*index)], vec![accumulator]),
+            SubInstructions::AddressPcRelative(..) => (vec![], vec![accumulator]),
+            SubInstructions::NoOperation
+            | SubInstructions::Halt
+            | SubInstructions::StepProgramMemory(_)
+            | SubInstructions::Fence => (vec![], vec![]),
+        };
+        // A view and its parent share bytes, so hazards are tracked on the parent
+        let root = |register: u8| cpu.registers.look_up_u8(register).and_then(|reg| reg.parent).unwrap_or(register);
+        for register in reads.into_iter().flatten().map(root) {
+            if !effects.destinations.contains(&register) && !effects.sources.contains(&register) {
+                effects.sources.push(register);
+            }
+        }
+        for register in writes.into_iter().flatten().map(root) {
+            if !effects.destinations.contains(&register) {
+                effects.destinations.push(register);
+            }
+        }
+    }
+    effects
+}
+
+// Clocks the reference engine until the instruction at the program counter completes
+fn execute_instruction(cpu: &mut CPU) {
+    let mut started = false;
+    while !cpu.is_halted() && !cpu.is_faulted() {
+        cpu.clock();
+        match cpu.current_opcode {
+            Some(_) => started = true,
+            None if started => break,
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::writers::ProgramWriter;
+    use crate::InstructionSet;
+
+    fn run(machine: &mut TestMachine, forwarding: bool) -> Pipeline {
+        let mut pipeline = Pipeline::new(PipelineConfig { forwarding });
+        pipeline.run(&mut machine.cpu, 100);
+        pipeline
+    }
+
+    #[test]
+    fn independent_instructions_issue_every_cycle() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg_0, 1])
+                .add_instruction(InstructionSet::LoadImmediate, &[reg_1, 2])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let pipeline = run(&mut machine, true);
+        machine.expect().halted(true).register("reg_0", 1).register("reg_1", 2).check();
+        assert_eq!(pipeline.stats, PipelineStats { cycles: 7, instructions: 3, stalls: 0, flushes: 0 });
+    }
+
+    #[test]
+    fn forwarding_removes_alu_stalls() {
+        let program = |p: &mut ProgramWriter, reg_0: u8| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg_0, 1])
+                .add_instruction(InstructionSet::AddImmediate, &[reg_0, 2])
+                .add_instruction(InstructionSet::Halt, &[]);
+        };
+        let mut forwarded = TestMachine::default();
+        let reg_0 = forwarded.reg("reg_0");
+        forwarded.program(|p| program(p, reg_0));
+        assert_eq!(run(&mut forwarded, true).stats.stalls, 0);
+
+        let mut stalled = TestMachine::default();
+        stalled.program(|p| program(p, reg_0));
+        let pipeline = run(&mut stalled, false);
+        // The add reads reg_0 in ID in the cycle the load immediate writes it back
+        assert_eq!(pipeline.records[1].stages, [2, 5, 6, 7, 8]);
+        assert_eq!(pipeline.stats.stalls, 2);
+        stalled.expect().register("reg_0", 3).check();
+    }
+
+    #[test]
+    fn back_to_back_compares_do_not_stall_on_flags() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0") as u64;
+        let reg_1 = machine.reg("reg_1") as u64;
+        machine.program(|p| {
+            p.add_relative_instruction(InstructionSet::BranchNotEqual, "end", &[reg_0, 0])
+                .add_relative_instruction(InstructionSet::BranchNotEqual, "end", &[reg_1, 0])
+                .label("end")
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let pipeline = run(&mut machine, false);
+        machine.expect().halted(true).check();
+        assert_eq!(pipeline.stats.stalls, 0);
+    }
+
+    #[test]
+    fn views_conflict_with_their_parent() {
+        let mut machine = TestMachine::with_data_size(64, 2);
+        machine.cpu.registers.add_sub_register("reg_0_low".to_string(), "reg_0", 0, 8).unwrap();
+        let (reg_0, reg_0_low) = (machine.reg("reg_0"), machine.reg("reg_0_low"));
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadImmediate, &[reg_0_low, 1])
+                .add_instruction(InstructionSet::AddImmediate, &[reg_0, 2])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let pipeline = run(&mut machine, false);
+        assert_eq!(pipeline.stats.stalls, 2);
+        machine.expect().register("reg_0", 3).check();
+    }
+
+    #[test]
+    fn load_use_stalls_with_forwarding() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        machine.load(0x30, &[4]);
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::LoadFromMemory, &[0x30, reg_0])
+                .add_instruction(InstructionSet::AddImmediate, &[reg_0, 2])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let pipeline = run(&mut machine, true);
+        machine.expect().register("reg_0", 6).check();
+        assert_eq!(pipeline.records[1].stages, [2, 3, 5, 6, 7]);
+        assert_eq!(pipeline.stats.stalls, 1);
+    }
+
+    #[test]
+    fn taken_jump_flushes_fetched_instructions() {
+        let mut machine = TestMachine::default();
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::Jump, &[10]);
+        });
+        machine.load(10, &[InstructionSet::Halt as u8]);
+        let pipeline = run(&mut machine, true);
+        machine.expect().halted(true).check();
+        assert_eq!(pipeline.stats.flushes, 2);
+        // Fetched in the cycle after the jump's EX
+        assert_eq!(pipeline.records[1].stages[IF], pipeline.records[0].stages[EX] + 1);
+        assert!(pipeline.diagram().contains("2 flushed"), "{}", pipeline.diagram());
+    }
+
+    #[test]
+    fn matches_sequential_results() {
+        let program = |machine: &mut TestMachine| {
+            let reg_0 = machine.reg("reg_0");
+            let reg_1 = machine.reg("reg_1");
+            machine.program(|p| {
+                p.add_instruction(InstructionSet::LoadImmediate, &[reg_0, 3])
+                    .add_instruction(InstructionSet::PushReg, &[reg_0])
+                    .add_instruction(InstructionSet::AddImmediate, &[reg_0, 4])
+                    .add_instruction(InstructionSet::PopReg, &[reg_1])
+                    .add_instruction(InstructionSet::Halt, &[]);
+            });
+        };
+        let mut sequential = TestMachine::default();
+        program(&mut sequential);
+        sequential.run(500);
+        let mut pipelined = TestMachine::default();
+        program(&mut pipelined);
+        let pipeline = run(&mut pipelined, true);
+        assert_eq!(pipelined.cpu.register_data, sequential.cpu.register_data);
+        assert_eq!(pipelined.cpu.memory.read_chunk(0, 64), sequential.cpu.memory.read_chunk(0, 64));
+        assert!(pipeline.stats.cycles < sequential.cycles as u64);
+    }
+}
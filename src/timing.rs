@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::mem::{discriminant, Discriminant};
+
+use crate::computer::SubInstructions;
+
+// Timing model.
+//
+// Every clock call is one cycle. A micro-op with latency n runs in its clock and then stalls the CPU
+// for n - 1 more, each memory access stalls for memory_latency on top. The defaults reproduce one
+// clock per micro-op.
+//
+// let mut timing = TimingModel::default();
+// timing.set_latency(&SubInstructions::Add, 3);
+// timing.memory_latency = 2;
+// cpu.timing = timing;
+
+#[derive(Clone, Debug)]
+pub struct TimingModel {
+    pub default_latency: u32,
+    // Extra cycles for each read_memory or write_memory, instruction fetches included
+    pub memory_latency: u32,
+    latencies: HashMap<Discriminant<SubInstructions>, u32>,
+}
+
+impl Default for TimingModel {
+    fn default() -> Self {
+        TimingModel {
+            default_latency: 1,
+            memory_latency: 0,
+            latencies: HashMap::new(),
+        }
+    }
+}
+
+impl TimingModel {
+    // Sets the latency of every micro-op of the same variant, any arguments of sub_instruction are ignored
+    pub fn set_latency(&mut self, sub_instruction: &SubInstructions, latency: u32) -> &mut Self {
+        self.latencies.insert(discriminant(sub_instruction), latency);
+        self
+    }
+
+    // A micro-op always takes at least the clock it runs in
+    pub fn latency(&self, sub_instruction: &SubInstructions) -> u32 {
+        if self.latencies.is_empty() {
+            return self.default_latency.max(1);
+        }
+        self.latencies
+            .get(&discriminant(sub_instruction))
+            .copied()
+            .unwrap_or(self.default_latency)
+            .max(1)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InstructionTiming {
+    pub retired: u64,
+    pub cycles: u64,
+}
+
+impl InstructionTiming {
+    pub fn cpi(&self) -> f64 {
+        self.cycles as f64 / self.retired as f64
+    }
+}
+
+// Cycles spent per opcode, from fetch to the cycle the instruction completes including its stalls.
+// Indexed by opcode since it is updated on every instruction, opcodes never retired have no timing
+#[derive(Clone, Debug, Default)]
+pub struct TimingReport {
+    per_opcode: Vec<InstructionTiming>,
+}
+
+impl TimingReport {
+    pub fn record(&mut self, opcode: u8, cycles: u64) {
+        if self.per_opcode.is_empty() {
+            self.per_opcode.resize(256, InstructionTiming::default());
+        }
+        let timing = &mut self.per_opcode[opcode as usize];
+        timing.retired += 1;
+        timing.cycles += cycles;
+    }
+
+    pub fn get(&self, opcode: u8) -> Option<InstructionTiming> {
+        self.per_opcode.get(opcode as usize).copied().filter(|timing| timing.retired > 0)
+    }
+
+    pub fn cpi(&self, opcode: u8) -> Option<f64> {
+        self.get(opcode).map(|timing| timing.cpi())
+    }
+
+    pub fn total(&self) -> InstructionTiming {
+        self.per_opcode.iter().fold(InstructionTiming::default(), |total, timing| InstructionTiming {
+            retired: total.retired + timing.retired,
+            cycles: total.cycles + timing.cycles,
+        })
+    }
+
+    // (opcode, timing) sorted by opcode
+    pub fn rows(&self) -> Vec<(u8, InstructionTiming)> {
+        (0..=u8::MAX).filter_map(|opcode| Some((opcode, self.get(opcode)?))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestMachine;
+    use crate::InstructionSet;
+
+    fn micro_ops(machine: &TestMachine, opcode: InstructionSet) -> u64 {
+        machine.cpu.instruction_set[&(opcode as u8)].sub_instructions.len() as u64
+    }
+
+    fn cycles(machine: &TestMachine, opcode: InstructionSet) -> u64 {
+        machine.cpu.timing_report.get(opcode as u8).unwrap().cycles
+    }
+
+    #[test]
+    fn default_model_charges_one_cycle_per_micro_op() {
+        let mut machine = TestMachine::default();
+        let reg_0 = machine.reg("reg_0");
+        let reg_1 = machine.reg("reg_1");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::MoveRegister, &[reg_0, reg_1])
+                .add_instruction(InstructionSet::Jump, &[10]);
+        });
+        machine.load(10, &[InstructionSet::Halt as u8]);
+        let used = machine.run(200);
+        // Fetch, the micro-ops, then the step to the next instruction
+        assert_eq!(cycles(&machine, InstructionSet::MoveRegister), micro_ops(&machine, InstructionSet::MoveRegister) + 2);
+        // Jumps replace the step
+        assert_eq!(cycles(&machine, InstructionSet::Jump), micro_ops(&machine, InstructionSet::Jump) + 1);
+        assert_eq!(machine.cpu.cycles, used as u64);
+        assert_eq!(machine.cpu.timing_report.total().retired, 3);
+    }
+
+    #[test]
+    fn latencies_stall_the_cpu() {
+        let mut machine = TestMachine::default();
+        machine.cpu.timing.set_latency(&SubInstructions::Add, 5).memory_latency = 3;
+        let reg_0 = machine.reg("reg_0");
+        machine.program(|p| {
+            p.add_instruction(InstructionSet::AddImmediate, &[reg_0, 1])
+                .add_instruction(InstructionSet::LoadFromMemory, &[0x30, reg_0])
+                .add_instruction(InstructionSet::Halt, &[]);
+        });
+        let used = machine.run(200);
+        machine.expect().halted(true).check();
+        // Fetch plus three operand reads
+        let add_reads = 4;
+        assert_eq!(cycles(&machine, InstructionSet::AddImmediate), micro_ops(&machine, InstructionSet::AddImmediate) + 2 + 4 + add_reads * 3);
+        // Fetch, two operand reads and the load itself
+        assert_eq!(cycles(&machine, InstructionSet::LoadFromMemory), micro_ops(&machine, InstructionSet::LoadFromMemory) + 2 + 4 * 3);
+        assert_eq!(machine.cpu.timing_report.total().cycles, used as u64);
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+
+use crate::computer::{Instruction, Operand, RegisterRole, Registers, SubInstructions};
+
+// Static checks for instruction microcode, run before a program ever executes
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    // An argument offset points outside the instruction's argument bytes (offset 0 is the opcode)
+    ArgumentOffsetOutOfRange { opcode: u8, sub_step: usize, offset: u8, args: u8 },
+    // A non control flow instruction leaves the program counter on the wrong byte
+    ProgramCounterAdvance { opcode: u8, expected: u8, actual: usize },
+    UnknownRegister { opcode: u8, sub_step: usize, register: u8 },
+    // A sub instruction reads an operand as the wrong kind, e.g. a register id from an immediate
+    OperandKindMismatch { opcode: u8, sub_step: usize, offset: u8, operand: Operand },
+    DuplicateOpcode { opcode: u8 },
+    // A sub instruction uses a register by role and no register has it, e.g. SystemCall without trap_vector
+    MissingRole { opcode: u8, sub_step: usize, role: RegisterRole },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::ArgumentOffsetOutOfRange { opcode, sub_step, offset, args } => write!(
+                f, "opcode {} step {}: argument offset {} outside 1..={}", opcode, sub_step, offset, args
+            ),
+            Diagnostic::ProgramCounterAdvance { opcode, expected, actual } => write!(
+                f, "opcode {}: steps the program counter by {} but has {} argument byte(s)", opcode, actual, expected
+            ),
+            Diagnostic::UnknownRegister { opcode, sub_step, register } => write!(
+                f, "opcode {} step {}: register {} does not exist", opcode, sub_step, register
+            ),
+            Diagnostic::OperandKindMismatch { opcode, sub_step, offset, operand } => write!(
+                f, "opcode {} step {}: argument offset {} is a {:?} operand", opcode, sub_step, offset, operand
+            ),
+            Diagnostic::DuplicateOpcode { opcode } => write!(f, "opcode {} is defined more than once", opcode),
+            Diagnostic::MissingRole { opcode, sub_step, role } => write!(
+                f, "opcode {} step {}: no register has the {} role", opcode, sub_step, role.name()
+            ),
+        }
+    }
+}
+
+pub fn validate_instruction(opcode: u8, instruction: &Instruction, registers: &Registers) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let args = instruction.args;
+    let layout = instruction.operand_layout();
+    let mut steps = 0usize;
+    let mut control_flow = false;
+    for (sub_step, sub_instruction) in instruction.sub_instructions.iter().enumerate() {
+        match sub_instruction {
+            SubInstructions::LoadImmediate(offset)
+            | SubInstructions::LoadFromRegister(offset)
+            | SubInstructions::StoreToRegister(offset) if *offset == 0 || *offset > args => {
+                diagnostics.push(Diagnostic::ArgumentOffsetOutOfRange { opcode, sub_step, offset: *offset, args });
+            }
+            SubInstructions::LoadImmediateWide(offset, width, _) if *offset == 0 || *offset as usize + *width as usize - 1 > args as usize => {
+                diagnostics.push(Diagnostic::ArgumentOffsetOutOfRange { opcode, sub_step, offset: *offset, args });
+            }
+            SubInstructions::LoadImmediate(offset)
+            | SubInstructions::LoadImmediateWide(offset, _, _) if layout[*offset as usize - 1].is_register() => {
+                let operand = layout[*offset as usize - 1];
+                diagnostics.push(Diagnostic::OperandKindMismatch { opcode, sub_step, offset: *offset, operand });
+            }
+            SubInstructions::LoadFromRegister(offset)
+            | SubInstructions::StoreToRegister(offset) if !layout[*offset as usize - 1].is_register() => {
+                let operand = layout[*offset as usize - 1];
+                diagnostics.push(Diagnostic::OperandKindMismatch { opcode, sub_step, offset: *offset, operand });
+            }
+            SubInstructions::LoadFromRegisterInternal(register)
+            | SubInstructions::StoreToRegisterInternal(register) if registers.look_up_u8(*register).is_none() => {
+                diagnostics.push(Diagnostic::UnknownRegister { opcode, sub_step, register: *register });
+            }
+            SubInstructions::StepProgramMemory(size) => {
+                steps += *size as usize;
+            }
+            SubInstructions::Jump => {
+                control_flow = true;
+            }
+            SubInstructions::SystemCall | SubInstructions::ReturnFromTrap => {
+                control_flow = true;
+                let missing = RegisterRole::TRAP.into_iter().filter(|role| registers.role(*role).is_none());
+                diagnostics.extend(missing.map(|role| Diagnostic::MissingRole { opcode, sub_step, role }));
+            }
+            _ => {}
+        }
+        // The address micro-ops read several operands each
+        let (register_offsets, immediates): (Vec<u8>, Vec<(u8, u8)>) = match sub_instruction {
+            SubInstructions::AddressOffset(base, displacement, width, _) => (vec![*base], vec![(*displacement, *width)]),
+            SubInstructions::AddressIndexed(base, index, scale) => (vec![*base, *index], vec![(*scale, 1)]),
+            SubInstructions::AddressPcRelative(displacement, width, _) => (vec![], vec![(*displacement, *width)]),
+            _ => (vec![], vec![]),
+        };
+        let operands = register_offsets.iter().map(|offset| (*offset, 1, true)).chain(immediates.iter().map(|(offset, width)| (*offset, *width, false)));
+        for (offset, width, register) in operands {
+            if offset == 0 || offset as usize + width as usize - 1 > args as usize {
+                diagnostics.push(Diagnostic::ArgumentOffsetOutOfRange { opcode, sub_step, offset, args });
+            } else if layout[offset as usize - 1].is_register() != register {
+                let operand = layout[offset as usize - 1];
+                diagnostics.push(Diagnostic::OperandKindMismatch { opcode, sub_step, offset, operand });
+            }
+        }
+    }
+    if !control_flow && steps != args as usize {
+        diagnostics.push(Diagnostic::ProgramCounterAdvance { opcode, expected: args, actual: steps });
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computer::create_default_cpu;
+    use crate::instructions::add_instructions;
+    use crate::writers::InstructionSetWriter;
+    use crate::InstructionSet;
+
+    #[test]
+    fn default_instruction_set_is_clean() {
+        let cpu = create_default_cpu(64, 0);
+        let mut writer = InstructionSetWriter::new(1);
+        let cpu_data_size = cpu.cpu_data_size;
+        let endianness = cpu.endianness;
+        add_instructions(&mut writer, |role| cpu.registers.role(role).unwrap(), cpu_data_size, endianness);
+        let diagnostics = writer.validate(&cpu.registers);
+        assert!(diagnostics.is_empty(), "{:#?}", diagnostics);
+    }
+
+    #[test]
+    fn reports_each_kind_of_mistake() {
+        let cpu = create_default_cpu(64, 0);
+        let mut writer = InstructionSetWriter::new(1);
+        writer.add_instruction(InstructionSet::LoadImmediate, &[Operand::Reg, Operand::Imm8])
+            .add_sub_instruction(SubInstructions::LoadImmediate(3))
+            .add_sub_instruction(SubInstructions::StoreToRegisterInternal(200))
+            .add_sub_instruction(SubInstructions::StepProgramMemory(1));
+        writer.add_instruction(InstructionSet::Halt, &[]);
+        writer.add_instruction(InstructionSet::Halt, &[])
+            .add_sub_instruction(SubInstructions::Halt);
+        let opcode = InstructionSet::LoadImmediate as u8;
+        assert_eq!(writer.validate(&cpu.registers), vec![
+            Diagnostic::DuplicateOpcode { opcode: InstructionSet::Halt as u8 },
+            Diagnostic::ArgumentOffsetOutOfRange { opcode, sub_step: 0, offset: 3, args: 2 },
+            Diagnostic::UnknownRegister { opcode, sub_step: 1, register: 200 },
+            Diagnostic::ProgramCounterAdvance { opcode, expected: 2, actual: 1 },
+        ]);
+    }
+
+    #[test]
+    fn jumps_need_not_step() {
+        let cpu = create_default_cpu(64, 0);
+        let instruction = Instruction {
+            sub_instructions: vec![SubInstructions::LoadImmediate(1), SubInstructions::Jump],
+            operands: vec![Operand::Addr],
+            data_size: 1,
+            args: 1,
+            privileged: false,
+        };
+        let diagnostics = validate_instruction(0, &instruction, &cpu.registers);
+        assert!(diagnostics.is_empty(), "{:#?}", diagnostics);
+    }
+
+    #[test]
+    fn reports_operand_kind_mismatch() {
+        let cpu = create_default_cpu(64, 0);
+        let instruction = Instruction {
+            sub_instructions: vec![
+                SubInstructions::LoadFromRegister(2),
+                SubInstructions::LoadImmediate(1),
+                SubInstructions::StepProgramMemory(2),
+            ],
+            operands: vec![Operand::Reg, Operand::Imm8],
+            data_size: 1,
+            args: 2,
+            privileged: false,
+        };
+        assert_eq!(validate_instruction(7, &instruction, &cpu.registers), vec![
+            Diagnostic::OperandKindMismatch { opcode: 7, sub_step: 0, offset: 2, operand: Operand::Imm8 },
+            Diagnostic::OperandKindMismatch { opcode: 7, sub_step: 1, offset: 1, operand: Operand::Reg },
+        ]);
+    }
+}
@@ -1,17 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::computer::{Instruction, SubInstructions};
+use num_bigint::BigUint;
+
+use crate::computer::{Endianness, Instruction, Operand, Registers, SubInstructions};
+use crate::linker::Linker;
+use crate::object::{displacement, ObjectFile, Relocation, RelocationKind, SectionKind, Symbol};
+use crate::validator::{validate_instruction, Diagnostic};
 
 pub struct InstructionBuilder {
     sub_instructions: Vec<SubInstructions>,
-    args: u8,
+    operands: Vec<Operand>,
+    data_size: u8,
+    privileged: bool,
 }
 
 impl InstructionBuilder {
-    pub fn new(args: u8) -> Self {
+    pub fn new(operands: &[Operand], data_size: u8) -> Self {
         InstructionBuilder {
             sub_instructions: Vec::new(),
-            args,
+            operands: operands.to_vec(),
+            data_size,
+            privileged: false,
         }
     }
 
@@ -20,34 +29,84 @@ impl InstructionBuilder {
         self
     }
 
+    // Restricts the instruction to supervisor mode
+    pub fn privileged(&mut self) -> &mut Self {
+        self.privileged = true;
+        self
+    }
+
     pub fn build(self) -> Instruction {
+        let args = self.operands.iter().map(|operand| operand.width(self.data_size)).sum();
         Instruction {
             sub_instructions: self.sub_instructions,
-            args: self.args,
+            operands: self.operands,
+            data_size: self.data_size,
+            args,
+            privileged: self.privileged,
         }
     }
+
+    fn preview(&self) -> Instruction {
+        InstructionBuilder {
+            sub_instructions: self.sub_instructions.clone(),
+            operands: self.operands.clone(),
+            data_size: self.data_size,
+            privileged: self.privileged,
+        }.build()
+    }
 }
 
 pub struct InstructionSetWriter {
     instruction_set: HashMap<u8, InstructionBuilder>,
+    duplicate_opcodes: Vec<u8>,
+    data_size: u8,
 }
 
 impl InstructionSetWriter {
-    pub fn new() -> Self {
+    // data_size is the cpu_data_size that Imm and Addr operands follow
+    pub fn new(data_size: u8) -> Self {
         InstructionSetWriter {
             instruction_set: HashMap::new(),
+            duplicate_opcodes: Vec::new(),
+            data_size,
         }
     }
 
-    pub fn add_instruction<T>(&mut self, opcode: T, args: u8) -> &mut InstructionBuilder
+    pub fn add_instruction<T>(&mut self, opcode: T, operands: &[Operand]) -> &mut InstructionBuilder
     where
         T: Into<u8>,
     {
         let u8_opcode: u8 = opcode.into();
-        self.instruction_set.insert(u8_opcode, InstructionBuilder::new(args));
+        if self.instruction_set.insert(u8_opcode, InstructionBuilder::new(operands, self.data_size)).is_some() {
+            self.duplicate_opcodes.push(u8_opcode);
+        }
         self.instruction_set.get_mut(&u8_opcode).unwrap()
     }
 
+    pub fn validate(&self, registers: &Registers) -> Vec<Diagnostic> {
+        let mut diagnostics: Vec<Diagnostic> = self.duplicate_opcodes
+            .iter()
+            .map(|opcode| Diagnostic::DuplicateOpcode { opcode: *opcode })
+            .collect();
+        let mut opcodes: Vec<&u8> = self.instruction_set.keys().collect();
+        opcodes.sort();
+        for opcode in opcodes {
+            let instruction = self.instruction_set[opcode].preview();
+            diagnostics.extend(validate_instruction(*opcode, &instruction, registers));
+        }
+        diagnostics
+    }
+
+    // Like build, but refuses an instruction set with any diagnostics
+    pub fn build_checked(self, registers: &Registers) -> Result<HashMap<u8, Instruction>, Vec<Diagnostic>> {
+        let diagnostics = self.validate(registers);
+        if diagnostics.is_empty() {
+            Ok(self.build())
+        } else {
+            Err(diagnostics)
+        }
+    }
+
     pub fn build(self) -> HashMap<u8, Instruction> {
         self.instruction_set
             .into_iter()
@@ -58,35 +117,431 @@ impl InstructionSetWriter {
 
 pub struct ProgramWriter {
     instruction_set: HashMap<u8, Instruction>,
+    register_count: usize,
+    endianness: Endianness,
+    // Addr operands must be below this when set
+    memory_size: Option<usize>,
     program: Vec<u8>,
+    data: Vec<u8>,
+    bss_size: usize,
+    labels: HashMap<String, (SectionKind, usize)>,
+    globals: HashSet<String>,
+    fixups: Vec<Fixup>,
+}
+
+// An operand to fill in once its label is known
+struct Fixup {
+    // Offset of the instruction's opcode, relative displacements are measured from it
+    instruction: usize,
+    operand: usize,
+    width: usize,
+    label: String,
+    relative: bool,
 }
 
 impl ProgramWriter {
-    pub fn new(instruction_set: HashMap<u8, Instruction>) -> Self {
+    pub fn new(instruction_set: HashMap<u8, Instruction>, registers: &Registers) -> Self {
         ProgramWriter {
             instruction_set,
+            register_count: registers.registers.len(),
+            endianness: Endianness::Big,
+            memory_size: None,
             program: Vec::new(),
+            data: Vec::new(),
+            bss_size: 0,
+            labels: HashMap::new(),
+            globals: HashSet::new(),
+            fixups: Vec::new(),
         }
     }
 
+    // Byte order for operands wider than one byte, this must match the CPU's endianness
+    pub fn set_endianness(&mut self, endianness: Endianness) -> &mut Self {
+        self.endianness = endianness;
+        self
+    }
+
+    // Rejects Addr operands at or past size, unchecked by default
+    pub fn set_memory_size(&mut self, size: usize) -> &mut Self {
+        self.memory_size = Some(size);
+        self
+    }
+
+    // Takes one value per operand and encodes each at its operand's width
     pub fn add_instruction<T>(&mut self, opcode: T, args: &[u8]) -> &mut Self
+    where
+        T: Into<u8>,
+    {
+        let wide_args: Vec<u64> = args.iter().map(|arg| *arg as u64).collect();
+        self.add_instruction_wide(opcode, &wide_args)
+    }
+
+    // Like add_instruction, for operand values that do not fit in a byte
+    pub fn add_instruction_wide<T>(&mut self, opcode: T, args: &[u64]) -> &mut Self
+    where
+        T: Into<u8>,
+    {
+        self.try_add_instruction_wide(opcode, args).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Like add_instruction, returning an unknown opcode or a bad argument as an error instead of panicking
+    pub fn try_add_instruction<T>(&mut self, opcode: T, args: &[u8]) -> Result<&mut Self, String>
+    where
+        T: Into<u8>,
+    {
+        let wide_args: Vec<u64> = args.iter().map(|arg| *arg as u64).collect();
+        self.try_add_instruction_wide(opcode, &wide_args)
+    }
+
+    pub fn try_add_instruction_wide<T>(&mut self, opcode: T, args: &[u64]) -> Result<&mut Self, String>
+    where
+        T: Into<u8>,
+    {
+        let big_args: Vec<BigUint> = args.iter().map(|arg| BigUint::from(*arg)).collect();
+        self.try_add_instruction_big(opcode, &big_args)
+    }
+
+    // Like try_add_instruction_wide, for operands wider than 8 bytes
+    pub fn try_add_instruction_big<T>(&mut self, opcode: T, args: &[BigUint]) -> Result<&mut Self, String>
     where
         T: Into<u8>,
     {
         let u8_opcode: u8 = opcode.into();
-        if let Some(instruction) = self.instruction_set.get(&u8_opcode) {
-            if args.len() != instruction.args as usize {
-                panic!("Incorrect number of arguments for opcode {}", u8_opcode);
+        let instruction = self.instruction_set.get(&u8_opcode)
+            .ok_or_else(|| format!("Opcode {} not found in instruction set", u8_opcode))?;
+        if args.len() != instruction.operands.len() {
+            return Err(format!("Incorrect number of arguments for opcode {}", u8_opcode));
+        }
+        let mut encoded = vec![u8_opcode];
+        for (index, (operand, value)) in instruction.operands.iter().zip(args).enumerate() {
+            if operand.is_register() && *value >= BigUint::from(self.register_count) {
+                return Err(format!("Argument {} of opcode {}: register {} does not exist", index, u8_opcode, value));
             }
-            self.program.push(u8_opcode);
-            self.program.extend_from_slice(args);
-        } else {
-            panic!("Opcode {} not found in instruction set", u8_opcode);
+            let width = instruction.operand_width(*operand) as usize;
+            if value.bits() > 8 * width as u64 {
+                return Err(format!("Argument {} of opcode {}: {} does not fit in {:?}", index, u8_opcode, value, operand));
+            }
+            if let (Operand::Addr, Some(size)) = (operand, self.memory_size) {
+                if *value >= BigUint::from(size) {
+                    return Err(format!("Argument {} of opcode {}: address {:#x} is outside {} bytes of memory", index, u8_opcode, value, size));
+                }
+            }
+            encoded.extend(self.endianness.encode(value, width));
+        }
+        self.program.extend(encoded);
+        Ok(self)
+    }
+
+    // Offset of the next instruction from the start of the program
+    pub fn position(&self) -> usize {
+        self.program.len()
+    }
+
+    // Names the position of the next instruction for relative and address operands
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        self.try_label(name).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_label(&mut self, name: &str) -> Result<&mut Self, String> {
+        self.define(name, SectionKind::Code, self.position())
+    }
+
+    // Appends bytes to the data section under a label
+    pub fn data(&mut self, name: &str, bytes: &[u8]) -> &mut Self {
+        self.try_data(name, bytes).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_data(&mut self, name: &str, bytes: &[u8]) -> Result<&mut Self, String> {
+        self.define(name, SectionKind::Data, self.data.len())?;
+        self.data.extend(bytes);
+        Ok(self)
+    }
+
+    // Reserves size zeroed bytes in the bss section under a label
+    pub fn reserve(&mut self, name: &str, size: usize) -> &mut Self {
+        self.try_reserve(name, size).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_reserve(&mut self, name: &str, size: usize) -> Result<&mut Self, String> {
+        self.define(name, SectionKind::Bss, self.bss_size)?;
+        self.bss_size += size;
+        Ok(self)
+    }
+
+    // Names the end of a section, where the next bytes emitted into it go
+    pub fn label_in(&mut self, section: SectionKind, name: &str) -> &mut Self {
+        self.try_label_in(section, name).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_label_in(&mut self, section: SectionKind, name: &str) -> Result<&mut Self, String> {
+        self.define(name, section, self.section_size(section))
+    }
+
+    pub fn section_size(&self, section: SectionKind) -> usize {
+        match section {
+            SectionKind::Code => self.program.len(),
+            SectionKind::Data => self.data.len(),
+            SectionKind::Bss => self.bss_size,
         }
+    }
+
+    // Appends raw bytes to a section without naming them, the bss section only takes zeros
+    pub fn emit(&mut self, section: SectionKind, bytes: &[u8]) -> &mut Self {
+        self.try_emit(section, bytes).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_emit(&mut self, section: SectionKind, bytes: &[u8]) -> Result<&mut Self, String> {
+        match section {
+            SectionKind::Code => self.program.extend(bytes),
+            SectionKind::Data => self.data.extend(bytes),
+            SectionKind::Bss if bytes.iter().all(|byte| *byte == 0) => self.bss_size += bytes.len(),
+            SectionKind::Bss => return Err("The bss section only holds zeros".to_string()),
+        }
+        Ok(self)
+    }
+
+    // Makes a label visible to other objects when linking, it may be defined later
+    pub fn global(&mut self, name: &str) -> &mut Self {
+        self.globals.insert(name.to_string());
         self
     }
 
+    fn define(&mut self, name: &str, section: SectionKind, offset: usize) -> Result<&mut Self, String> {
+        if self.labels.insert(name.to_string(), (section, offset)).is_some() {
+            return Err(format!("Label '{}' is defined more than once", name));
+        }
+        Ok(self)
+    }
+
+    // Like add_instruction_wide, with the displacement from this instruction's opcode to label inserted
+    // as the operand its microcode reads with AddressPcRelative. The label may come later, displacements
+    // are filled in by build
+    pub fn add_relative_instruction<T>(&mut self, opcode: T, label: &str, args: &[u64]) -> &mut Self
+    where
+        T: Into<u8>,
+    {
+        self.try_add_relative_instruction(opcode, label, args).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_add_relative_instruction<T>(&mut self, opcode: T, label: &str, args: &[u64]) -> Result<&mut Self, String>
+    where
+        T: Into<u8>,
+    {
+        let opcode = opcode.into();
+        let instruction = self.instruction_set.get(&opcode)
+            .ok_or_else(|| format!("Opcode {} not found in instruction set", opcode))?;
+        let operand = instruction.pc_relative_operand()
+            .ok_or_else(|| format!("Opcode {} has no PC relative operand", opcode))?;
+        let big_args: Vec<BigUint> = args.iter().map(|arg| BigUint::from(*arg)).collect();
+        self.add_with_label(opcode, operand, label, &big_args, true)
+    }
+
+    // Like add_instruction_wide, with label's address inserted as operand number operand. The address is
+    // only known once the program is built or linked
+    pub fn add_address_instruction<T>(&mut self, opcode: T, operand: usize, label: &str, args: &[u64]) -> &mut Self
+    where
+        T: Into<u8>,
+    {
+        self.try_add_address_instruction(opcode, operand, label, args).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_add_address_instruction<T>(&mut self, opcode: T, operand: usize, label: &str, args: &[u64]) -> Result<&mut Self, String>
+    where
+        T: Into<u8>,
+    {
+        let big_args: Vec<BigUint> = args.iter().map(|arg| BigUint::from(*arg)).collect();
+        self.add_with_label(opcode.into(), operand, label, &big_args, false)
+    }
+
+    // Shared by the address and relative forms, relative if label's displacement goes into operand
+    pub(crate) fn add_with_label(&mut self, opcode: u8, operand: usize, label: &str, args: &[BigUint], relative: bool) -> Result<&mut Self, String> {
+        let instruction = self.instruction_set.get(&opcode)
+            .ok_or_else(|| format!("Opcode {} not found in instruction set", opcode))?;
+        let (offset, width) = match instruction.operands.get(operand) {
+            Some(kind) if !kind.is_register() && operand <= args.len() => {
+                let offset: usize = instruction.operands[..operand].iter().map(|kind| instruction.operand_width(*kind) as usize).sum();
+                (offset, instruction.operand_width(*kind) as usize)
+            }
+            _ => return Err(format!("Opcode {} has no address operand {}", opcode, operand)),
+        };
+        let position = self.position();
+        let mut placeholder = args.to_vec();
+        placeholder.insert(operand, BigUint::default());
+        self.try_add_instruction_big(opcode, &placeholder)?;
+        self.fixups.push(Fixup { instruction: position, operand: position + 1 + offset, width, label: label.to_string(), relative });
+        Ok(self)
+    }
+
     pub fn build(self) -> Vec<u8> {
-        self.program
+        self.try_build().unwrap_or_else(|error| panic!("{}", error))
     }
-}
\ No newline at end of file
+
+    // A program to load at address 0, the data section follows the code and bss the data. Every label
+    // must be defined
+    pub fn try_build(self) -> Result<Vec<u8>, String> {
+        if let Some(fixup) = self.fixups.iter().find(|fixup| !self.labels.contains_key(&fixup.label)) {
+            return Err(format!("Label '{}' is not defined", fixup.label));
+        }
+        let image = Linker::new().add(self.try_build_object("program")?).link()?;
+        Ok(image.flatten().1)
+    }
+
+    // A relocatable object for the Linker. Relative operands to code labels are position independent and
+    // filled in now, everything else becomes a relocation, including labels other objects define
+    pub fn try_build_object(mut self, name: &str) -> Result<ObjectFile, String> {
+        if let Some(global) = self.globals.iter().find(|global| !self.labels.contains_key(*global)) {
+            return Err(format!("Global '{}' is not defined", global));
+        }
+        let mut object = ObjectFile::new(name, self.endianness);
+        for fixup in &self.fixups {
+            match (fixup.relative, self.labels.get(&fixup.label)) {
+                (true, Some((SectionKind::Code, target))) => {
+                    let value = displacement(*target, fixup.instruction, fixup.width).map_err(|error| format!("{} to '{}'", error, fixup.label))?;
+                    let bytes = self.endianness.encode(&value, fixup.width);
+                    self.program[fixup.operand..fixup.operand + fixup.width].copy_from_slice(&bytes);
+                }
+                (relative, _) => object.relocations.push(Relocation {
+                    section: SectionKind::Code,
+                    offset: fixup.operand,
+                    width: fixup.width,
+                    symbol: fixup.label.clone(),
+                    kind: if relative { RelocationKind::Relative { origin: fixup.instruction } } else { RelocationKind::Absolute },
+                }),
+            }
+        }
+        let mut labels: Vec<(&String, &(SectionKind, usize))> = self.labels.iter().collect();
+        labels.sort_by_key(|(name, (section, offset))| (*section, *offset, name.as_str()));
+        object.symbols = labels
+            .into_iter()
+            .map(|(name, (section, offset))| Symbol { name: name.clone(), section: *section, offset: *offset, global: self.globals.contains(name) })
+            .collect();
+        object.code = self.program;
+        object.data = self.data;
+        object.bss_size = self.bss_size;
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computer::create_default_cpu;
+    use crate::InstructionSet;
+
+    fn writer() -> ProgramWriter {
+        let cpu = create_default_cpu(64, 0);
+        let mut instruction_set_writer = InstructionSetWriter::new(1);
+        instruction_set_writer.add_instruction(InstructionSet::LoadImmediate, &[Operand::Reg, Operand::Imm16]);
+        instruction_set_writer.add_instruction(InstructionSet::Jump, &[Operand::Addr]);
+        instruction_set_writer.add_instruction(InstructionSet::Branch, &[Operand::Imm])
+            .add_sub_instruction(SubInstructions::AddressPcRelative(1, 1, Endianness::Big));
+        ProgramWriter::new(instruction_set_writer.build(), &cpu.registers)
+    }
+
+    #[test]
+    fn encodes_operands_at_their_width() {
+        let mut program_writer = writer();
+        program_writer
+            .add_instruction_wide(InstructionSet::LoadImmediate, &[13, 0x1234])
+            .add_instruction(InstructionSet::Jump, &[7]);
+        assert_eq!(program_writer.build(), vec![InstructionSet::LoadImmediate as u8, 13, 0x12, 0x34, InstructionSet::Jump as u8, 7]);
+    }
+
+    #[test]
+    fn rejects_addresses_outside_memory() {
+        let mut program_writer = writer();
+        program_writer.add_instruction(InstructionSet::Jump, &[200]);
+        program_writer.set_memory_size(64);
+        assert_eq!(
+            program_writer.try_add_instruction(InstructionSet::Jump, &[64]).err().as_deref(),
+            Some("Argument 0 of opcode 15: address 0x40 is outside 64 bytes of memory")
+        );
+        program_writer.try_add_instruction(InstructionSet::Jump, &[63]).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "register 200 does not exist")]
+    fn rejects_unknown_register() {
+        writer().add_instruction(InstructionSet::LoadImmediate, &[200, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in Imm16")]
+    fn rejects_immediate_wider_than_operand() {
+        writer().add_instruction_wide(InstructionSet::LoadImmediate, &[13, 0x10000]);
+    }
+
+    #[test]
+    fn try_add_instruction_reports_errors() {
+        let mut program_writer = writer();
+        let error = program_writer.try_add_instruction(InstructionSet::Halt, &[]).err();
+        assert_eq!(error.as_deref(), Some("Opcode 1 not found in instruction set"));
+        assert!(program_writer.try_add_instruction(InstructionSet::Jump, &[7]).is_ok());
+        assert_eq!(program_writer.build(), vec![InstructionSet::Jump as u8, 7]);
+    }
+
+    #[test]
+    fn relative_instructions_resolve_labels_both_ways() {
+        let mut program_writer = writer();
+        program_writer
+            .label("start")
+            .add_relative_instruction(InstructionSet::Branch, "end", &[])
+            .add_relative_instruction(InstructionSet::Branch, "start", &[])
+            .label("end");
+        assert_eq!(program_writer.position(), 4);
+        assert_eq!(program_writer.build(), vec![InstructionSet::Branch as u8, 4, InstructionSet::Branch as u8, 0xfe]);
+
+        let mut program_writer = writer();
+        program_writer.add_relative_instruction(InstructionSet::Branch, "nowhere", &[]);
+        assert_eq!(program_writer.try_build().err().as_deref(), Some("Label 'nowhere' is not defined"));
+        assert!(writer().try_add_relative_instruction(InstructionSet::LoadImmediate, "start", &[0]).is_err());
+        // Jump takes an absolute address, so a displacement would be misread
+        let error = writer().try_add_relative_instruction(InstructionSet::Jump, "start", &[]).err();
+        assert_eq!(error.as_deref(), Some("Opcode 15 has no PC relative operand"));
+        assert!(writer().label("twice").try_label("twice").is_err());
+    }
+
+    #[test]
+    fn relative_displacements_must_fit_their_operand() {
+        let mut program_writer = writer();
+        program_writer.add_relative_instruction(InstructionSet::Branch, "far", &[]);
+        for _ in 0..100 {
+            program_writer.add_instruction(InstructionSet::Jump, &[0]);
+        }
+        program_writer.label("far");
+        assert_eq!(program_writer.try_build().err().as_deref(), Some("Displacement 202 does not fit in 1 byte(s) to 'far'"));
+    }
+
+    #[test]
+    fn flat_builds_put_data_after_code() {
+        let mut program_writer = writer();
+        program_writer
+            .add_address_instruction(InstructionSet::Jump, 0, "table", &[])
+            .data("table", &[7, 8])
+            .reserve("scratch", 2);
+        assert_eq!(program_writer.build(), vec![InstructionSet::Jump as u8, 2, 7, 8, 0, 0]);
+        let mut program_writer = writer();
+        program_writer.global("missing");
+        assert_eq!(program_writer.try_build_object("empty").err().as_deref(), Some("Global 'missing' is not defined"));
+    }
+
+    #[test]
+    fn emitted_bytes_follow_their_section_labels() {
+        let mut program_writer = writer();
+        program_writer
+            .add_address_instruction(InstructionSet::Jump, 0, "after", &[])
+            .emit(SectionKind::Code, &[9])
+            .label_in(SectionKind::Data, "after")
+            .emit(SectionKind::Data, &[1, 2])
+            .emit(SectionKind::Bss, &[0, 0]);
+        assert_eq!(program_writer.section_size(SectionKind::Bss), 2);
+        assert!(program_writer.try_emit(SectionKind::Bss, &[1]).is_err());
+        assert_eq!(program_writer.build(), vec![InstructionSet::Jump as u8, 3, 9, 1, 2, 0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Incorrect number of arguments")]
+    fn rejects_missing_operand() {
+        writer().add_instruction(InstructionSet::Jump, &[]);
+    }
+}